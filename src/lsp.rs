@@ -0,0 +1,929 @@
+//! `e64br lsp`, a Language Server Protocol server for .ebr files, spoken
+//! over stdin and stdout. It assembles each open file as it changes,
+//! publishing the errors and warnings, and answers go-to-definition, hover
+//! and completion from the symbols of the last time the file assembled.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use packed_struct::prelude::*;
+
+use crate::{
+    compile_rom, flags_affected, highlight, is_identifier, json_string, operand_kind, split_label,
+    strip_comment, CompileError, Instruction, OperandKind, Symbol, SymbolKind,
+};
+
+/// As much of JSON as the protocol needs. Objects keep their keys in order.
+#[derive(Clone, Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn string(text: impl Into<String>) -> Json {
+        Json::String(text.into())
+    }
+
+    /// The value of `key`, or Null if this isn't an object that has one.
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(number) if number >= 0.0 && number.fract() == 0.0 => Some(number as usize),
+            _ => None,
+        }
+    }
+
+    fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser { text, at: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        (parser.at == text.len()).then_some(value)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+                write!(f, "{}", *number as i64)
+            }
+            Json::Number(number) => write!(f, "{}", number),
+            Json::String(text) => write!(f, "{}", json_string(text)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", json_string(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    /// The byte offset of the next character.
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.at..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.at += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Option<Json> {
+        self.text[self.at..].starts_with(word).then(|| {
+            self.at += word.len();
+            value
+        })
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match self.peek()? {
+            'n' => self.literal("null", Json::Null),
+            't' => self.literal("true", Json::Bool(true)),
+            'f' => self.literal("false", Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.at += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.at += 1;
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => {}
+                        ']' => return Some(Json::Array(values)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.at += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.at += 1;
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.next()? != ':' {
+                        return None;
+                    }
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => {}
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => {
+                let length = self.text[self.at..]
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(self.text.len() - self.at);
+                let number = self.text[self.at..][..length].parse().ok()?;
+                self.at += length;
+                Some(Json::Number(number))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.next()? != '"' {
+            return None;
+        }
+        let mut text = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(text),
+                '\\' => match self.next()? {
+                    'b' => text.push('\u{8}'),
+                    'f' => text.push('\u{c}'),
+                    'n' => text.push('\n'),
+                    'r' => text.push('\r'),
+                    't' => text.push('\t'),
+                    'u' => {
+                        let mut unit = self.hex()?;
+                        // A character outside the BMP is a surrogate pair.
+                        if (0xD800..0xDC00).contains(&unit) {
+                            if !self.text[self.at..].starts_with("\\u") {
+                                return None;
+                            }
+                            self.at += 2;
+                            let low = self.hex()?;
+                            unit = 0x10000 + ((unit - 0xD800) << 10) + (low.checked_sub(0xDC00)?);
+                        }
+                        text.push(char::from_u32(unit)?);
+                    }
+                    c => text.push(c),
+                },
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Option<u32> {
+        let digits = self.text.get(self.at..self.at + 4)?;
+        self.at += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+}
+
+/// The longest message body read, far more than any .ebr file needs, so a
+/// bad Content-Length can't have the server allocate without bound.
+const MAX_MESSAGE_LENGTH: usize = 16 << 20;
+
+/// Reads a message, which is a header, a blank line and `Content-Length`
+/// bytes of JSON. None at the end of the input, and Some(None) for a body
+/// that isn't JSON, which the client is told of without ending the session.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Option<Json>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+    let length = length.ok_or_else(|| invalid("message has no Content-Length"))?;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(invalid("message is too long"));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(std::str::from_utf8(&body).ok().and_then(Json::parse)))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// The path a `file://` URI names.
+fn uri_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The `file://` URI of `path`, made absolute.
+fn path_uri(path: &Path) -> String {
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => std::env::current_dir().unwrap_or_default().join(path),
+    };
+    let mut uri = String::from("file://");
+    for byte in path.display().to_string().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Positions count UTF-16 code units, so these convert between them and
+/// byte offsets into a line.
+fn column(line: &str, offset: usize) -> usize {
+    line[..offset].encode_utf16().count()
+}
+
+fn offset(line: &str, column: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= column {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// A range within line `line`, 1-based, of the bytes `span` of `text`.
+fn range(line: usize, text: &str, span: Range<usize>) -> Json {
+    let position = |offset| {
+        Json::object(vec![
+            ("line", Json::Number(line.saturating_sub(1) as f64)),
+            ("character", Json::Number(column(text, offset) as f64)),
+        ])
+    };
+    Json::object(vec![
+        ("start", position(span.start)),
+        ("end", position(span.end)),
+    ])
+}
+
+/// The whole of a line's code.
+fn code_span(text: &str) -> Range<usize> {
+    let code = strip_comment(text).trim_end();
+    code.len() - code.trim_start().len()..code.len()
+}
+
+/// The name the assembler gives `word` on line `index` of `text`. A local
+/// label is scoped to the global label before it, as localize() does, so
+/// `.loop` after `main:` is `main.loop`.
+fn scoped_name(text: &str, index: usize, word: &str) -> String {
+    if !word.starts_with('.') {
+        return word.to_string();
+    }
+    let scope = text
+        .lines()
+        .take(index + 1)
+        .flat_map(|line| {
+            let mut code = strip_comment(line).trim();
+            let mut labels = Vec::new();
+            while let Some((label, rest)) = split_label(code) {
+                labels.push(label);
+                code = rest;
+            }
+            labels
+        })
+        .filter(|label| is_identifier(label))
+        .last();
+    format!("{}{}", scope.unwrap_or_default(), word)
+}
+
+/// The symbol or mnemonic at `column` of `line`.
+fn word_at(line: &str, column: usize) -> Option<&str> {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    let at = offset(line, column);
+    let start = line[..at].rfind(|c| !word(c)).map_or(0, |index| index + 1);
+    let end = line[at..]
+        .find(|c| !word(c))
+        .map_or(line.len(), |index| at + index);
+    (start < end).then(|| &line[start..end])
+}
+
+fn mnemonic(word: &str) -> Option<Instruction> {
+    Instruction::all_variants()
+        .iter()
+        .copied()
+        .find(|instruction| format!("{:?}", instruction).eq_ignore_ascii_case(word))
+}
+
+fn describe_operand(kind: OperandKind) -> &'static str {
+    match kind {
+        OperandKind::None => "no operand",
+        OperandKind::RamAddress => "a RAM address, 0..=255",
+        OperandKind::RomAddress => "a ROM address, 0..=255",
+        OperandKind::Immediate => "a signed 56-bit value",
+        OperandKind::Fields => "several values packed together",
+    }
+}
+
+fn describe_symbol(symbol: &Symbol) -> String {
+    match symbol.kind {
+        SymbolKind::Label => format!("label `{}`, ROM address {}", symbol.name, symbol.value),
+        SymbolKind::Constant => format!("constant `{}` = {}", symbol.name, symbol.value),
+        SymbolKind::Data => format!("data `{}`, RAM address {}", symbol.name, symbol.value),
+        SymbolKind::Variable(1) => {
+            format!("variable `{}`, RAM address {}", symbol.name, symbol.value)
+        }
+        SymbolKind::Variable(size) => format!(
+            "variable `{}`, RAM addresses {}..={}",
+            symbol.name,
+            symbol.value,
+            symbol.value + size as i64 - 1
+        ),
+    }
+}
+
+struct Document {
+    text: String,
+    /// The symbols from the last time the document assembled, which are
+    /// kept while it has errors.
+    symbols: Vec<Symbol>,
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    /// Answers a request or acts on a notification, returning the messages
+    /// to send back.
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let params = message.get("params");
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let result = match message.get("method").as_str().unwrap_or_default() {
+            "initialize" => Json::object(vec![
+                (
+                    "capabilities",
+                    Json::object(vec![
+                        // Each change sends the whole document.
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("definitionProvider", Json::Bool(true)),
+                        ("hoverProvider", Json::Bool(true)),
+                        ("completionProvider", Json::object(Vec::new())),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object(vec![("name", Json::string("e64br"))]),
+                ),
+            ]),
+            "shutdown" => Json::Null,
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text");
+                return self.update(uri, text.as_str().unwrap_or_default());
+            }
+            "textDocument/didChange" => {
+                let text = match params.get("contentChanges") {
+                    Json::Array(changes) => changes.last().map(|change| change.get("text")),
+                    _ => None,
+                };
+                match text.and_then(Json::as_str) {
+                    Some(text) => return self.update(uri, text),
+                    None => return Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish(&uri, Vec::new())];
+            }
+            "textDocument/definition" => self.definition(&uri, params.get("position")),
+            "textDocument/hover" => self.hover(&uri, params.get("position")),
+            "textDocument/completion" => self.completion(&uri),
+            // Other notifications, such as `initialized`, need nothing.
+            _ if message.get("id") == &Json::Null => return Vec::new(),
+            method => {
+                return vec![error(
+                    message.get("id").clone(),
+                    -32601,
+                    format!("unknown method `{}`", method),
+                )];
+            }
+        };
+        vec![Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("id", message.get("id").clone()),
+            ("result", result),
+        ])]
+    }
+
+    /// Assembles a document's new text, keeping its symbols if it assembles,
+    /// and publishes what the assembler had to say.
+    fn update(&mut self, uri: String, text: &str) -> Vec<Json> {
+        let path = uri_path(&uri);
+        let name = path.display().to_string();
+        let document = self.documents.entry(uri.clone()).or_insert(Document {
+            text: String::new(),
+            symbols: Vec::new(),
+        });
+        document.text = text.to_string();
+        let result = compile_rom(&[(text.to_string(), &path)], &[], false);
+        let diagnostics = match &result {
+            Ok(program) => {
+                document.symbols = program.symbols.clone();
+                program
+                    .warnings
+                    .iter()
+                    .filter_map(|warning| diagnostic(warning, true, &name))
+                    .collect()
+            }
+            Err(errors) => errors
+                .iter()
+                .filter_map(|error| diagnostic(error, false, &name))
+                .collect(),
+        };
+        vec![publish(&uri, diagnostics)]
+    }
+
+    fn symbol_at(&self, uri: &str, position: &Json) -> Option<&Symbol> {
+        let document = self.documents.get(uri)?;
+        let index = position.get("line").as_usize()?;
+        let line = document.text.lines().nth(index)?;
+        let word = word_at(line, position.get("character").as_usize()?)?;
+        let name = scoped_name(&document.text, index, word);
+        document.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    fn definition(&self, uri: &str, position: &Json) -> Json {
+        let Some(symbol) = self.symbol_at(uri, position) else {
+            return Json::Null;
+        };
+        let Some((file, line)) = &symbol.definition else {
+            return Json::Null;
+        };
+        let path = Path::new(&**file);
+        let uri = match uri_path(uri) == path {
+            true => uri.to_string(),
+            false => path_uri(path),
+        };
+        Json::object(vec![
+            ("uri", Json::string(uri)),
+            ("range", range(*line, "", 0..0)),
+        ])
+    }
+
+    fn hover(&self, uri: &str, position: &Json) -> Json {
+        let contents = match self.symbol_at(uri, position) {
+            Some(symbol) => describe_symbol(symbol),
+            None => {
+                let word = self.documents.get(uri).and_then(|document| {
+                    let line = document
+                        .text
+                        .lines()
+                        .nth(position.get("line").as_usize()?)?;
+                    word_at(line, position.get("character").as_usize()?)
+                });
+                let Some(instruction) = word.and_then(mnemonic) else {
+                    return Json::Null;
+                };
+                let flags: Vec<String> = ["C", "Z", "N", "V"]
+                    .iter()
+                    .zip(flags_affected(instruction).effects())
+                    .map(|(flag, (_, effect))| format!("{} {}", flag, effect.symbol()))
+                    .collect();
+                format!(
+                    "`{:?}` ({:#04x}) takes {}\n\nflags: {} (- kept, * set from the result, 0 cleared, 1 set)",
+                    instruction,
+                    instruction.to_primitive(),
+                    describe_operand(operand_kind(instruction)),
+                    flags.join(", ")
+                )
+            }
+        };
+        Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", Json::string("markdown")),
+                ("value", Json::String(contents)),
+            ]),
+        )])
+    }
+
+    /// Every mnemonic, and every symbol the document had the last time it
+    /// assembled.
+    fn completion(&self, uri: &str) -> Json {
+        let item = |label: String, kind: f64, detail: String| {
+            Json::object(vec![
+                ("label", Json::String(label)),
+                ("kind", Json::Number(kind)),
+                ("detail", Json::String(detail)),
+            ])
+        };
+        // The numbers are CompletionItemKind's: keyword, function, constant
+        // and variable.
+        let instructions = Instruction::all_variants();
+        let mnemonics = instructions.iter().map(|&instruction| {
+            let operand = describe_operand(operand_kind(instruction));
+            item(format!("{:?}", instruction), 14.0, operand.to_string())
+        });
+        let symbols = self
+            .documents
+            .get(uri)
+            .map_or(&[][..], |document| &document.symbols)
+            .iter()
+            .map(|symbol| {
+                let kind = match symbol.kind {
+                    SymbolKind::Label => 3.0,
+                    SymbolKind::Constant => 21.0,
+                    SymbolKind::Data | SymbolKind::Variable(_) => 6.0,
+                };
+                item(symbol.name.clone(), kind, describe_symbol(symbol))
+            });
+        Json::Array(mnemonics.chain(symbols).collect())
+    }
+}
+
+/// An error or warning as an LSP diagnostic in the file named `name`. One
+/// from a file it includes is put on the `.include` line.
+fn diagnostic(error: &CompileError, warning: bool, name: &str) -> Option<Json> {
+    let range = match &*error.file == name {
+        true => range(error.line, &error.text, highlight(error)),
+        false => {
+            let context = error
+                .context
+                .iter()
+                .rev()
+                .find(|context| &*context.file == name)?;
+            range(context.line, &context.text, code_span(&context.text))
+        }
+    };
+    let related = |file: &str, line, text: &str, span, message: &str| {
+        Json::object(vec![
+            (
+                "location",
+                Json::object(vec![
+                    ("uri", Json::string(path_uri(Path::new(file)))),
+                    ("range", self::range(line, text, span)),
+                ]),
+            ),
+            ("message", Json::string(message)),
+        ])
+    };
+    // An error in another file is also shown where it is.
+    let elsewhere = (&*error.file != name).then(|| {
+        let message = error.kind.to_string();
+        related(
+            &error.file,
+            error.line,
+            &error.text,
+            highlight(error),
+            &message,
+        )
+    });
+    let notes = error.context.iter().chain(&error.notes).map(|note| {
+        let span = code_span(&note.text);
+        related(&note.file, note.line, &note.text, span, note.note)
+    });
+    let related: Vec<Json> = elsewhere.into_iter().chain(notes).collect();
+    Some(Json::object(vec![
+        ("range", range),
+        // DiagnosticSeverity's error and warning.
+        ("severity", Json::Number(if warning { 2.0 } else { 1.0 })),
+        ("source", Json::string("e64br")),
+        ("message", Json::string(error.kind.to_string())),
+        ("relatedInformation", Json::Array(related)),
+    ]))
+}
+
+/// A reply to request `id` that it failed, with one of JSON-RPC's error codes.
+fn error(id: Json, code: i64, message: impl Into<String>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("id", id),
+        (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(code as f64)),
+                ("message", Json::string(message)),
+            ]),
+        ),
+    ])
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", Json::string(uri)),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+/// Serves until the client sends `exit` or closes stdin.
+pub fn serve() -> Result<(), Box<dyn Error>> {
+    serve_on(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+    Ok(())
+}
+
+fn serve_on(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(input)? {
+        let Some(message) = message else {
+            // Without a readable id, the reply can only have a null one.
+            write_message(output, &error(Json::Null, -32700, "message is not JSON"))?;
+            continue;
+        };
+        if message.get("method").as_str() == Some("exit") {
+            break;
+        }
+        for reply in server.handle(&message) {
+            write_message(output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///test.ebr";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Json> {
+        let message = Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("method", Json::string("textDocument/didOpen")),
+            (
+                "params",
+                Json::object(vec![(
+                    "textDocument",
+                    Json::object(vec![
+                        ("uri", Json::string(URI)),
+                        ("text", Json::string(text)),
+                    ]),
+                )]),
+            ),
+        ]);
+        server.handle(&message)
+    }
+
+    /// The result of a `method` request at a position in the document.
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let message = Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("id", Json::Number(1.0)),
+            ("method", Json::string(method)),
+            (
+                "params",
+                Json::object(vec![
+                    (
+                        "textDocument",
+                        Json::object(vec![("uri", Json::string(URI))]),
+                    ),
+                    (
+                        "position",
+                        Json::object(vec![
+                            ("line", Json::Number(line as f64)),
+                            ("character", Json::Number(character as f64)),
+                        ]),
+                    ),
+                ]),
+            ),
+        ]);
+        let mut replies = server.handle(&message);
+        assert_eq!(replies.len(), 1);
+        replies.remove(0).get("result").clone()
+    }
+
+    fn definition(server: &mut Server, line: usize, character: usize) -> Json {
+        request(server, "textDocument/definition", line, character)
+    }
+
+    fn hover(server: &mut Server, line: usize, character: usize) -> String {
+        let result = request(server, "textDocument/hover", line, character);
+        let contents = result.get("contents");
+        assert_eq!(contents.get("kind").as_str(), Some("markdown"));
+        contents.get("value").as_str().unwrap().to_string()
+    }
+
+    fn frame(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn publishes_errors_on_the_token() {
+        let mut server = Server::default();
+        let replies = open(&mut server, "    LDA 5 6\n    HLT\n");
+        assert_eq!(replies.len(), 1);
+        let params = replies[0].get("params");
+        assert_eq!(params.get("uri").as_str(), Some(URI));
+        let Json::Array(diagnostics) = params.get("diagnostics") else {
+            panic!("no diagnostics in {}", replies[0]);
+        };
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.get("severity"), &Json::Number(1.0));
+        let start = diagnostic.get("range").get("start");
+        assert_eq!(start.get("line").as_usize(), Some(0));
+        assert_eq!(start.get("character").as_usize(), Some(10));
+    }
+
+    #[test]
+    fn publishes_nothing_for_a_clean_file() {
+        let mut server = Server::default();
+        let replies = open(&mut server, "    LDI 1\n    OUT\n    HLT\n");
+        assert_eq!(
+            replies[0].get("params").get("diagnostics"),
+            &Json::Array(Vec::new())
+        );
+    }
+
+    #[test]
+    fn finds_definitions() {
+        let mut server = Server::default();
+        open(
+            &mut server,
+            "main:\n.loop:\n    JMP .loop\n    JMP main\nother:\n.loop:\n    JMP .loop\n",
+        );
+        let line = |result: &Json| result.get("range").get("start").get("line").as_usize();
+        assert_eq!(line(&definition(&mut server, 3, 9)), Some(0));
+        assert_eq!(definition(&mut server, 2, 9).get("uri").as_str(), Some(URI));
+        // Each `.loop` is the one in its own global label's region.
+        assert_eq!(line(&definition(&mut server, 2, 9)), Some(1));
+        assert_eq!(line(&definition(&mut server, 6, 9)), Some(5));
+        assert_eq!(definition(&mut server, 2, 4), Json::Null);
+    }
+
+    #[test]
+    fn hovers_on_mnemonics_and_symbols() {
+        let mut server = Server::default();
+        open(&mut server, ".var total\n    ADD total\n    HLT\n");
+        assert_eq!(
+            hover(&mut server, 1, 5),
+            "`ADD` (0x03) takes a RAM address, 0..=255\n\n\
+             flags: C *, Z *, N *, V * (- kept, * set from the result, 0 cleared, 1 set)"
+        );
+        assert_eq!(hover(&mut server, 1, 9), "variable `total`, RAM address 0");
+        assert_eq!(hover(&mut server, 0, 6), "variable `total`, RAM address 0");
+        assert_eq!(request(&mut server, "textDocument/hover", 2, 0), Json::Null);
+    }
+
+    #[test]
+    fn completes_mnemonics_and_symbols() {
+        let mut server = Server::default();
+        open(&mut server, ".equ LIMIT 3\nmain:\n    LDI LIMIT\n    HLT\n");
+        let Json::Array(items) = request(&mut server, "textDocument/completion", 2, 8) else {
+            panic!("completion isn't a list");
+        };
+        let detail = |label: &str| {
+            items
+                .iter()
+                .find(|item| item.get("label").as_str() == Some(label))
+                .map(|item| item.get("detail").as_str().unwrap().to_string())
+        };
+        assert_eq!(detail("LDI").as_deref(), Some("a signed 56-bit value"));
+        assert_eq!(detail("LIMIT").as_deref(), Some("constant `LIMIT` = 3"));
+        assert_eq!(
+            detail("main").as_deref(),
+            Some("label `main`, ROM address 0")
+        );
+        assert_eq!(
+            items.len(),
+            Instruction::all_variants().len() + 2,
+            "{:?}",
+            items
+        );
+    }
+
+    #[test]
+    fn answers_a_malformed_frame_and_keeps_serving() {
+        let input = frame("{bad json")
+            + &frame(r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#)
+            + &frame(r#"{"jsonrpc":"2.0","method":"exit"}"#);
+        let mut output = Vec::new();
+        serve_on(&mut input.as_bytes(), &mut output).unwrap();
+        let mut output = output.as_slice();
+        let parse_error = read_message(&mut output).unwrap().flatten().unwrap();
+        assert_eq!(parse_error.get("id"), &Json::Null);
+        assert_eq!(
+            parse_error.get("error").get("code"),
+            &Json::Number(-32700.0)
+        );
+        let shutdown = read_message(&mut output).unwrap().flatten().unwrap();
+        assert_eq!(shutdown.get("id"), &Json::Number(1.0));
+        assert_eq!(shutdown.get("result"), &Json::Null);
+        assert!(read_message(&mut output).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_an_overlong_content_length() {
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX);
+        let error = read_message(&mut input.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "message is too long");
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":"é\n😀"}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("b").as_str(), Some("é\n😀"));
+        assert_eq!(Json::parse(&value.to_string()), Some(value));
+        assert_eq!(Json::parse("{\"a\":1,}"), None);
+    }
+}
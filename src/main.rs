@@ -1,13 +1,24 @@
-#[macro_use]
-extern crate packed_struct;
-#[macro_use]
-extern crate bitflags;
 extern crate args;
+extern crate bitflags;
 extern crate getopts;
+extern crate packed_struct;
+
+mod lsp;
 
 use args::*;
+use bitflags::bitflags;
 use getopts::Occur;
-use std::{error::Error, str::FromStr};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, IsTerminal, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+};
 
 use packed_struct::prelude::*;
 
@@ -28,6 +39,82 @@ pub enum Instruction {
     ADI,
     LDR,
     ADR,
+    SUI,
+    MUL,
+    MLI,
+    DIV,
+    MOD,
+    AND,
+    OR,
+    XOR,
+    ANI,
+    ORI,
+    XRI,
+    NOT,
+    NEG,
+    SHL,
+    SHR,
+    ROL,
+    ROR,
+    INC,
+    DEC,
+    CMP,
+    CPI,
+    JNZ,
+    JNC,
+    JN,
+    JP,
+    LDB,
+    STB,
+    TAB,
+    TBA,
+    ADB,
+    PUSH,
+    POP,
+    CALL,
+    RET,
+    LDP,
+    STP,
+    LDX,
+    INX,
+    DEX,
+    LAX,
+    SAX,
+    IN,
+    OUTC,
+    OUTH,
+    OUTB,
+    OUTS,
+    JR,
+    RND,
+    CLC,
+    SEC,
+    CLZ,
+    JMA,
+    BRK,
+    DBG,
+    XCHG,
+    ABS,
+    BIT,
+    TFA,
+    TAF,
+    SLP,
+    MCP,
+    ASR,
+    JSR,
+    RSR,
+    STR,
+    STRI,
+    MOV,
+    ADC,
+    SBC,
+    DJNZ,
+    STI,
+    SMB,
+    RMB,
+    LDH,
+    JO,
+    JNO,
 }
 
 #[derive(PackedStruct, Copy, Clone, Debug)]
@@ -46,203 +133,8668 @@ impl RomLayout {
     }
 }
 
+/// Why a line failed to assemble.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileErrorKind {
+    MissingInstruction,
+    UnknownInstruction {
+        instruction: String,
+        suggestion: Option<String>,
+    },
+    MissingOperand(String),
+    InvalidNumber(String),
+    InvalidLiteral {
+        literal: String,
+        reason: &'static str,
+    },
+    /// An operand expression that can't be evaluated, such as `4 / 0`.
+    InvalidExpression {
+        expression: String,
+        reason: String,
+    },
+    /// `operand` is spelt as it was written, so `LDH 0x100` reports
+    /// `0x100` rather than 256.
+    OutOfRange {
+        what: &'static str,
+        operand: String,
+        range: String,
+    },
+    UndefinedSymbol {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    DuplicateSymbol(String),
+    /// A name given to `.export` by more than one file.
+    DuplicateExport(String),
+    /// An `.import` that no file exports.
+    UnresolvedImport {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    /// A `.bytes` word whose first byte is no instruction's opcode.
+    UnknownOpcode(u8),
+    /// A directive used with the wrong shape; the message shows the right one.
+    InvalidDirective(&'static str),
+    UnterminatedMacro(String),
+    /// An `.if` without an `.endif`.
+    UnterminatedIf,
+    /// A `.rep` without an `.endrep`.
+    UnterminatedRep,
+    // Warnings, unless --strict makes them errors.
+    IgnoredOperand {
+        instruction: Instruction,
+        operand: String,
+    },
+    ReadsPastEnd {
+        instruction: Instruction,
+        address: i64,
+        last: usize,
+    },
+    /// The last instruction can fall through into the padding.
+    FallsOffEnd(Instruction),
+    JumpPastEnd {
+        instruction: Instruction,
+        target: usize,
+        last: usize,
+    },
+    /// A jump to a word LDR, ADR or STR uses as data.
+    JumpToData {
+        instruction: Instruction,
+        target: usize,
+    },
+    /// A jump to a `.data` label, which is a RAM address.
+    JumpToRam {
+        instruction: Instruction,
+        operand: String,
+    },
+    /// A run of this many instructions that can never run.
+    Unreachable(usize),
+    /// A read of a RAM cell nothing in the program writes.
+    UninitializedRead {
+        instruction: Instruction,
+        cell: usize,
+    },
+    /// `.endmacro` without a `.macro`, or a `.macro` inside another.
+    MisplacedDirective(&'static str),
+    /// A `.var` that doesn't fit in the cells left.
+    RamExhausted {
+        name: String,
+        size: usize,
+        free: usize,
+    },
+    /// A `.data` block over cells allocated by `.var`.
+    DataOverlapsVariable {
+        name: String,
+        cells: Range<usize>,
+    },
+    /// A directive that can only be given once, given again.
+    RepeatedDirective(&'static str),
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The chain of macros that ends up invoking its own start.
+    RecursiveMacro(Vec<String>),
+    IncludeFailed {
+        path: String,
+        reason: io::ErrorKind,
+    },
+    /// The chain of files that ends up including its own start.
+    IncludeCycle(Vec<String>),
+    /// The program's length in instructions, once it passes ROM_SIZE.
+    ProgramTooLong(usize),
+    /// Tokens after an instruction's last operand.
+    UnexpectedOperands(Vec<String>),
+    /// A `.label` that isn't defined in the scope it's used in, which is
+    /// after the global label `scope`, if any.
+    UndefinedLocal {
+        label: String,
+        scope: Option<String>,
+    },
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileErrorKind::MissingInstruction => write!(f, "missing instruction"),
+            CompileErrorKind::UnknownInstruction {
+                instruction,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "unknown instruction `{}`{}",
+                    instruction,
+                    did_you_mean(suggestion.as_slice())
+                )
+            }
+            CompileErrorKind::MissingOperand(instruction) => {
+                write!(f, "{} is missing an operand", instruction)
+            }
+            CompileErrorKind::InvalidNumber(token) => write!(f, "`{}` is not a number", token),
+            CompileErrorKind::InvalidLiteral { literal, reason } if literal.starts_with('"') => {
+                write!(f, "invalid string `{}`: {}", literal, reason)
+            }
+            CompileErrorKind::InvalidLiteral { literal, reason } => {
+                write!(f, "invalid character literal `{}`: {}", literal, reason)
+            }
+            CompileErrorKind::InvalidExpression { expression, reason } => {
+                write!(f, "can't evaluate `{}`: {}", expression, reason)
+            }
+            CompileErrorKind::OutOfRange {
+                what,
+                operand,
+                range,
+            } => write!(f, "{} {} is out of range {}", what, operand, range),
+            CompileErrorKind::UndefinedSymbol { name, suggestions } => {
+                write!(f, "`{}` is not defined{}", name, did_you_mean(suggestions))
+            }
+            CompileErrorKind::DuplicateSymbol(name) => write!(f, "`{}` is already defined", name),
+            CompileErrorKind::DuplicateExport(name) => write!(f, "`{}` is already exported", name),
+            CompileErrorKind::UnresolvedImport { name, suggestions } => {
+                write!(f, "no file exports `{}`{}", name, did_you_mean(suggestions))
+            }
+            CompileErrorKind::UnknownOpcode(opcode) => {
+                write!(f, "{:#04x} is not an instruction's opcode", opcode)
+            }
+            CompileErrorKind::InvalidDirective(usage) => write!(f, "expected `{}`", usage),
+            CompileErrorKind::UnterminatedIf => write!(f, "`.if` has no `.endif`"),
+            CompileErrorKind::UnterminatedRep => write!(f, "`.rep` has no `.endrep`"),
+            CompileErrorKind::IgnoredOperand {
+                instruction,
+                operand,
+            } => write!(
+                f,
+                "{:?} takes no operand, ignoring `{}`",
+                instruction, operand
+            ),
+            CompileErrorKind::ReadsPastEnd {
+                instruction,
+                address,
+                last,
+            } => write!(
+                f,
+                "{:?} reads ROM address {}, past the last instruction ({})",
+                instruction, address, last
+            ),
+            CompileErrorKind::JumpPastEnd {
+                instruction,
+                target,
+                last,
+            } => write!(
+                f,
+                "{:?} jumps to {}, past the last instruction ({}), where there's only HLT padding",
+                instruction, target, last
+            ),
+            CompileErrorKind::JumpToData {
+                instruction,
+                target,
+            } => write!(
+                f,
+                "{:?} jumps to {}, which the program reads as data",
+                instruction, target
+            ),
+            CompileErrorKind::JumpToRam {
+                instruction,
+                operand,
+            } => write!(
+                f,
+                "{:?} jumps to `{}`, which names RAM data, not code",
+                instruction, operand
+            ),
+            CompileErrorKind::FallsOffEnd(instruction) => write!(
+                f,
+                "the program can run on past its last instruction, {:?}, into the HLT padding",
+                instruction
+            ),
+            CompileErrorKind::UninitializedRead { instruction, cell } => write!(
+                f,
+                "{:?} reads RAM address {}, which nothing in the program writes",
+                instruction, cell
+            ),
+            CompileErrorKind::Unreachable(1) => {
+                write!(
+                    f,
+                    "unreachable: nothing jumps or falls through to this instruction"
+                )
+            }
+            CompileErrorKind::Unreachable(count) => write!(
+                f,
+                "unreachable: nothing jumps or falls through to this instruction or the {} after it",
+                count - 1
+            ),
+            CompileErrorKind::UnterminatedMacro(name) => {
+                write!(f, "macro `{}` has no `.endmacro`", name)
+            }
+            CompileErrorKind::MisplacedDirective(directive) => {
+                write!(f, "`{}` can't be used here", directive)
+            }
+            CompileErrorKind::RamExhausted { name, size, free } => write!(
+                f,
+                "no room in RAM for `{}`, which needs {} cells when {} are left",
+                name, size, free
+            ),
+            CompileErrorKind::DataOverlapsVariable { name, cells } => write!(
+                f,
+                "the data overlaps `{}`, in cells {}..={}",
+                name,
+                cells.start,
+                cells.end - 1
+            ),
+            CompileErrorKind::RepeatedDirective(directive) => {
+                write!(f, "`{}` can only be given once", directive)
+            }
+            CompileErrorKind::MacroArguments {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro `{}` takes {} argument(s) but was given {}",
+                name, expected, found
+            ),
+            CompileErrorKind::RecursiveMacro(chain) => {
+                write!(f, "macro expansion is recursive: {}", chain.join(" -> "))
+            }
+            CompileErrorKind::IncludeFailed { path, reason } => {
+                write!(f, "can't include `{}`: {}", path, reason)
+            }
+            CompileErrorKind::IncludeCycle(chain) => {
+                write!(f, "include cycle: {}", chain.join(" -> "))
+            }
+            CompileErrorKind::UndefinedLocal { label, scope } => {
+                write!(f, "local label `{}` is not defined in this scope", label)?;
+                match scope {
+                    Some(scope) => write!(f, " (after `{}`)", scope),
+                    None => Ok(()),
+                }
+            }
+            CompileErrorKind::UnexpectedOperands(tokens) => {
+                write!(f, "unexpected `{}` after the operands", tokens.join(" "))
+            }
+            CompileErrorKind::ProgramTooLong(length) => write!(
+                f,
+                "program is {} instructions, maximum is {}",
+                length, ROM_SIZE
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    file: Rc<str>,
+    /// 1-based, counting every line of the file.
+    line: usize,
+    /// The source line as written.
+    text: String,
+    kind: CompileErrorKind,
+    /// See SourceLine::context.
+    context: Vec<Context>,
+    /// Other lines the error is about, such as an earlier definition.
+    notes: Vec<Context>,
+}
+
+impl CompileError {
+    fn new(source: &SourceLine, kind: CompileErrorKind) -> CompileError {
+        CompileError {
+            file: source.file.clone(),
+            line: source.line,
+            text: source.text.clone(),
+            kind,
+            context: source.context.clone(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn noting(mut self, source: &SourceLine, note: &'static str) -> CompileError {
+        self.notes.push(Context {
+            file: source.file.clone(),
+            line: source.line,
+            text: source.text.clone(),
+            note,
+        });
+        self
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.kind)
+    }
+}
+
+impl Error for CompileError {}
+
+impl CompileErrorKind {
+    /// The token on the line the error is about, as written, for report to
+    /// underline.
+    fn token(&self) -> Option<&str> {
+        match self {
+            CompileErrorKind::UnknownInstruction { instruction, .. } => Some(instruction),
+            CompileErrorKind::InvalidNumber(token) => Some(token),
+            CompileErrorKind::InvalidLiteral { literal, .. } => Some(literal),
+            CompileErrorKind::InvalidExpression { expression, .. } => Some(expression),
+            CompileErrorKind::OutOfRange { operand, .. } => Some(operand),
+            CompileErrorKind::UndefinedSymbol { name, .. }
+            | CompileErrorKind::UnresolvedImport { name, .. }
+            | CompileErrorKind::RamExhausted { name, .. }
+            | CompileErrorKind::MacroArguments { name, .. } => Some(name),
+            CompileErrorKind::DuplicateSymbol(name) | CompileErrorKind::DuplicateExport(name) => {
+                Some(name)
+            }
+            CompileErrorKind::IgnoredOperand { operand, .. }
+            | CompileErrorKind::JumpToRam { operand, .. } => Some(operand),
+            CompileErrorKind::IncludeFailed { path, .. } => Some(path),
+            CompileErrorKind::UnexpectedOperands(tokens) => tokens.first().map(String::as_str),
+            CompileErrorKind::UndefinedLocal { label, .. } => Some(label),
+            _ => None,
+        }
+    }
+
+    /// A short hint to print under the underlined token: the range an
+    /// operand has to be in, or the names a misspelt one might have meant.
+    fn help(&self) -> Option<String> {
+        let suggestions = match self {
+            CompileErrorKind::OutOfRange { range, .. } => {
+                return Some(format!("expected {}", range))
+            }
+            CompileErrorKind::UnknownInstruction { suggestion, .. } => suggestion.as_slice(),
+            CompileErrorKind::UndefinedSymbol { suggestions, .. }
+            | CompileErrorKind::UnresolvedImport { suggestions, .. } => suggestions,
+            _ => return None,
+        };
+        let suggestion = did_you_mean(suggestions);
+        suggestion
+            .strip_prefix(", ")
+            .map(|suggestion| suggestion.to_string())
+    }
+
+    fn out_of_range(what: &'static str, value: i64, range: impl fmt::Display) -> CompileErrorKind {
+        CompileErrorKind::OutOfRange {
+            what,
+            operand: value.to_string(),
+            range: range.to_string(),
+        }
+    }
+
+    /// Puts out-of-range or unexpected operands back the way they were
+    /// written, given each operand's text and value.
+    fn respelled(mut self, operands: &[(String, i64)]) -> CompileErrorKind {
+        match &mut self {
+            CompileErrorKind::OutOfRange { operand, .. } => {
+                let spelling = operands
+                    .iter()
+                    .find(|(_, value)| value.to_string() == *operand);
+                if let Some((spelling, _)) = spelling {
+                    *operand = spelling.clone();
+                }
+            }
+            CompileErrorKind::UnexpectedOperands(tokens) if tokens.len() <= operands.len() => {
+                let spelling = &operands[operands.len() - tokens.len()..];
+                *tokens = spelling.iter().map(|(text, _)| text.clone()).collect();
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
+/// Splits a line on whitespace, keeping quoted text such as `' '` whole.
+fn tokens(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c.is_whitespace() => {
+                if let Some(start) = start.take() {
+                    tokens.push(&line[start..index]);
+                }
+            }
+            None => {
+                start.get_or_insert(index);
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+            }
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&line[start..]);
+    }
+    tokens
+}
+
+/// The character a backslash escape such as `\n` stands for.
+fn unescape(escape: char) -> Option<char> {
+    match escape {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' => Some('\\'),
+        '\'' => Some('\''),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
+/// The characters of a string literal such as `"Hi\n"`, with the same
+/// escapes as character literals.
+fn parse_string(token: &str) -> Result<Vec<i64>, CompileErrorKind> {
+    let invalid = |reason| CompileErrorKind::InvalidLiteral {
+        literal: token.to_string(),
+        reason,
+    };
+    let inner = token
+        .strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .ok_or(invalid("expected text between double quotes"))?;
+    let mut chars = inner.chars();
+    let mut values = Vec::new();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars
+                .next()
+                .and_then(unescape)
+                .ok_or(invalid("unsupported escape"))?,
+            c => c,
+        };
+        if !c.is_ascii() {
+            return Err(invalid("only ASCII characters are supported"));
+        }
+        values.push(c as i64);
+    }
+    Ok(values)
+}
+
+/// The value of a character literal such as `'H'` or `'\n'`.
+fn parse_char(token: &str) -> Result<i64, CompileErrorKind> {
+    let invalid = |reason| CompileErrorKind::InvalidLiteral {
+        literal: token.to_string(),
+        reason,
+    };
+    let inner = token
+        .strip_prefix('\'')
+        .and_then(|inner| inner.strip_suffix('\''))
+        .filter(|inner| !inner.is_empty())
+        .ok_or(invalid("expected one character between single quotes"))?;
+    let c = match inner.strip_prefix('\\') {
+        Some(escape) => {
+            let mut chars = escape.chars();
+            match (chars.next().and_then(unescape), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(invalid("unsupported escape")),
+            }
+        }
+        None => {
+            let mut chars = inner.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(invalid("more than one character")),
+            }
+        }
+    };
+    if !c.is_ascii() {
+        return Err(invalid("only ASCII characters are supported"));
+    }
+    Ok(c as i64)
+}
+
+/// Parses an integer literal: decimal, or `0x` hex, `0b` binary or `0o`
+/// octal, each with an optional leading `-` and `_` digit separators, or a
+/// character literal.
+fn parse_number(token: &str) -> Result<i64, CompileErrorKind> {
+    if token.starts_with('\'') {
+        return parse_char(token);
+    }
+    let invalid = || CompileErrorKind::InvalidNumber(token.to_string());
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        _ => (10, digits),
+    };
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return Err(invalid());
+    }
+    let magnitude = i128::from_str_radix(&digits, radix).map_err(|_| invalid())?;
+    let value = if negative { -magnitude } else { magnitude };
+    i64::try_from(value).map_err(|_| CompileErrorKind::OutOfRange {
+        what: "operand",
+        operand: token.to_string(),
+        range: immediate_range(),
+    })
+}
+
 impl FromStr for RomLayout {
-    type Err = ();
+    type Err = CompileErrorKind;
     fn from_str(input: &str) -> Result<RomLayout, Self::Err> {
-        let mut input = input.split(' ').rev().collect::<Vec<&str>>();
-        let instruction = input.pop().ok_or(())?;
+        RomLayout::parse(input).map(|(word, _)| word)
+    }
+}
+
+impl RomLayout {
+    /// Assembles one instruction. An operand given to an instruction that
+    /// takes none is ignored and returned, so the caller can warn about it;
+    /// any other token left over is an error.
+    fn parse(input: &str) -> Result<(RomLayout, Option<&str>), CompileErrorKind> {
+        let mut input = tokens(input);
+        input.reverse();
+        let mnemonic = input.pop().ok_or(CompileErrorKind::MissingInstruction)?;
+        // Mnemonics are case-insensitive: `lda`, `Lda` and `LDA` are all LDA.
+        let instruction = mnemonic.to_ascii_uppercase();
+        let has_operand = !input.is_empty();
+        let given = input.len();
 
-        let mut value = || -> Result<i64, Self::Err> {
-            let s = input.pop().ok_or(())?;
-            s.parse::<i64>().or(Err(()))
+        // Every operand has to survive the round trip through the 56-bit
+        // value field, or the program would silently use a different number.
+        let mut value = || -> Result<i64, CompileErrorKind> {
+            let s = input
+                .pop()
+                .ok_or_else(|| CompileErrorKind::MissingOperand(instruction.to_string()))?;
+            immediate(parse_number(s)?)
         };
 
-        match instruction {
+        let word = match instruction.as_str() {
             "NOP" => Ok(RomLayout::new(Instruction::NOP, 0)),
             "LDA" => Ok(RomLayout::new(Instruction::LDA, value()?)),
             "STA" => Ok(RomLayout::new(Instruction::STA, value()?)),
             "ADD" => Ok(RomLayout::new(Instruction::ADD, value()?)),
             "SUB" => Ok(RomLayout::new(Instruction::SUB, value()?)),
-            "OUT" => Ok(RomLayout::new(Instruction::OUT, 0)),
+            "OUT" => {
+                let port = if has_operand { value()? } else { 0 };
+                Ok(RomLayout::new(Instruction::OUT, port))
+            }
             "JMP" => Ok(RomLayout::new(Instruction::JMP, value()?)),
             "JC" => Ok(RomLayout::new(Instruction::JC, value()?)),
             "JZ" => Ok(RomLayout::new(Instruction::JZ, value()?)),
-            "HLT" => Ok(RomLayout::new(Instruction::HLT, 0)),
+            "HLT" => {
+                let exit_code = if has_operand { value()? } else { 0 };
+                Ok(RomLayout::new(Instruction::HLT, exit_code))
+            }
+            // Wider constants are built with an LDI of the low 56 bits (as a
+            // signed value) followed by an LDH of the top byte.
             "LDI" => Ok(RomLayout::new(Instruction::LDI, value()?)),
             "ADI" => Ok(RomLayout::new(Instruction::ADI, value()?)),
             "LDR" => Ok(RomLayout::new(Instruction::LDR, value()?)),
             "ADR" => Ok(RomLayout::new(Instruction::ADR, value()?)),
-            _ => todo!(),
+            "SUI" => Ok(RomLayout::new(Instruction::SUI, value()?)),
+            "MUL" => Ok(RomLayout::new(Instruction::MUL, value()?)),
+            "MLI" => Ok(RomLayout::new(Instruction::MLI, value()?)),
+            "DIV" => Ok(RomLayout::new(Instruction::DIV, value()?)),
+            "MOD" => Ok(RomLayout::new(Instruction::MOD, value()?)),
+            "AND" => Ok(RomLayout::new(Instruction::AND, value()?)),
+            "OR" => Ok(RomLayout::new(Instruction::OR, value()?)),
+            "XOR" => Ok(RomLayout::new(Instruction::XOR, value()?)),
+            "ANI" => Ok(RomLayout::new(Instruction::ANI, value()?)),
+            "ORI" => Ok(RomLayout::new(Instruction::ORI, value()?)),
+            "XRI" => Ok(RomLayout::new(Instruction::XRI, value()?)),
+            "NOT" => Ok(RomLayout::new(Instruction::NOT, 0)),
+            "NEG" => Ok(RomLayout::new(Instruction::NEG, 0)),
+            "SHL" => Ok(RomLayout::new(Instruction::SHL, value()?)),
+            "SHR" => Ok(RomLayout::new(Instruction::SHR, value()?)),
+            "ROL" => Ok(RomLayout::new(Instruction::ROL, 0)),
+            "ROR" => Ok(RomLayout::new(Instruction::ROR, 0)),
+            "INC" => Ok(RomLayout::new(Instruction::INC, value()?)),
+            "DEC" => Ok(RomLayout::new(Instruction::DEC, value()?)),
+            "CMP" => Ok(RomLayout::new(Instruction::CMP, value()?)),
+            "CPI" => Ok(RomLayout::new(Instruction::CPI, value()?)),
+            "JNZ" => Ok(RomLayout::new(Instruction::JNZ, value()?)),
+            "JNC" => Ok(RomLayout::new(Instruction::JNC, value()?)),
+            "JN" => Ok(RomLayout::new(Instruction::JN, value()?)),
+            "JP" => Ok(RomLayout::new(Instruction::JP, value()?)),
+            "LDB" => Ok(RomLayout::new(Instruction::LDB, value()?)),
+            "STB" => Ok(RomLayout::new(Instruction::STB, value()?)),
+            "TAB" => Ok(RomLayout::new(Instruction::TAB, 0)),
+            "TBA" => Ok(RomLayout::new(Instruction::TBA, 0)),
+            "ADB" => Ok(RomLayout::new(Instruction::ADB, 0)),
+            "PUSH" => Ok(RomLayout::new(Instruction::PUSH, 0)),
+            "POP" => Ok(RomLayout::new(Instruction::POP, 0)),
+            "CALL" => Ok(RomLayout::new(Instruction::CALL, value()?)),
+            "RET" => Ok(RomLayout::new(Instruction::RET, 0)),
+            "LDP" => Ok(RomLayout::new(Instruction::LDP, value()?)),
+            "STP" => Ok(RomLayout::new(Instruction::STP, value()?)),
+            "LDX" => Ok(RomLayout::new(Instruction::LDX, value()?)),
+            "INX" => Ok(RomLayout::new(Instruction::INX, 0)),
+            "DEX" => Ok(RomLayout::new(Instruction::DEX, 0)),
+            "LAX" => Ok(RomLayout::new(Instruction::LAX, value()?)),
+            "SAX" => Ok(RomLayout::new(Instruction::SAX, value()?)),
+            "IN" => Ok(RomLayout::new(Instruction::IN, 0)),
+            "OUTC" => Ok(RomLayout::new(Instruction::OUTC, 0)),
+            "OUTH" => Ok(RomLayout::new(Instruction::OUTH, 0)),
+            "OUTB" => Ok(RomLayout::new(Instruction::OUTB, 0)),
+            "OUTS" => Ok(RomLayout::new(Instruction::OUTS, value()?)),
+            "JR" => Ok(RomLayout::new(Instruction::JR, value()?)),
+            "RND" => Ok(RomLayout::new(Instruction::RND, 0)),
+            "CLC" => Ok(RomLayout::new(Instruction::CLC, 0)),
+            "SEC" => Ok(RomLayout::new(Instruction::SEC, 0)),
+            "CLZ" => Ok(RomLayout::new(Instruction::CLZ, 0)),
+            "JMA" => Ok(RomLayout::new(Instruction::JMA, 0)),
+            "BRK" => Ok(RomLayout::new(Instruction::BRK, 0)),
+            "DBG" => {
+                // Without an operand no RAM cell is shown.
+                let address = if has_operand { value()? } else { -1 };
+                Ok(RomLayout::new(Instruction::DBG, address))
+            }
+            "XCHG" => Ok(RomLayout::new(Instruction::XCHG, value()?)),
+            "ABS" => Ok(RomLayout::new(Instruction::ABS, 0)),
+            "BIT" => Ok(RomLayout::new(Instruction::BIT, value()?)),
+            "TFA" => Ok(RomLayout::new(Instruction::TFA, 0)),
+            "TAF" => Ok(RomLayout::new(Instruction::TAF, 0)),
+            "SLP" => match value()? {
+                ms @ 0..=MAX_SLEEP_MS => Ok(RomLayout::new(Instruction::SLP, ms)),
+                ms => Err(CompileErrorKind::out_of_range(
+                    "sleep",
+                    ms,
+                    format!("0..={} ms", MAX_SLEEP_MS),
+                )),
+            },
+            "MCP" => {
+                let fields = [value()?, value()?, value()?];
+                Ok(RomLayout::new(Instruction::MCP, pack_fields(&fields)?))
+            }
+            "ASR" => Ok(RomLayout::new(Instruction::ASR, value()?)),
+            "JSR" => Ok(RomLayout::new(Instruction::JSR, value()?)),
+            "RSR" => Ok(RomLayout::new(Instruction::RSR, 0)),
+            "STR" => Ok(RomLayout::new(Instruction::STR, value()?)),
+            "STRI" => Ok(RomLayout::new(Instruction::STRI, value()?)),
+            "MOV" => {
+                let fields = [value()?, value()?];
+                Ok(RomLayout::new(Instruction::MOV, pack_fields(&fields)?))
+            }
+            "ADC" => Ok(RomLayout::new(Instruction::ADC, value()?)),
+            "SBC" => Ok(RomLayout::new(Instruction::SBC, value()?)),
+            "DJNZ" => {
+                let fields = [value()?, value()?];
+                Ok(RomLayout::new(Instruction::DJNZ, pack_fields(&fields)?))
+            }
+            // The address takes the low byte and the 32-bit signed value the
+            // bits above it.
+            "STI" => {
+                let (address, constant) = (value()?, value()?);
+                if !(0..=255).contains(&address) {
+                    return Err(CompileErrorKind::out_of_range(
+                        "address", address, "0..=255",
+                    ));
+                }
+                if i32::try_from(constant).is_err() {
+                    return Err(CompileErrorKind::out_of_range(
+                        "constant",
+                        constant,
+                        format!("{}..={}", i32::MIN, i32::MAX),
+                    ));
+                }
+                Ok(RomLayout::new(Instruction::STI, constant << 8 | address))
+            }
+            "SMB" | "RMB" => {
+                let (address, bit) = (value()?, value()?);
+                if !(0..64).contains(&bit) {
+                    return Err(CompileErrorKind::out_of_range("bit", bit, "0..=63"));
+                }
+                let instruction = match instruction.as_str() {
+                    "SMB" => Instruction::SMB,
+                    _ => Instruction::RMB,
+                };
+                Ok(RomLayout::new(instruction, pack_fields(&[address, bit])?))
+            }
+            "LDH" => match value()? {
+                byte @ 0..=255 => Ok(RomLayout::new(Instruction::LDH, byte)),
+                byte => Err(CompileErrorKind::out_of_range("byte", byte, "0..=255")),
+            },
+            "JO" => Ok(RomLayout::new(Instruction::JO, value()?)),
+            "JNO" => Ok(RomLayout::new(Instruction::JNO, value()?)),
+            _ => Err(CompileErrorKind::UnknownInstruction {
+                instruction: mnemonic.to_string(),
+                suggestion: closest_mnemonic(mnemonic),
+            }),
+        }?;
+        if given > 0 {
+            operand_kind(word.instruction).check(word.value.into())?;
+        }
+
+        // Leftover tokens are usually two instructions run together.
+        input.reverse();
+        match input.as_slice() {
+            [] => Ok((word, None)),
+            // Nothing was taken, so the instruction has no operand.
+            [operand] if given == 1 => Ok((word, Some(operand))),
+            unexpected => Err(CompileErrorKind::UnexpectedOperands(
+                unexpected.iter().map(|token| token.to_string()).collect(),
+            )),
         }
     }
 }
 
+/// Edit distance counting insertions, deletions, substitutions and swaps of
+/// neighbouring chars, so `HTL` is one edit from `HLT`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Up to `limit` of the `candidates` close enough to `name` to be plausible
+/// typos of it, nearest first and otherwise in the order given.
+fn closest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    limit: usize,
+) -> Vec<String> {
+    let mut close: Vec<_> = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, candidate)| distance <= 2 && distance < candidate.len())
+        .collect();
+    close.sort_by_key(|&(distance, _)| distance);
+    close
+        .into_iter()
+        .take(limit)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// `, did you mean ...?` for the suggestions from closest, or nothing.
+fn did_you_mean(suggestions: &[String]) -> String {
+    let quoted: Vec<String> = suggestions.iter().map(|s| format!("`{}`", s)).collect();
+    match quoted.as_slice() {
+        [] => String::new(),
+        [only] => format!(", did you mean {}?", only),
+        [rest @ .., last] => format!(", did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+/// The mnemonic nearest to a misspelt one, if any is close enough to be a
+/// plausible typo. Case is ignored, so `lda` suggests `LDA`.
+fn closest_mnemonic(instruction: &str) -> Option<String> {
+    let mnemonics: Vec<String> = Instruction::all_variants()
+        .iter()
+        .map(|variant| variant.to_display_str().into_owned())
+        .collect();
+    let instruction = instruction.to_ascii_uppercase();
+    closest(&instruction, mnemonics.iter().map(String::as_str), 1).pop()
+}
+
+/// The defined symbols most like an undefined one.
+fn closest_symbols(symbols: &HashMap<&str, i64>, name: &str) -> Vec<String> {
+    let mut names: Vec<&str> = symbols.keys().copied().collect();
+    names.sort();
+    closest(name, names, 3)
+}
+
+/// The longest pause SLP will assemble, in milliseconds.
+const MAX_SLEEP_MS: i64 = 10_000;
+
 const ROM_SIZE: usize = 256;
 
-type ROM = [RomLayout; ROM_SIZE];
-type RAM = [i64; 256];
+/// The range of the signed 56-bit value field of a ROM word.
+const IMMEDIATE_MIN: i64 = -(1 << 55);
+const IMMEDIATE_MAX: i64 = (1 << 55) - 1;
+
+/// Checks that `value` fits the signed 56-bit value field of a ROM word.
+fn immediate(value: i64) -> Result<i64, CompileErrorKind> {
+    match value {
+        IMMEDIATE_MIN..=IMMEDIATE_MAX => Ok(value),
+        _ => Err(CompileErrorKind::out_of_range(
+            "operand",
+            value,
+            immediate_range(),
+        )),
+    }
+}
+
+fn immediate_range() -> String {
+    format!(
+        "{}..={} (56 bits); use LDI with LDH for wider constants",
+        IMMEDIATE_MIN, IMMEDIATE_MAX
+    )
+}
+
+/// Instructions taking several operands (such as `MCP src dst len`) pack
+/// them into the value field as 8-bit fields, the first operand in the
+/// lowest byte. Each operand must fit in 0..=255.
+fn pack_fields(fields: &[i64]) -> Result<i64, CompileErrorKind> {
+    fields
+        .iter()
+        .rev()
+        .try_fold(0, |packed, &field| match field {
+            0..=255 => Ok(packed << 8 | field),
+            _ => Err(CompileErrorKind::out_of_range("operand", field, "0..=255")),
+        })
+}
+
+fn field(value: i64, index: usize) -> usize {
+    (value >> (8 * index)) as usize & 0xFF
+}
+
+const RAM_SIZE: usize = 256;
+
+/// The stack grows down from here: PUSH pre-decrements and POP
+/// post-increments, so the stack lives in ram[0..STACK_TOP] and the cell at
+/// STACK_TOP itself is never written by the stack.
+const STACK_TOP: u8 = 255;
+
+/// JSR leaves its return address here for RSR, so programs using JSR must
+/// not keep their own data in this cell. It sits just above the stack.
+const LINK_CELL: usize = STACK_TOP as usize;
+
+type Rom = [RomLayout; ROM_SIZE];
+type Ram = [i64; RAM_SIZE];
 
 bitflags! {
+    /// The bit values are visible to programs through TFA and TAF, so they
+    /// must not change: CARRY is bit 0, ZERO bit 1, NEGATIVE bit 2 and
+    /// OVERFLOW bit 3. JUMP is internal to the sequencer and is never exposed.
+    ///
+    /// Every instruction that sets flags sets ZERO and NEGATIVE from its
+    /// result. CARRY and OVERFLOW depend on the instruction:
+    ///
+    /// * ADD, ADI, ADR, ADB, ADC, INC, INX: CARRY is the unsigned carry out,
+    ///   OVERFLOW the signed overflow.
+    /// * SUB, SUI, SBC, CMP, CPI, DEC, DEX, DJNZ: CARRY is the unsigned borrow,
+    ///   OVERFLOW the signed overflow.
+    /// * MUL, MLI, NEG, ABS: both mean the signed result didn't fit.
+    /// * SHL, SHR, ASR, ROL, ROR: CARRY is the bit shifted out, OVERFLOW is
+    ///   cleared.
+    /// * DIV, MOD, AND, OR, XOR, ANI, ORI, XRI, NOT, BIT: both are cleared.
+    /// * IN: both cleared, except that EOF sets CARRY.
+    /// * LDA, LDI, LDR, LDH, LDP, LAX, POP, TBA: loads into reg_a set ZERO
+    ///   and NEGATIVE from the loaded value and leave CARRY and OVERFLOW
+    ///   alone, so `LDA x` / `JZ done` works without a dummy `ADI 0`.
+    ///
+    /// Everything else leaves the flags alone, apart from CLC, SEC, CLZ and
+    /// TAF which exist to change them.
+    ///
+    /// flags_affected() is the table form of this and is what `--describe-isa`
+    /// prints. Debug builds check every step against it.
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
     struct ProgramFlags: u64 {
         const NONE  = 0x00000000;
         const CARRY = 0x00000001;
         const ZERO  = 0x00000002;
+        const NEGATIVE = 0x00000004;
+        const OVERFLOW = 0x00000008;
         const JUMP  = 0x00000010;
     }
 }
 
-struct ProgramState {
-    program_counter: u8, // same size as the ROM
-    ram: RAM,
-    rom: ROM,
-    reg_a: i64,
-    reg_jump: u8,
-    flags: ProgramFlags,
+/// What an instruction does to a single flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Effect {
+    Kept,
+    /// Computed from the instruction's result.
+    Updated,
+    Cleared,
+    Set,
 }
 
-impl ProgramState {
-    fn new(rom: ROM) -> ProgramState {
-        ProgramState {
-            program_counter: 0,
-            ram: [0; 256],
-            rom,
-            reg_a: 0,
-            reg_jump: 0,
-            flags: ProgramFlags::NONE,
+impl Effect {
+    fn allows(self, before: bool, after: bool) -> bool {
+        match self {
+            Effect::Kept => before == after,
+            Effect::Updated => true,
+            Effect::Cleared => !after,
+            Effect::Set => after,
         }
     }
 
-    fn step(&mut self) -> Instruction {
-        let rom = self.rom[self.program_counter as usize];
-        let rom_value_index = rom.value.to_primitive() as usize;
-
-        match rom.instruction {
-            Instruction::NOP => {}
-            Instruction::LDA => self.reg_a = self.ram[rom_value_index],
-            Instruction::STA => self.ram[rom_value_index] = self.reg_a,
-            Instruction::ADD => self.alu(self.ram[rom_value_index]),
-            Instruction::SUB => todo!(),
-            Instruction::OUT => println!("{}", self.reg_a),
-            Instruction::JMP => {
-                self.flags.insert(ProgramFlags::JUMP);
-                self.reg_jump = rom_value_index.try_into().unwrap();
-            }
-            Instruction::JC => {
-                if self.flags.contains(ProgramFlags::CARRY) {
-                    self.flags.insert(ProgramFlags::JUMP);
-                    self.reg_jump = rom_value_index.try_into().unwrap();
-                }
-            }
-            Instruction::JZ => {
-                if self.flags.contains(ProgramFlags::ZERO) {
-                    self.flags.insert(ProgramFlags::JUMP);
-                    self.reg_jump = rom_value_index.try_into().unwrap();
-                }
-            }
-            Instruction::HLT => {}
-            Instruction::LDI => self.reg_a = rom.value.into(),
-            Instruction::ADI => todo!(),
-            Instruction::LDR => self.reg_a = self.rom[rom_value_index].value.into(),
-            Instruction::ADR => todo!(),
+    fn symbol(self) -> char {
+        match self {
+            Effect::Kept => '-',
+            Effect::Updated => '*',
+            Effect::Cleared => '0',
+            Effect::Set => '1',
         }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FlagEffect {
+    carry: Effect,
+    zero: Effect,
+    negative: Effect,
+    overflow: Effect,
+}
+
+impl FlagEffect {
+    const NONE: FlagEffect =
+        FlagEffect::new(Effect::Kept, Effect::Kept, Effect::Kept, Effect::Kept);
+    const ARITHMETIC: FlagEffect = FlagEffect::new(
+        Effect::Updated,
+        Effect::Updated,
+        Effect::Updated,
+        Effect::Updated,
+    );
+    const SHIFT: FlagEffect = FlagEffect::new(
+        Effect::Updated,
+        Effect::Updated,
+        Effect::Updated,
+        Effect::Cleared,
+    );
+    const LOGIC: FlagEffect = FlagEffect::new(
+        Effect::Cleared,
+        Effect::Updated,
+        Effect::Updated,
+        Effect::Cleared,
+    );
+    const LOAD: FlagEffect =
+        FlagEffect::new(Effect::Kept, Effect::Updated, Effect::Updated, Effect::Kept);
 
-        self.count();
-        rom.instruction
+    const fn new(carry: Effect, zero: Effect, negative: Effect, overflow: Effect) -> FlagEffect {
+        FlagEffect {
+            carry,
+            zero,
+            negative,
+            overflow,
+        }
     }
 
-    fn alu(&mut self, value: i64) {
-        let (value, carry) = self.reg_a.overflowing_add(value);
-        self.flags.set(ProgramFlags::CARRY, carry);
-        self.flags.set(ProgramFlags::ZERO, value == 0);
-        self.reg_a = value;
+    fn effects(self) -> [(ProgramFlags, Effect); 4] {
+        [
+            (ProgramFlags::CARRY, self.carry),
+            (ProgramFlags::ZERO, self.zero),
+            (ProgramFlags::NEGATIVE, self.negative),
+            (ProgramFlags::OVERFLOW, self.overflow),
+        ]
     }
 
-    fn count(&mut self) {
-        if self.flags.contains(ProgramFlags::JUMP) {
-            self.program_counter = self.reg_jump;
-            self.flags.remove(ProgramFlags::JUMP);
-        } else {
-            self.program_counter += 1;
-        }
+    /// Whether going from `before` to `after` is something this effect can do.
+    fn allows(self, before: ProgramFlags, after: ProgramFlags) -> bool {
+        self.effects()
+            .iter()
+            .all(|&(flag, effect)| effect.allows(before.contains(flag), after.contains(flag)))
     }
 }
 
-fn compile_rom(program: &str) -> Vec<RomLayout> {
-    let rom: Result<Vec<_>, ()> = program
-        .trim()
-        .lines()
-        .map(|s| RomLayout::from_str(s))
-        .into_iter()
-        .collect();
-    rom.unwrap()
+/// Whether execution can go on to the next instruction after this one.
+/// Calls can, as they return to it; HLT and unconditional jumps can't.
+fn falls_through(instruction: Instruction) -> bool {
+    use Instruction::*;
+    !matches!(instruction, HLT | JMP | JR | JMA | RET | RSR)
 }
 
-fn read_rom(bytes: &[u8]) -> ROM {
-    let mut rom = Vec::new();
-    for chunk in bytes.chunks(8) {
-        rom.push(RomLayout::unpack_from_slice(chunk).unwrap());
+/// Where the jump or call at `address` goes, for those whose target is in
+/// the word: not JMA, which jumps to A, nor RET or RSR.
+fn jump_target(word: RomLayout, address: usize) -> Option<i64> {
+    use Instruction::*;
+    let value: i64 = word.value.into();
+    match word.instruction {
+        JR => Some(address as i64 + value),
+        DJNZ => Some(field(value, 1) as i64),
+        // These address words of data, not code.
+        LDR | ADR | STR | STRI => None,
+        instruction => (operand_kind(instruction) == OperandKind::RomAddress).then_some(value),
     }
+}
 
-    rom.resize(ROM_SIZE, RomLayout::new(Instruction::HLT, 0));
-    rom.try_into().unwrap()
+/// The ROM addresses LDR, ADR and STR use as data.
+fn data_words(rom: &[RomLayout]) -> HashSet<usize> {
+    rom.iter()
+        .filter(|word| {
+            matches!(
+                word.instruction,
+                Instruction::LDR | Instruction::ADR | Instruction::STR
+            )
+        })
+        .filter_map(|word| usize::try_from(i64::from(word.value)).ok())
+        .collect()
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = Args::new("Emulator 64Bit (Rust)", "It isn't that exciting.");
-    args.option(
-        "c",
-        "compile",
-        "Used to compile a ebr file into bytecode.",
-        "FILE",
-        Occur::Optional,
-        None,
-    );
-    args.option(
-        "o",
-        "output",
-        "Used to name the outputted ebrc file from -c.",
-        "FILE",
-        Occur::Optional,
-        None,
-    );
-    args.option(
-        "r",
-        "run",
-        "Used to run a ebrc file",
-        "FILE",
-        Occur::Optional,
-        None,
-    );
+/// Which ROM words execution can reach from `start`, by falling through and
+/// by every jump and call whose target is known when assembling. None if a
+/// reachable JMA, STR, STRI, RET or RSR means anything could run: what they
+/// go to, or the jump they rewrite, can be a label the program pushed or
+/// stored as a plain value.
+fn reachable(rom: &[RomLayout], start: usize) -> Option<Vec<bool>> {
+    use Instruction::*;
+    let mut reached = vec![false; rom.len()];
+    let mut pending = vec![start];
+    while let Some(address) = pending.pop() {
+        let Some(word) = rom.get(address) else {
+            continue;
+        };
+        if reached[address] {
+            continue;
+        }
+        reached[address] = true;
+        if matches!(word.instruction, JMA | STR | STRI | RET | RSR) {
+            return None;
+        }
+        let target = jump_target(*word, address).and_then(|target| usize::try_from(target).ok());
+        pending.extend(target);
+        if falls_through(word.instruction) {
+            pending.push(address + 1);
+        }
+    }
+    Some(reached)
+}
 
-    args.parse(std::env::args().collect::<Vec<_>>())?;
+/// The peephole pass of -O. It drops NOPs, an LDI or LDA straight before an
+/// LDI, and the LDA of `STA x` then `LDA x` when A's flags already match x,
+/// then moves each jump and `start` onto the words left, returning where
+/// each old address went. A word that jumps land on is only dropped if going
+/// on to the next word instead does the same, and the words LDR and ADR use
+/// as data are always kept. So are the words at `checkpoints`, and an
+/// LDI or LDA whose value of A a later checkpoint would see. None, with
+/// nothing changed, for a program with JMA, STR, STRI, RET or RSR, as where
+/// they go can't be followed: a return address or a rewritten jump can be a
+/// label pushed or stored as a plain value, which relocating the jumps
+/// wouldn't move.
+fn peephole(
+    rom: &mut Vec<RomLayout>,
+    sources: &mut Vec<Option<SourceLine>>,
+    start: &mut u8,
+    checkpoints: &HashSet<usize>,
+) -> Option<Vec<usize>> {
+    use Instruction::*;
+    if rom
+        .iter()
+        .any(|word| matches!(word.instruction, JMA | STR | STRI | RET | RSR))
+    {
+        return None;
+    }
+    let mut targets = HashSet::from([*start as usize]);
+    for (address, word) in rom.iter().enumerate() {
+        let target = jump_target(*word, address).and_then(|target| usize::try_from(target).ok());
+        targets.extend(target);
+    }
+    let data = data_words(rom);
+    // Whether the instruction leaves A's value in ZERO and NEGATIVE.
+    let flags_a = |instruction| {
+        matches!(
+            instruction,
+            LDI | LDA
+                | LDR
+                | ADD
+                | ADI
+                | ADR
+                | SUB
+                | SUI
+                | MUL
+                | MLI
+                | AND
+                | OR
+                | XOR
+                | ANI
+                | ORI
+                | XRI
+                | NOT
+                | NEG
+                | ADC
+                | SBC
+                | ADB
+                | TBA
+                | POP
+        )
+    };
 
-    let source = args.value_of::<String>("compile");
-    let output = args.value_of::<String>("output");
-    let rom = args.value_of::<String>("run");
+    let mut kept = vec![true; rom.len()];
+    'search: loop {
+        let live: Vec<usize> = (0..rom.len()).filter(|&address| kept[address]).collect();
+        for (index, &address) in live.iter().enumerate() {
+            let word = |offset: isize| {
+                let address = *live.get(index.checked_add_signed(offset)?)?;
+                Some((
+                    address,
+                    rom[address].instruction,
+                    i64::from(rom[address].value),
+                ))
+            };
+            let (_, instruction, value) = word(0).unwrap();
+            let redundant = match (word(-2), word(-1), instruction, word(1)) {
+                _ if data.contains(&address) || checkpoints.contains(&address) => false,
+                (_, _, NOP, _) => true,
+                (_, _, LDI | LDA, Some((next, LDI, _))) => !checkpoints.contains(&next),
+                (Some((_, before, _)), Some((stored, STA, x)), LDA, _) => {
+                    x == value
+                        && flags_a(before)
+                        && !targets.contains(&stored)
+                        && !targets.contains(&address)
+                }
+                _ => false,
+            };
+            if redundant {
+                kept[address] = false;
+                continue 'search;
+            }
+        }
+        break;
+    }
 
-    if let Ok(source) = source {
-        let source = std::fs::read_to_string(source)?;
-        let rom = compile_rom(source.as_str());
-        let rom: Vec<u8> = rom.iter().map(|r| r.pack().unwrap()).flatten().collect();
+    let length = rom.len();
+    let mut moved = Vec::new();
+    let mut next = 0;
+    for address in 0..=length.max(ROM_SIZE) {
+        moved.push(next);
+        if kept.get(address).copied().unwrap_or(true) {
+            next += 1;
+        }
+    }
+    let relocate = |address: i64| match usize::try_from(address) {
+        Ok(address) if address < moved.len() => moved[address] as i64,
+        _ => address,
+    };
+    let mut optimized = Vec::new();
+    for (address, word) in rom.iter().enumerate().filter(|&(address, _)| kept[address]) {
+        let value: i64 = word.value.into();
+        let value = match word.instruction {
+            JR => relocate(address as i64 + value) - moved[address] as i64,
+            DJNZ => value & !0xFF00 | relocate(field(value, 1) as i64) << 8,
+            instruction if operand_kind(instruction) == OperandKind::RomAddress => relocate(value),
+            _ => value,
+        };
+        optimized.push(RomLayout::new(word.instruction, value));
+    }
+    *rom = optimized;
+    let mut address = 0;
+    sources.retain(|_| {
+        address += 1;
+        kept[address - 1]
+    });
+    *start = moved[*start as usize] as u8;
+    Some(moved)
+}
 
-        let output = match output {
-            Ok(output) => output,
-            Err(_) => "a.ebrc".to_string(),
+/// The RAM reads of addresses that no instruction or data block writes, as
+/// the ROM address of the read and the RAM address read. None if the program
+/// has an STP or SAX, which could write anywhere. Nothing written by the
+/// stack is counted, so reading what PUSH left with LDA is reported too.
+fn uninitialized_reads(rom: &[RomLayout], data: &[DataBlock]) -> Option<Vec<(usize, usize)>> {
+    use Instruction::*;
+    let mut written = vec![false; RAM_SIZE];
+    for block in data {
+        written[block.address..][..block.values.len()].fill(true);
+    }
+    let mut reads = Vec::new();
+    for (address, word) in rom.iter().enumerate() {
+        let value: i64 = word.value.into();
+        // Packed operands hold the cell in a field, whatever the sign of the
+        // whole value; a plain address that's negative is out of RAM.
+        let cell = usize::try_from(value);
+        let cells = match word.instruction {
+            STP | SAX => return None,
+            STI | SMB | RMB | DJNZ => field(value, 0)..field(value, 0) + 1,
+            MOV => field(value, 1)..field(value, 1) + 1,
+            MCP => field(value, 1)..field(value, 1) + field(value, 2),
+            JSR => LINK_CELL..LINK_CELL + 1,
+            _ => {
+                let Ok(cell) = cell else {
+                    continue;
+                };
+                match word.instruction {
+                    STA | STB | XCHG | INC | DEC => cell..cell + 1,
+                    LDA | ADD | SUB | MUL | DIV | MOD | AND | OR | XOR | CMP | LDB | ADC | SBC
+                    | BIT | LDP | OUTS => {
+                        reads.push((address, cell));
+                        continue;
+                    }
+                    _ => continue,
+                }
+            }
         };
+        for cell in cells.filter(|&cell| cell < RAM_SIZE) {
+            written[cell] = true;
+        }
+    }
+    reads.retain(|&(_, cell)| cell < RAM_SIZE && !written[cell]);
+    Some(reads)
+}
+
+/// Whether `source` has an `allow(lint)` comment, saying a warning about it
+/// is expected.
+fn allows(source: &SourceLine, lint: &str) -> bool {
+    let comment = &source.text[strip_comment(&source.text).len()..];
+    comment.contains(&format!("allow({})", lint))
+}
 
-        std::fs::write(output, rom)?;
+/// The flag contract of every instruction. The match is exhaustive on
+/// purpose, so a new instruction can't be added without deciding its flags.
+fn flags_affected(instruction: Instruction) -> FlagEffect {
+    use Instruction::*;
+    match instruction {
+        ADD | ADI | ADR | ADB | ADC | INC | INX | SUB | SUI | SBC | CMP | CPI | DEC | DEX
+        | DJNZ | MUL | MLI | NEG | ABS => FlagEffect::ARITHMETIC,
+        SHL | SHR | ASR | ROL | ROR | IN => FlagEffect::SHIFT,
+        DIV | MOD | AND | OR | XOR | ANI | ORI | XRI | NOT | BIT => FlagEffect::LOGIC,
+        LDA | LDI | LDR | LDH | LDP | LAX | POP | TBA => FlagEffect::LOAD,
+        CLC => FlagEffect {
+            carry: Effect::Cleared,
+            ..FlagEffect::NONE
+        },
+        SEC => FlagEffect {
+            carry: Effect::Set,
+            ..FlagEffect::NONE
+        },
+        CLZ => FlagEffect {
+            zero: Effect::Cleared,
+            ..FlagEffect::NONE
+        },
+        TAF => FlagEffect::ARITHMETIC,
+        NOP | STA | OUT | JMP | JC | JZ | HLT | JNZ | JNC | JN | JP | LDB | STB | TAB | PUSH
+        | CALL | RET | STP | LDX | SAX | OUTC | OUTH | OUTB | OUTS | JR | RND | JMA | BRK | DBG
+        | XCHG | TFA | SLP | MCP | JSR | RSR | STR | STRI | MOV | STI | SMB | RMB | JO | JNO => {
+            FlagEffect::NONE
+        }
     }
+}
 
-    if let Ok(run) = rom {
-        let rom = std::fs::read(run)?;
-        let mut state = ProgramState::new(read_rom(&rom));
-        loop {
-            if state.step() == Instruction::HLT {
-                break;
-            }
+/// What an instruction's operand is, and so which values it accepts.
+/// Addresses are checked at assembly time rather than left to fault at
+/// runtime.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OperandKind {
+    None,
+    /// A RAM cell, 0..=255.
+    RamAddress,
+    /// A ROM slot, 0..=255.
+    RomAddress,
+    /// A signed 56-bit constant. Some instructions narrow this further.
+    Immediate,
+    /// Several small fields packed into the value, checked as they're packed.
+    Fields,
+}
+
+impl OperandKind {
+    fn name(self) -> &'static str {
+        match self {
+            OperandKind::None => "-",
+            OperandKind::RamAddress => "ram",
+            OperandKind::RomAddress => "rom",
+            OperandKind::Immediate => "imm",
+            OperandKind::Fields => "fields",
         }
     }
 
-    Ok(())
+    fn check(self, value: i64) -> Result<i64, CompileErrorKind> {
+        match self {
+            OperandKind::RamAddress if !(0..RAM_SIZE as i64).contains(&value) => Err(
+                CompileErrorKind::out_of_range("RAM address", value, "0..=255"),
+            ),
+            OperandKind::RomAddress if !(0..ROM_SIZE as i64).contains(&value) => Err(
+                CompileErrorKind::out_of_range("ROM address", value, "0..=255"),
+            ),
+            _ => Ok(value),
+        }
+    }
+}
+
+fn operand_kind(instruction: Instruction) -> OperandKind {
+    use Instruction::*;
+    match instruction {
+        NOP | NOT | NEG | ROL | ROR | TAB | TBA | ADB | PUSH | POP | RET | INX | DEX | IN
+        | OUTC | OUTH | OUTB | RND | CLC | SEC | CLZ | JMA | BRK | ABS | TFA | TAF | RSR => {
+            OperandKind::None
+        }
+        LDA | STA | ADD | SUB | MUL | DIV | MOD | AND | OR | XOR | INC | DEC | CMP | LDB | STB
+        | LDP | STP | OUTS | DBG | XCHG | BIT | ADC | SBC => OperandKind::RamAddress,
+        JMP | JC | JZ | JNZ | JNC | JN | JP | JO | JNO | CALL | JSR | LDR | ADR | STR | STRI => {
+            OperandKind::RomAddress
+        }
+        // LAX and SAX take a base that X is added to, and JR an offset.
+        OUT | HLT | LDI | ADI | SUI | MLI | ANI | ORI | XRI | SHL | SHR | ASR | CPI | LDX | LAX
+        | SAX | JR | SLP | LDH => OperandKind::Immediate,
+        MCP | MOV | DJNZ | STI | SMB | RMB => OperandKind::Fields,
+    }
+}
+
+/// Prints flags_affected() for every instruction, for `--describe-isa`.
+fn describe_isa() {
+    println!("opcode  mnemonic  C Z N V  operand");
+    for instruction in Instruction::all_variants().iter() {
+        let effect = flags_affected(*instruction);
+        let symbols: Vec<String> = effect
+            .effects()
+            .iter()
+            .map(|(_, effect)| effect.symbol().to_string())
+            .collect();
+        println!(
+            "{:#04x}    {:<8}  {}  {}",
+            instruction.to_primitive(),
+            format!("{:?}", instruction),
+            symbols.join(" "),
+            operand_kind(*instruction).name()
+        );
+    }
+    println!();
+    println!("C CARRY, Z ZERO, N NEGATIVE, V OVERFLOW");
+    println!("- kept, * set from the result, 0 cleared, 1 set");
+    println!("operands: ram and rom are addresses 0..=255, imm a signed 56-bit value,");
+    println!("fields several values packed together, - none");
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeFault {
+    DivideByZero,
+    DivideOverflow,
+    AddressOutOfRange(i64),
+    StackOverflow,
+    StackUnderflow,
+    JumpOutOfRange(i64),
+    RomAddressOutOfRange(i64),
+    UnmappedPort(i64),
+    ValueOutOfRange(i64),
+    SleepOutOfRange(i64),
+    InvalidOpcode(u8),
+    SelfModifyDisabled,
+    OutputFailed(io::ErrorKind),
+}
+
+impl fmt::Display for RuntimeFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeFault::DivideByZero => write!(f, "divide by zero"),
+            RuntimeFault::DivideOverflow => write!(f, "division overflows (i64::MIN by -1)"),
+            RuntimeFault::AddressOutOfRange(address) => {
+                write!(
+                    f,
+                    "RAM address {} is out of range 0..={}",
+                    address,
+                    RAM_SIZE - 1
+                )
+            }
+            RuntimeFault::StackOverflow => write!(f, "stack overflow (the stack is full)"),
+            RuntimeFault::StackUnderflow => write!(f, "stack underflow (nothing to pop)"),
+            RuntimeFault::UnmappedPort(port) => write!(f, "output port {} is not mapped", port),
+            RuntimeFault::OutputFailed(kind) => write!(f, "output failed: {}", kind),
+            RuntimeFault::ValueOutOfRange(value) => write!(
+                f,
+                "{} does not fit in a ROM value ({}..={})",
+                value, IMMEDIATE_MIN, IMMEDIATE_MAX
+            ),
+            RuntimeFault::SleepOutOfRange(ms) => write!(
+                f,
+                "sleep of {} ms is out of range 0..={} ms",
+                ms, MAX_SLEEP_MS
+            ),
+            RuntimeFault::InvalidOpcode(opcode) => {
+                write!(f, "{:#04x} is not a valid opcode", opcode)
+            }
+            RuntimeFault::SelfModifyDisabled => {
+                write!(f, "STRI needs --allow-self-modify")
+            }
+            RuntimeFault::JumpOutOfRange(address) => {
+                write!(
+                    f,
+                    "jump target {} is out of range 0..={}",
+                    address,
+                    ROM_SIZE - 1
+                )
+            }
+            RuntimeFault::RomAddressOutOfRange(address) => {
+                write!(
+                    f,
+                    "ROM address {} is out of range 0..={}",
+                    address,
+                    ROM_SIZE - 1
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    program_counter: u8,
+    instruction: Instruction,
+    fault: RuntimeFault,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "runtime error at pc {} ({:?}): {}",
+            self.program_counter, self.instruction, self.fault
+        )
+    }
+}
+
+impl Error for RuntimeError {}
+
+fn ram_index(address: i64) -> Result<usize, RuntimeFault> {
+    usize::try_from(address)
+        .ok()
+        .filter(|&index| index < RAM_SIZE)
+        .ok_or(RuntimeFault::AddressOutOfRange(address))
+}
+
+/// `a + b + carry`, returning the result, the unsigned carry out and whether
+/// the signed sum overflowed. The unsigned steps can't both carry, so
+/// chaining the carry in is just another add.
+fn add_with_carry(a: i64, b: i64, carry: bool) -> (i64, bool, bool) {
+    let (sum, first) = (a as u64).overflowing_add(b as u64);
+    let (sum, second) = sum.overflowing_add(carry as u64);
+    let sum = sum as i64;
+    (sum, first | second, ((a ^ sum) & (b ^ sum)) < 0)
+}
+
+/// `a - b - borrow`, returning the result, the unsigned borrow out and
+/// whether the signed difference overflowed.
+fn sub_with_borrow(a: i64, b: i64, borrow: bool) -> (i64, bool, bool) {
+    let (difference, first) = (a as u64).overflowing_sub(b as u64);
+    let (difference, second) = difference.overflowing_sub(borrow as u64);
+    let difference = difference as i64;
+    (difference, first | second, ((a ^ b) & (a ^ difference)) < 0)
+}
+
+fn rom_address(address: i64) -> Result<u8, RuntimeFault> {
+    u8::try_from(address).map_err(|_| RuntimeFault::JumpOutOfRange(address))
+}
+
+fn rom_index(address: i64) -> Result<usize, RuntimeFault> {
+    match address {
+        0..=255 => Ok(address as usize),
+        _ => Err(RuntimeFault::RomAddressOutOfRange(address)),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Shift {
+    Left,
+    Right,
+}
+
+struct ProgramState {
+    program_counter: u8, // same size as the ROM
+    ram: Ram,
+    rom: Rom,
+    reg_a: i64,
+    reg_b: i64,
+    reg_x: i64,
+    reg_jump: u8,
+    stack_pointer: u8,
+    flags: ProgramFlags,
+    input: VecDeque<String>,
+    exit_code: u8,
+    rng: u64,
+    dbg: bool,
+    sleep: bool,
+    allow_self_modify: bool,
+    wrap_pc: bool,
+    /// Set by HLT, or by running off the end of ROM. The program counter
+    /// stays where the machine stopped.
+    halted: bool,
+    /// How many instructions were loaded; the rest of ROM is padding.
+    program_length: usize,
+    warn_padding: bool,
+    /// Output streams by port number. Port 0 is stdout and port 1 stderr;
+    /// anything else has to be mapped with --port before OUT can use it.
+    ports: HashMap<i64, Box<dyn Write>>,
+}
+
+impl ProgramState {
+    /// A machine about to run `rom` from address `start`.
+    fn new(rom: Rom, start: u8) -> ProgramState {
+        ProgramState {
+            program_counter: start,
+            ram: [0; RAM_SIZE],
+            rom,
+            reg_a: 0,
+            reg_b: 0,
+            reg_x: 0,
+            reg_jump: 0,
+            stack_pointer: STACK_TOP,
+            flags: ProgramFlags::NONE,
+            input: VecDeque::new(),
+            exit_code: 0,
+            rng: 0,
+            dbg: true,
+            sleep: true,
+            allow_self_modify: false,
+            wrap_pc: false,
+            halted: false,
+            program_length: ROM_SIZE,
+            warn_padding: true,
+            ports: HashMap::from([
+                (0, Box::new(io::stdout()) as Box<dyn Write>),
+                (1, Box::new(io::stderr())),
+            ]),
+        }
+        .seeded(RandomState::new().build_hasher().finish())
+    }
+
+    /// A machine about to run `program`, with its `.data` blocks in RAM.
+    fn loaded(program: &Program) -> ProgramState {
+        let mut state = ProgramState::new(program.padded_rom(), program.start);
+        state.program_length = program.rom.len();
+        for block in &program.data {
+            state.ram[block.address..][..block.values.len()].copy_from_slice(&block.values);
+        }
+        state
+    }
+
+    /// Reseeds RND. The seed is run through splitmix64 first so that small or
+    /// zero seeds still give a well mixed, non-zero xorshift state.
+    fn seeded(mut self, seed: u64) -> ProgramState {
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        self.rng = (z ^ (z >> 31)).max(1);
+        self
+    }
+
+    /// Once the machine has halted, further steps change nothing and keep
+    /// returning HLT.
+    fn step(&mut self) -> Result<Instruction, RuntimeError> {
+        if self.halted {
+            return Ok(Instruction::HLT);
+        }
+
+        let rom = self.rom[self.program_counter as usize];
+        let program_counter = self.program_counter;
+        let error = |fault| RuntimeError {
+            program_counter,
+            instruction: rom.instruction,
+            fault,
+        };
+        let address = || ram_index(rom.value.into()).map_err(error);
+        let target = || rom_address(rom.value.into()).map_err(error);
+        let rom_word = || rom_index(rom.value.into()).map_err(error);
+        let flags = self.flags;
+
+        match rom.instruction {
+            Instruction::NOP => {}
+            Instruction::LDA => self.load(self.ram[address()?]),
+            Instruction::STA => self.ram[address()?] = self.reg_a,
+            Instruction::ADD => self.alu(self.ram[address()?]),
+            Instruction::SUB => self.alu_sub(self.ram[address()?]),
+            Instruction::OUT => {
+                let line = format!("{}\n", self.reg_a);
+                self.write_port(rom.value.into(), line.as_bytes())
+                    .map_err(error)?
+            }
+            Instruction::JMP => self.jump_if(true, target()?),
+            Instruction::JC => self.jump_if(self.flags.contains(ProgramFlags::CARRY), target()?),
+            Instruction::JZ => self.jump_if(self.flags.contains(ProgramFlags::ZERO), target()?),
+            Instruction::HLT => {
+                self.exit_code = i64::from(rom.value).clamp(0, 255) as u8;
+                self.halted = true;
+            }
+            Instruction::LDI => self.load(rom.value.into()),
+            Instruction::ADI => self.alu(rom.value.into()),
+            Instruction::LDR => self.load(self.rom[rom_word()?].value.into()),
+            Instruction::ADR => self.alu(self.rom[rom_word()?].value.into()),
+            Instruction::SUI => self.alu_sub(rom.value.into()),
+            Instruction::MUL => self.alu_mul(self.ram[address()?]),
+            Instruction::MLI => self.alu_mul(rom.value.into()),
+            Instruction::DIV => self
+                .alu_div(self.ram[address()?], i64::checked_div)
+                .map_err(error)?,
+            Instruction::MOD => self
+                .alu_div(self.ram[address()?], i64::checked_rem)
+                .map_err(error)?,
+            Instruction::AND => self.alu_logic(self.reg_a & self.ram[address()?]),
+            Instruction::OR => self.alu_logic(self.reg_a | self.ram[address()?]),
+            Instruction::XOR => self.alu_logic(self.reg_a ^ self.ram[address()?]),
+            Instruction::ANI => self.alu_logic(self.reg_a & i64::from(rom.value)),
+            Instruction::ORI => self.alu_logic(self.reg_a | i64::from(rom.value)),
+            Instruction::XRI => self.alu_logic(self.reg_a ^ i64::from(rom.value)),
+            Instruction::NOT => self.alu_logic(!self.reg_a),
+            Instruction::NEG => self.alu_neg(),
+            Instruction::SHL => self.alu_shift(Shift::Left, rom.value.into()),
+            Instruction::SHR => self.alu_shift(Shift::Right, rom.value.into()),
+            Instruction::ROL => self.alu_rotate(Shift::Left),
+            Instruction::ROR => self.alu_rotate(Shift::Right),
+            Instruction::INC => self.alu_ram(address()?, 1),
+            Instruction::DEC => self.alu_ram(address()?, -1),
+            Instruction::CMP => {
+                self.alu_compare(self.ram[address()?]);
+            }
+            Instruction::CPI => {
+                self.alu_compare(rom.value.into());
+            }
+            Instruction::JNZ => self.jump_if(!self.flags.contains(ProgramFlags::ZERO), target()?),
+            Instruction::JNC => self.jump_if(!self.flags.contains(ProgramFlags::CARRY), target()?),
+            Instruction::JN => self.jump_if(self.flags.contains(ProgramFlags::NEGATIVE), target()?),
+            Instruction::JP => {
+                self.jump_if(!self.flags.contains(ProgramFlags::NEGATIVE), target()?)
+            }
+            Instruction::LDB => self.reg_b = self.ram[address()?],
+            Instruction::STB => self.ram[address()?] = self.reg_b,
+            Instruction::TAB => self.reg_b = self.reg_a,
+            Instruction::TBA => self.load(self.reg_b),
+            Instruction::ADB => self.alu(self.reg_b),
+            Instruction::PUSH => self.push(self.reg_a).map_err(error)?,
+            Instruction::POP => {
+                let value = self.pop().map_err(error)?;
+                self.load(value)
+            }
+            Instruction::CALL => {
+                // The return address is the slot after the CALL; count()
+                // then takes the jump instead of advancing.
+                let target = target()?;
+                self.push(self.program_counter as i64 + 1).map_err(error)?;
+                self.jump_if(true, target);
+            }
+            Instruction::RET => {
+                let target = self.pop().and_then(rom_address).map_err(error)?;
+                self.jump_if(true, target);
+            }
+            Instruction::LDP => self.load(self.ram[self.pointer(rom.value.into()).map_err(error)?]),
+            Instruction::STP => {
+                self.ram[self.pointer(rom.value.into()).map_err(error)?] = self.reg_a
+            }
+            Instruction::LDX => self.reg_x = rom.value.into(),
+            Instruction::INX => self.reg_x = self.alu_step(self.reg_x, 1),
+            Instruction::DEX => self.reg_x = self.alu_step(self.reg_x, -1),
+            Instruction::LAX => self.load(self.ram[self.indexed(rom.value.into()).map_err(error)?]),
+            Instruction::SAX => {
+                self.ram[self.indexed(rom.value.into()).map_err(error)?] = self.reg_a
+            }
+            Instruction::IN => match self.read_input() {
+                Some(value) => {
+                    self.set_flags(value, false);
+                    self.reg_a = value;
+                }
+                None => {
+                    // EOF: load 0 with ZERO and CARRY set, so JZ also catches
+                    // the end of input and JC tells it apart from a real 0.
+                    self.set_flags(0, true);
+                    self.reg_a = 0;
+                }
+            },
+            // Only the low byte is written, raw, so values outside 0..=255
+            // are masked and multi-byte UTF-8 has to be emitted byte by byte.
+            Instruction::OUTC => self.write_port(0, &[self.reg_a as u8]).map_err(error)?,
+            // Formatted as u64 so negative values print in two's complement.
+            Instruction::OUTH => {
+                let line = format!("{:#x}\n", self.reg_a as u64);
+                self.write_port(0, line.as_bytes()).map_err(error)?
+            }
+            Instruction::OUTB => {
+                let line = format!("{:#b}\n", self.reg_a as u64);
+                self.write_port(0, line.as_bytes()).map_err(error)?
+            }
+            // Stops at the first 0 cell, or at the end of RAM if the string
+            // is unterminated. Each cell contributes its low byte, as OUTC.
+            Instruction::OUTS => {
+                let start = address()?;
+                let bytes: Vec<u8> = self.ram[start..]
+                    .iter()
+                    .take_while(|&&cell| cell != 0)
+                    .map(|&cell| cell as u8)
+                    .collect();
+                self.write_port(0, &bytes).map_err(error)?;
+            }
+            // The offset is relative to the JR itself: `JR 1` falls through
+            // to the next instruction and `JR 0` spins in place.
+            Instruction::JR => {
+                let offset = i64::from(rom.value);
+                let target = rom_address(program_counter as i64 + offset).map_err(error)?;
+                self.jump_if(true, target);
+            }
+            Instruction::RND => self.reg_a = self.random(),
+            Instruction::CLC => self.flags.remove(ProgramFlags::CARRY),
+            Instruction::SEC => self.flags.insert(ProgramFlags::CARRY),
+            Instruction::CLZ => self.flags.remove(ProgramFlags::ZERO),
+            Instruction::JMA => {
+                let target = rom_address(self.reg_a).map_err(error)?;
+                self.jump_if(true, target);
+            }
+            // A NOP to the machine; the run loop decides whether to pause.
+            Instruction::BRK => {}
+            Instruction::DBG if self.dbg => eprintln!("{}", self.debug_line(rom.value.into())),
+            Instruction::DBG => {}
+            // Flags are left untouched, like the plain loads and stores.
+            Instruction::XCHG => {
+                let index = address()?;
+                std::mem::swap(&mut self.reg_a, &mut self.ram[index]);
+            }
+            Instruction::ABS => self.alu_abs(),
+            // AND's flags without storing the result, as CMP is to SUB.
+            Instruction::BIT => self.set_flags(self.reg_a & self.ram[address()?], false),
+            Instruction::TFA => {
+                self.reg_a = self.flags.difference(ProgramFlags::JUMP).bits() as i64
+            }
+            Instruction::TAF => {
+                let flags = ProgramFlags::from_bits_truncate(self.reg_a as u64);
+                let jump = self.flags.intersection(ProgramFlags::JUMP);
+                self.flags = flags.difference(ProgramFlags::JUMP).union(jump);
+            }
+            // The assembler keeps SLP in range, but a patched or self-modified
+            // word could ask for a pause of thousands of years.
+            Instruction::SLP => match i64::from(rom.value) {
+                ms @ 0..=MAX_SLEEP_MS if self.sleep => {
+                    std::thread::sleep(std::time::Duration::from_millis(ms as u64))
+                }
+                0..=MAX_SLEEP_MS => {}
+                ms => return Err(error(RuntimeFault::SleepOutOfRange(ms))),
+            },
+            Instruction::MCP => {
+                let value = i64::from(rom.value);
+                let (source, destination, length) =
+                    (field(value, 0), field(value, 1), field(value, 2));
+                for end in [source + length, destination + length] {
+                    if end > RAM_SIZE {
+                        return Err(error(RuntimeFault::AddressOutOfRange(end as i64 - 1)));
+                    }
+                }
+                // copy_within has memmove semantics, so overlap is fine in both
+                // directions.
+                self.ram.copy_within(source..source + length, destination);
+            }
+            Instruction::ASR => self.alu_asr(rom.value.into()),
+            Instruction::JSR => {
+                let target = target()?;
+                self.ram[LINK_CELL] = self.program_counter as i64 + 1;
+                self.jump_if(true, target);
+            }
+            Instruction::RSR => {
+                let target = rom_address(self.ram[LINK_CELL]).map_err(error)?;
+                self.jump_if(true, target);
+            }
+            Instruction::STR => {
+                let address = rom_address(rom.value.into()).map_err(error)?;
+                if !(IMMEDIATE_MIN..=IMMEDIATE_MAX).contains(&self.reg_a) {
+                    return Err(error(RuntimeFault::ValueOutOfRange(self.reg_a)));
+                }
+                self.rom[address as usize].value = self.reg_a.into();
+            }
+            // reg_a is taken as a whole packed ROM word, opcode in the top byte.
+            Instruction::STRI => {
+                if !self.allow_self_modify {
+                    return Err(error(RuntimeFault::SelfModifyDisabled));
+                }
+                let address = rom_address(rom.value.into()).map_err(error)?;
+                let word = self.reg_a.to_be_bytes();
+                self.rom[address as usize] = RomLayout::unpack_from_slice(&word)
+                    .map_err(|_| error(RuntimeFault::InvalidOpcode(word[0])))?;
+            }
+            Instruction::MOV => {
+                let value = i64::from(rom.value);
+                self.ram[field(value, 1)] = self.ram[field(value, 0)];
+            }
+            Instruction::ADC => {
+                let carry = self.flags.contains(ProgramFlags::CARRY);
+                self.alu_carry(self.ram[address()?], carry)
+            }
+            Instruction::SBC => {
+                let borrow = self.flags.contains(ProgramFlags::CARRY);
+                self.reg_a = self.alu_borrow(self.ram[address()?], borrow);
+            }
+            // Flags are updated as DEC would update them.
+            Instruction::DJNZ => {
+                let value = i64::from(rom.value);
+                let counter = field(value, 0);
+                self.alu_ram(counter, -1);
+                self.jump_if(self.ram[counter] != 0, field(value, 1) as u8);
+            }
+            Instruction::STI => {
+                let value = i64::from(rom.value);
+                self.ram[field(value, 0)] = value >> 8;
+            }
+            Instruction::SMB => {
+                let value = i64::from(rom.value);
+                self.ram[field(value, 0)] |= 1 << field(value, 1);
+            }
+            Instruction::RMB => {
+                let value = i64::from(rom.value);
+                self.ram[field(value, 0)] &= !(1 << field(value, 1));
+            }
+            // Replaces the top byte of reg_a, keeping the low 56 bits.
+            Instruction::LDH => {
+                let high = i64::from(rom.value) << 56;
+                self.load((self.reg_a & 0x00FF_FFFF_FFFF_FFFF) | high);
+            }
+            Instruction::JO => self.jump_if(self.flags.contains(ProgramFlags::OVERFLOW), target()?),
+            Instruction::JNO => {
+                self.jump_if(!self.flags.contains(ProgramFlags::OVERFLOW), target()?)
+            }
+        }
+
+        debug_assert!(
+            flags_affected(rom.instruction).allows(flags, self.flags),
+            "{:?} changed the flags from {:?} to {:?}, outside flags_affected()",
+            rom.instruction,
+            flags,
+            self.flags
+        );
+
+        if !self.halted {
+            self.count();
+        }
+        Ok(rom.instruction)
+    }
+
+    /// Reads the next number for IN, or None at EOF. Interactively each line
+    /// is one value and malformed lines re-prompt. Piped input is read as
+    /// whitespace separated values, skipping (with a warning) any that don't
+    /// parse.
+    fn read_input(&mut self) -> Option<i64> {
+        let stdin = io::stdin();
+        let interactive = stdin.is_terminal();
+        loop {
+            if let Some(token) = self.input.pop_front() {
+                match token.parse::<i64>() {
+                    Ok(value) => return Some(value),
+                    Err(_) => eprintln!("IN: `{}` is not a number", token),
+                }
+                continue;
+            }
+
+            if interactive {
+                eprint!("? ");
+                io::stderr().flush().ok();
+            }
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            if interactive {
+                self.input.push_back(line.trim().to_string());
+            } else {
+                self.input
+                    .extend(line.split_whitespace().map(str::to_string));
+            }
+        }
+    }
+
+    /// One line summary of the registers and flags, for BRK and DBG.
+    fn describe(&self) -> String {
+        let flags: Vec<_> = self.flags.iter_names().map(|(name, _)| name).collect();
+        format!(
+            "pc={} a={} b={} x={} sp={} flags=[{}]",
+            self.program_counter,
+            self.reg_a,
+            self.reg_b,
+            self.reg_x,
+            self.stack_pointer,
+            flags.join(" ")
+        )
+    }
+
+    /// What DBG prints: describe(), and the RAM cell at `address` unless
+    /// it's out of range, as the -1 of a DBG without an operand is.
+    fn debug_line(&self, address: i64) -> String {
+        match ram_index(address) {
+            Ok(index) => format!("DBG {} ram[{}]={}", self.describe(), index, self.ram[index]),
+            Err(_) => format!("DBG {}", self.describe()),
+        }
+    }
+
+    /// xorshift64*: tiny, and plenty for games and randomized tests.
+    fn random(&mut self) -> i64 {
+        let mut x = self.rng;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng = x;
+        x.wrapping_mul(0x2545F4914F6CDD1D) as i64
+    }
+
+    fn alu(&mut self, value: i64) {
+        self.alu_carry(value, false);
+    }
+
+    /// Multi-precision arithmetic works a word at a time, least significant
+    /// first: ADC adds in CARRY from the previous word and SBC subtracts it as
+    /// a borrow, and both leave CARRY set for the next word when the unsigned
+    /// result didn't fit. Seed the chain with CLC (or use plain ADD/SUB). On
+    /// the most significant word OVERFLOW tells whether the signed result
+    /// fitted.
+    fn alu_carry(&mut self, value: i64, carry: bool) {
+        let (value, carry, overflow) = add_with_carry(self.reg_a, value, carry);
+        self.set_arithmetic_flags(value, carry, overflow);
+        self.reg_a = value;
+    }
+
+    /// Subtracts `value` from reg_a. CARRY doubles as the borrow flag, so a JC
+    /// straight after a subtraction means "borrow occurred".
+    fn alu_sub(&mut self, value: i64) {
+        self.reg_a = self.alu_compare(value);
+    }
+
+    /// Sets flags exactly as a subtraction would and returns the difference
+    /// without storing it, so CMP can leave reg_a intact.
+    fn alu_compare(&mut self, value: i64) -> i64 {
+        self.alu_borrow(value, false)
+    }
+
+    /// The mirror image of alu_carry, used by SBC: subtracts `value` and the
+    /// incoming borrow.
+    fn alu_borrow(&mut self, value: i64, borrow: bool) -> i64 {
+        let (value, borrow, overflow) = sub_with_borrow(self.reg_a, value, borrow);
+        self.set_arithmetic_flags(value, borrow, overflow);
+        value
+    }
+
+    /// Multiplies reg_a by `value`, keeping the low 64 bits. CARRY and
+    /// OVERFLOW are both set when the signed product did not fit.
+    fn alu_mul(&mut self, value: i64) {
+        let (value, overflow) = self.reg_a.overflowing_mul(value);
+        self.set_arithmetic_flags(value, overflow, overflow);
+        self.reg_a = value;
+    }
+
+    /// Shared by DIV and MOD: `op` is either `checked_div` or `checked_rem`.
+    /// Both faults are reported rather than wrapped, as there is no sensible
+    /// value to leave in reg_a.
+    fn alu_div(&mut self, value: i64, op: fn(i64, i64) -> Option<i64>) -> Result<(), RuntimeFault> {
+        if value == 0 {
+            return Err(RuntimeFault::DivideByZero);
+        }
+        let value = op(self.reg_a, value).ok_or(RuntimeFault::DivideOverflow)?;
+        self.set_flags(value, false);
+        self.reg_a = value;
+        Ok(())
+    }
+
+    /// i64::MIN has no positive counterpart, so it negates to itself with
+    /// CARRY and OVERFLOW set.
+    fn alu_neg(&mut self) {
+        let (value, overflow) = self.reg_a.overflowing_neg();
+        self.set_arithmetic_flags(value, overflow, overflow);
+        self.reg_a = value;
+    }
+
+    /// As with NEG, i64::MIN stays i64::MIN with CARRY and OVERFLOW set.
+    fn alu_abs(&mut self) {
+        let (value, overflow) = self.reg_a.overflowing_abs();
+        self.set_arithmetic_flags(value, overflow, overflow);
+        self.reg_a = value;
+    }
+
+    /// Logical shift of reg_a by `count` bits, with CARRY receiving the last
+    /// bit shifted out. A count of 0 leaves reg_a alone and clears CARRY.
+    /// Counts of 64 or more (and negative counts) shift everything out, so
+    /// reg_a becomes 0 and CARRY is set if any bit was set beforehand.
+    fn alu_shift(&mut self, shift: Shift, count: i64) {
+        let value = self.reg_a as u64;
+        let (value, carry) = match count {
+            0 => (value, false),
+            1..=63 => match shift {
+                Shift::Left => (value << count, (value >> (64 - count)) & 1 == 1),
+                Shift::Right => (value >> count, (value >> (count - 1)) & 1 == 1),
+            },
+            _ => (0, value != 0),
+        };
+        let value = value as i64;
+        self.set_flags(value, carry);
+        self.reg_a = value;
+    }
+
+    /// Arithmetic shift right: like SHR but the sign bit is replicated. Counts
+    /// of 64 or more (and negative counts) leave 0 or -1 depending on the
+    /// sign, with CARRY holding the last copy of the sign bit shifted out.
+    fn alu_asr(&mut self, count: i64) {
+        let (value, carry) = match count {
+            0 => (self.reg_a, false),
+            1..=63 => (self.reg_a >> count, (self.reg_a >> (count - 1)) & 1 == 1),
+            _ => (self.reg_a >> 63, self.reg_a < 0),
+        };
+        self.set_flags(value, carry);
+        self.reg_a = value;
+    }
+
+    /// Rotates by one bit through CARRY, treating the 64-bit reg_a and the
+    /// carry bit as a single 65-bit register: the bit rotated out of reg_a
+    /// lands in CARRY and the old CARRY fills the vacated bit. 65 rotations
+    /// in the same direction restore both.
+    fn alu_rotate(&mut self, shift: Shift) {
+        let value = self.reg_a as u64;
+        let carry_in = self.flags.contains(ProgramFlags::CARRY) as u64;
+        let (value, carry) = match shift {
+            Shift::Left => ((value << 1) | carry_in, value >> 63 == 1),
+            Shift::Right => ((value >> 1) | (carry_in << 63), value & 1 == 1),
+        };
+        let value = value as i64;
+        self.set_flags(value, carry);
+        self.reg_a = value;
+    }
+
+    /// Adds `delta` to a RAM cell in place, leaving reg_a untouched.
+    fn alu_ram(&mut self, index: usize, delta: i64) {
+        self.ram[index] = self.alu_step(self.ram[index], delta);
+    }
+
+    /// Shared by the increment/decrement instructions. A positive `delta`
+    /// sets flags as ADD would and a negative one as SUB would, so a
+    /// decrement only borrows when it steps from 0 to -1.
+    fn alu_step(&mut self, value: i64, delta: i64) -> i64 {
+        let (value, carry, overflow) = match delta {
+            0.. => add_with_carry(value, delta, false),
+            _ => sub_with_borrow(value, delta.wrapping_neg(), false),
+        };
+        self.set_arithmetic_flags(value, carry, overflow);
+        value
+    }
+
+    /// Bitwise results can't carry, so CARRY is always cleared.
+    fn alu_logic(&mut self, value: i64) {
+        self.set_flags(value, false);
+        self.reg_a = value;
+    }
+
+    /// Loads `value` into reg_a, setting ZERO and NEGATIVE from it.
+    fn load(&mut self, value: i64) {
+        self.reg_a = value;
+        self.flags.set(ProgramFlags::ZERO, value == 0);
+        self.flags.set(ProgramFlags::NEGATIVE, value < 0);
+    }
+
+    /// Sets CARRY, ZERO and NEGATIVE for `value` and clears OVERFLOW, which
+    /// only the arithmetic instructions can set.
+    fn set_flags(&mut self, value: i64, carry: bool) {
+        self.set_arithmetic_flags(value, carry, false);
+    }
+
+    fn set_arithmetic_flags(&mut self, value: i64, carry: bool, overflow: bool) {
+        self.flags.set(ProgramFlags::CARRY, carry);
+        self.flags.set(ProgramFlags::ZERO, value == 0);
+        self.flags.set(ProgramFlags::NEGATIVE, value < 0);
+        self.flags.set(ProgramFlags::OVERFLOW, overflow);
+    }
+
+    /// Resolves the RAM index held in the pointer cell at `address`.
+    fn pointer(&self, address: i64) -> Result<usize, RuntimeFault> {
+        ram_index(self.ram[ram_index(address)?])
+    }
+
+    /// Resolves the effective address `base + reg_x`.
+    fn indexed(&self, base: i64) -> Result<usize, RuntimeFault> {
+        ram_index(base.saturating_add(self.reg_x))
+    }
+
+    fn write_port(&mut self, port: i64, bytes: &[u8]) -> Result<(), RuntimeFault> {
+        let writer = self
+            .ports
+            .get_mut(&port)
+            .ok_or(RuntimeFault::UnmappedPort(port))?;
+        writer
+            .write_all(bytes)
+            .map_err(|e| RuntimeFault::OutputFailed(e.kind()))
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), RuntimeFault> {
+        self.stack_pointer = self
+            .stack_pointer
+            .checked_sub(1)
+            .ok_or(RuntimeFault::StackOverflow)?;
+        self.ram[self.stack_pointer as usize] = value;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64, RuntimeFault> {
+        if self.stack_pointer == STACK_TOP {
+            return Err(RuntimeFault::StackUnderflow);
+        }
+        let value = self.ram[self.stack_pointer as usize];
+        self.stack_pointer += 1;
+        Ok(value)
+    }
+
+    fn jump_if(&mut self, condition: bool, target: u8) {
+        if condition {
+            self.flags.insert(ProgramFlags::JUMP);
+            self.reg_jump = target;
+        }
+    }
+
+    /// Whether HLT has run, or the program ran off the end of ROM.
+    fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Stepping past the last ROM slot halts the machine with a warning,
+    /// unless wrap_pc asks for the classic wraparound to slot 0.
+    fn count(&mut self) {
+        let from = self.program_counter;
+        let jumped = self.flags.contains(ProgramFlags::JUMP);
+        if jumped {
+            self.program_counter = self.reg_jump;
+            self.flags.remove(ProgramFlags::JUMP);
+        } else if let Some(next) = self.program_counter.checked_add(1) {
+            self.program_counter = next;
+        } else if self.wrap_pc {
+            self.program_counter = 0;
+        } else {
+            eprintln!(
+                "warning: execution ran off the end of ROM at {}, halting",
+                self.program_counter
+            );
+            self.halted = true;
+        }
+
+        if self.warn_padding && !self.halted {
+            if let Some(warning) = self.padding_warning(from, jumped) {
+                eprintln!("{}", warning);
+            }
+        }
+    }
+
+    /// The warning for a step from `from` to the program counter, if it
+    /// crossed into the padding. Only that step is reported.
+    fn padding_warning(&self, from: u8, jumped: bool) -> Option<String> {
+        let entered = self.program_counter as usize >= self.program_length
+            && (from as usize) < self.program_length;
+        match (entered, jumped) {
+            (false, _) => None,
+            (true, true) => Some(format!(
+                "warning: {:?} at {} jumped to {}, past the last instruction ({}) into the HLT padding",
+                self.rom[from as usize].instruction,
+                from,
+                self.program_counter,
+                self.program_length - 1
+            )),
+            (true, false) => Some(format!(
+                "warning: execution fell off the end of the program at {} into the HLT padding",
+                from
+            )),
+        }
+    }
+}
+
+fn is_identifier(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Cuts a `;` or `//` comment off the end of `line`. Comment markers inside
+/// quotes are left alone, so string and character literals can hold them.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' || line[index..].starts_with("//") => return &line[..index],
+            None => {}
+        }
+    }
+    line
+}
+
+/// A symbol name: an identifier, a local label such as `.loop`, or a local
+/// label's full name such as `main.loop`.
+fn is_symbol(token: &str) -> bool {
+    let mut segments = token.strip_prefix('.').unwrap_or(token).split('.');
+    segments.next().is_some_and(is_identifier)
+        && segments.all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+/// Splits a leading `name:` label definition off `line`.
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    is_symbol(label).then(|| (label, rest.trim_start()))
+}
+
+/// Groups a line's operand tokens into expressions. Tokens are joined while
+/// an operator or open parenthesis is left dangling, so `loop + 2` and
+/// `loop+2` are one operand, but `a -b` is two.
+fn operands(tokens: &[&str]) -> Vec<String> {
+    let mut operands: Vec<String> = Vec::new();
+    let mut depth = 0;
+    let mut dangling = false;
+    for token in tokens {
+        let joins =
+            depth > 0 || dangling || *token == "-" || token.starts_with(['+', '*', '/', '%', ')']);
+        match operands.last_mut() {
+            Some(operand) if joins => {
+                operand.push(' ');
+                operand.push_str(token);
+            }
+            _ => operands.push(token.to_string()),
+        }
+        if !token.starts_with('\'') {
+            depth += token.matches('(').count() as i64 - token.matches(')').count() as i64;
+            dangling = token.ends_with(['+', '-', '*', '/', '%', '(']);
+        } else {
+            dangling = false;
+        }
+    }
+    operands
+}
+
+/// The value of an operand: a literal, a symbol, or an expression combining
+/// them with `+ - * / %` and parentheses, in checked 64-bit arithmetic.
+fn resolve(symbols: &HashMap<&str, i64>, expression: &str) -> Result<i64, CompileErrorKind> {
+    let expression = expression.trim();
+    if is_symbol(expression) {
+        return symbols
+            .get(expression)
+            .copied()
+            .ok_or_else(|| CompileErrorKind::UndefinedSymbol {
+                name: expression.to_string(),
+                suggestions: closest_symbols(symbols, expression),
+            });
+    }
+    // Lone literals keep their own, more specific, errors.
+    let digits = expression.strip_prefix('-').unwrap_or(expression);
+    if expression.starts_with('\'') && char_literal_length(expression) == expression.len()
+        || digits.starts_with(|c: char| c.is_ascii_digit())
+            && !digits.contains(|c| "+-*/%() \t".contains(c))
+    {
+        return parse_number(expression);
+    }
+    let mut parser = Evaluator {
+        symbols,
+        rest: expression,
+    };
+    parser
+        .sum()
+        .and_then(|value| match parser.rest.trim_start() {
+            "" => Ok(value),
+            rest => Err(format!("unexpected `{}`", rest)),
+        })
+        .map_err(|reason| CompileErrorKind::InvalidExpression {
+            expression: expression.to_string(),
+            reason,
+        })
+}
+
+/// The length of the character literal at the start of `text`, up to its
+/// closing quote, or all of `text` if it has none.
+fn char_literal_length(text: &str) -> usize {
+    let mut escaped = false;
+    let end = text[1..].find(|c| {
+        let close = c == '\'' && !escaped;
+        escaped = c == '\\' && !escaped;
+        close
+    });
+    end.map_or(text.len(), |end| end + 2)
+}
+
+/// A recursive descent parser over the unparsed end of an expression.
+struct Evaluator<'a, 's> {
+    symbols: &'a HashMap<&'s str, i64>,
+    rest: &'a str,
+}
+
+impl<'a> Evaluator<'a, '_> {
+    fn eat(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Splits the leading run of characters matching `part` off `rest`.
+    fn take(&mut self, skip: usize, part: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest[skip..]
+            .find(|c| !part(c))
+            .map_or(self.rest.len(), |end| end + skip);
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        token
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.product()?;
+        while let Some(operator) = ['+', '-'].into_iter().find(|&c| self.eat(c)) {
+            let rhs = self.product()?;
+            value = match operator {
+                '+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            }
+            .ok_or("overflows 64 bits")?;
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        while let Some(operator) = ['*', '/', '%'].into_iter().find(|&c| self.eat(c)) {
+            let rhs = self.unary()?;
+            value = match operator {
+                '*' => value.checked_mul(rhs),
+                _ if rhs == 0 => return Err("division by zero".to_string()),
+                '/' => value.checked_div(rhs),
+                _ => value.checked_rem(rhs),
+            }
+            .ok_or("overflows 64 bits")?;
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        self.rest = self.rest.trim_start();
+        // A negative literal is taken whole, so that i64::MIN can be written.
+        if self.rest.starts_with('-') && self.rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            let literal = self.take(1, |c| c.is_ascii_alphanumeric() || c == '_');
+            return parse_number(literal).map_err(|kind| kind.to_string());
+        }
+        if self.eat('-') {
+            return self
+                .unary()?
+                .checked_neg()
+                .ok_or_else(|| "overflows 64 bits".to_string());
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<i64, String> {
+        if self.eat('(') {
+            let value = self.sum()?;
+            if !self.eat(')') {
+                return Err("missing `)`".to_string());
+            }
+            return Ok(value);
+        }
+        self.rest = self.rest.trim_start();
+        match self.rest.chars().next() {
+            Some('\'') => {
+                let (literal, rest) = self.rest.split_at(char_literal_length(self.rest));
+                self.rest = rest;
+                parse_char(literal).map_err(|kind| kind.to_string())
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let literal = self.take(0, |c| c.is_ascii_alphanumeric() || c == '_');
+                parse_number(literal).map_err(|kind| kind.to_string())
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let name = self.take(0, |c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+                self.symbols.get(name).copied().ok_or_else(|| {
+                    let suggestions = closest_symbols(self.symbols, name);
+                    format!("`{}` is not defined{}", name, did_you_mean(&suggestions))
+                })
+            }
+            Some(_) => Err(format!("expected a value at `{}`", self.rest)),
+            None => Err("expected a value at the end".to_string()),
+        }
+    }
+}
+
+/// Assembles `.data address value...` (or `.word`). Values are whole 64-bit
+/// words, since they go straight into RAM rather than through a ROM word.
+/// `.ascii address "text"` puts a character in each cell, and `.asciz` adds
+/// a zero after them for OUTS.
+fn data_block(symbols: &HashMap<&str, i64>, line: &str) -> Result<DataBlock, CompileErrorKind> {
+    let tokens = tokens(line);
+    let (operands, values) = match tokens[0] {
+        directive @ (".ascii" | ".asciz") => {
+            let usage = CompileErrorKind::InvalidDirective(".ascii address \"text\"");
+            let (text, address) = tokens[1..].split_last().ok_or(usage.clone())?;
+            let operands = evaluate(symbols, operands(address))?;
+            if operands.len() != 1 || !text.starts_with('"') {
+                return Err(usage);
+            }
+            let mut values = parse_string(text)?;
+            if directive == ".asciz" {
+                values.push(0);
+            }
+            (operands, values)
+        }
+        _ => {
+            let operands = evaluate(symbols, operands(&tokens[1..]))?;
+            if operands.len() < 2 {
+                return Err(CompileErrorKind::InvalidDirective(".data address value..."));
+            }
+            let values = operands[1..].iter().map(|(_, value)| *value).collect();
+            (operands, values)
+        }
+    };
+    let address = operands[0].1;
+    if !(0..RAM_SIZE as i64).contains(&address) {
+        return Err(
+            CompileErrorKind::out_of_range("RAM address", address, "0..=255")
+                .respelled(&operands[..1]),
+        );
+    }
+    let end = address + values.len() as i64 - 1;
+    if end >= RAM_SIZE as i64 {
+        return Err(CompileErrorKind::out_of_range(
+            "data end address",
+            end,
+            "0..=255",
+        ));
+    }
+    Ok(DataBlock {
+        address: address as usize,
+        values,
+    })
+}
+
+/// A block of RAM filled in by `.data` before the program starts.
+#[derive(Clone, Debug)]
+struct DataBlock {
+    address: usize,
+    values: Vec<i64>,
+}
+
+/// A value `.assert` can look at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Probe {
+    A,
+    B,
+    X,
+    Ram(usize),
+    Constant(i64),
+}
+
+impl Probe {
+    fn read(self, state: &ProgramState) -> i64 {
+        match self {
+            Probe::A => state.reg_a,
+            Probe::B => state.reg_b,
+            Probe::X => state.reg_x,
+            Probe::Ram(address) => state.ram[address],
+            Probe::Constant(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Probe::A => write!(f, "reg_a"),
+            Probe::B => write!(f, "reg_b"),
+            Probe::X => write!(f, "reg_x"),
+            Probe::Ram(address) => write!(f, "ram[{}]", address),
+            Probe::Constant(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// Parses an `.assert` condition, `left op right`, where op is one of
+/// COMPARISONS and each side is `reg_a`, `reg_b`, `reg_x`, `ram[address]` or
+/// a value. `resolve` evaluates the values and addresses.
+fn parse_condition(
+    condition: &str,
+    resolve: impl Fn(&str) -> Result<i64, CompileErrorKind>,
+) -> Result<(Probe, &'static str, Probe), CompileErrorKind> {
+    let usage = || CompileErrorKind::InvalidDirective(".assert value == value");
+    // The first operator, and the longest one there, so `<=` isn't `<`.
+    let (index, comparison) = COMPARISONS
+        .iter()
+        .filter_map(|&comparison| Some((condition.find(comparison)?, comparison)))
+        .min_by_key(|&(index, comparison)| (index, usize::MAX - comparison.len()))
+        .ok_or_else(usage)?;
+    let probe = |side: &str| {
+        let side = side.trim();
+        Ok(match side {
+            "" => return Err(usage()),
+            "reg_a" => Probe::A,
+            "reg_b" => Probe::B,
+            "reg_x" => Probe::X,
+            _ => match side
+                .strip_prefix("ram[")
+                .and_then(|side| side.strip_suffix(']'))
+            {
+                Some(address) => {
+                    let address = OperandKind::RamAddress.check(resolve(address)?)?;
+                    Probe::Ram(address as usize)
+                }
+                None => Probe::Constant(resolve(side)?),
+            },
+        })
+    };
+    let left = probe(&condition[..index])?;
+    let right = probe(&condition[index + comparison.len()..])?;
+    Ok((left, comparison, right))
+}
+
+/// An `.assert`, checked each time the instruction at `address` is about to
+/// run.
+#[derive(Clone, Debug)]
+struct Assertion {
+    address: usize,
+    left: Probe,
+    comparison: &'static str,
+    right: Probe,
+    /// Where the assertion was written, as `file:line`.
+    location: String,
+    /// The condition as written.
+    text: String,
+}
+
+impl Assertion {
+    /// Whether the assertion holds, or else what its sides were.
+    fn check(&self, state: &ProgramState) -> Result<(), String> {
+        let (left, right) = (self.left.read(state), self.right.read(state));
+        let holds = match self.comparison {
+            "==" => left == right,
+            "!=" => left != right,
+            "<=" => left <= right,
+            ">=" => left >= right,
+            "<" => left < right,
+            _ => left > right,
+        };
+        if holds {
+            return Ok(());
+        }
+        let sides: Vec<String> = [(self.left, left), (self.right, right)]
+            .iter()
+            .filter(|(probe, _)| !matches!(probe, Probe::Constant(_)))
+            .map(|(probe, value)| format!("{} is {}", probe, value))
+            .collect();
+        Err(sides.join(", "))
+    }
+}
+
+/// The message for the first of `program`'s assertions at the instruction
+/// `state` is about to run that doesn't hold, if any.
+fn failed_assertion(program: &Program, state: &ProgramState) -> Option<String> {
+    program
+        .assertions
+        .iter()
+        .filter(|assertion| assertion.address == state.program_counter as usize)
+        .find_map(|assertion| {
+            let sides = assertion.check(state).err()?;
+            Some(format!(
+                "{}: assertion failed: {} ({})",
+                assertion.location, assertion.text, sides
+            ))
+        })
+}
+
+/// Adds `text` to a section payload, as its length in bytes and then the
+/// bytes, 8 to a word and padded with zeros.
+fn push_string(payload: &mut Vec<i64>, text: &str) {
+    payload.push(text.len() as i64);
+    payload.extend(text.as_bytes().chunks(8).map(|chunk| {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        i64::from_be_bytes(word)
+    }));
+}
+
+/// Reads a string written by push_string.
+fn read_string(words: &mut impl Iterator<Item = u64>) -> Option<String> {
+    let length = usize::try_from(words.next()?).ok()?;
+    let bytes: Vec<u8> = words
+        .take(length.div_ceil(8))
+        .flat_map(u64::to_be_bytes)
+        .collect();
+    String::from_utf8(bytes.get(..length)?.to_vec()).ok()
+}
+
+/// An .ebrc file starts with a two-word header: MAGIC, the format version
+/// as a big-endian u16 and two zero bytes, then the number of instruction
+/// words and the number of sections, each a big-endian u32. The packed
+/// instruction words come next, 8 bytes each, then the sections, which have
+/// their lengths in them, so a reader can skip the ones it doesn't know.
+/// Files from before the header are just the words and sections, and load
+/// with --legacy.
+///
+/// A section is a header word, whose first byte is
+/// SECTION_MARKER (never a valid opcode), then a tag byte, two zero bytes and
+/// the payload length in words as a big-endian u32, and then the payload.
+///
+/// The data section's payload is the RAM address of a DataBlock followed by
+/// its values, each a big-endian i64.
+///
+/// Each assertion section's payload is the address of an Assertion, then
+/// its location, its text and its condition with every symbol resolved, each
+/// as a string: a length in bytes, then the bytes, 8 to a word and padded
+/// with zeros.
+///
+/// The optional entry section's payload is the address execution starts at,
+/// which is 0 without one.
+///
+/// The optional source map section's payload is the number of source files,
+/// then for each file its SourceFile::hash and its name as a string. Then
+/// there's a word for each ROM word with a source line: the address in the
+/// top 16 bits, the index of the file in the next 16 and the line in the low
+/// 32.
+#[derive(Clone, Debug)]
+struct Program {
+    rom: Vec<RomLayout>,
+    data: Vec<DataBlock>,
+    /// The address of the first instruction run, set by `.start`.
+    start: u8,
+    assertions: Vec<Assertion>,
+    /// The line each ROM word was assembled from, or None for a word the
+    /// assembler made up. Only the file and line are saved, in the source
+    /// map, and a program read from .ebrc without one has none.
+    sources: Vec<Option<SourceLine>>,
+    /// The files the program was assembled from.
+    source_files: Vec<SourceFile>,
+    /// Every symbol, sorted by value and then name. Not saved either.
+    symbols: Vec<Symbol>,
+    /// What the assembler had to say about the program. Not saved.
+    warnings: Vec<CompileError>,
+}
+
+/// A file a program was assembled from, and the hash of its text, so a
+/// source map isn't trusted once the file has changed.
+#[derive(Clone, Debug)]
+struct SourceFile {
+    name: Rc<str>,
+    hash: u64,
+}
+
+/// FNV-1a, which is all a check for an edited file needs.
+fn source_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+const MAGIC: &[u8; 4] = b"EBRC";
+/// The version of the .ebrc format written, and the newest one read.
+const FORMAT_VERSION: u16 = 1;
+const SECTION_MARKER: u8 = 0xFF;
+const DATA_SECTION: u8 = b'D';
+const ENTRY_SECTION: u8 = b'E';
+const ASSERTION_SECTION: u8 = b'A';
+const SOURCE_MAP_SECTION: u8 = b'S';
+
+impl Program {
+    fn to_bytes(&self, source_map: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for block in &self.data {
+            bytes.extend(section_header(DATA_SECTION, block.values.len() + 1));
+            bytes.extend((block.address as i64).to_be_bytes());
+            bytes.extend(block.values.iter().flat_map(|value| value.to_be_bytes()));
+        }
+        if self.start != 0 {
+            bytes.extend(section_header(ENTRY_SECTION, 1));
+            bytes.extend((self.start as i64).to_be_bytes());
+        }
+        for assertion in &self.assertions {
+            let mut payload = vec![assertion.address as i64];
+            push_string(&mut payload, &assertion.location);
+            push_string(&mut payload, &assertion.text);
+            let condition = format!(
+                "{} {} {}",
+                assertion.left, assertion.comparison, assertion.right
+            );
+            push_string(&mut payload, &condition);
+            bytes.extend(section_header(ASSERTION_SECTION, payload.len()));
+            bytes.extend(payload.iter().flat_map(|word| word.to_be_bytes()));
+        }
+        if source_map {
+            let mut payload = vec![self.source_files.len() as i64];
+            for file in &self.source_files {
+                payload.push(file.hash as i64);
+                push_string(&mut payload, &file.name);
+            }
+            for (address, source) in self.sources.iter().enumerate() {
+                let Some(source) = source else { continue };
+                let file = self
+                    .source_files
+                    .iter()
+                    .position(|file| file.name == source.file)
+                    .unwrap();
+                payload.push((address as i64) << 48 | (file as i64) << 32 | source.line as i64);
+            }
+            bytes.extend(section_header(SOURCE_MAP_SECTION, payload.len()));
+            bytes.extend(payload.iter().flat_map(|word| word.to_be_bytes()));
+        }
+        let sections = self.data.len()
+            + usize::from(self.start != 0)
+            + self.assertions.len()
+            + usize::from(source_map);
+        let mut file = MAGIC.to_vec();
+        file.extend(FORMAT_VERSION.to_be_bytes());
+        file.extend([0, 0]);
+        file.extend((self.rom.len() as u32).to_be_bytes());
+        file.extend((sections as u32).to_be_bytes());
+        file.extend(self.rom.iter().flat_map(|r| r.pack().unwrap()));
+        file.extend(bytes);
+        file
+    }
+
+    /// Fills in the text of the source lines read from a source map, from
+    /// the files that are still as they were when the program was assembled.
+    fn read_source_text(&mut self) {
+        for file in &self.source_files {
+            let Ok(text) = std::fs::read_to_string(&*file.name) else {
+                continue;
+            };
+            if source_hash(&text) != file.hash {
+                continue;
+            }
+            let lines: Vec<&str> = text.lines().collect();
+            for source in self.sources.iter_mut().flatten() {
+                if source.file == file.name {
+                    let text = source
+                        .line
+                        .checked_sub(1)
+                        .and_then(|index| lines.get(index));
+                    source.text = text.unwrap_or(&"").to_string();
+                }
+            }
+        }
+    }
+
+    /// The ROM image, padded out with HLT 0.
+    fn padded_rom(&self) -> Rom {
+        let mut rom = self.rom.clone();
+        rom.resize(ROM_SIZE, RomLayout::new(Instruction::HLT, 0));
+        rom.try_into().unwrap()
+    }
+}
+
+/// A listing of an assembled program: a line for each ROM word with its
+/// address, packed bytes and the source line it came from, then the symbols.
+///
+/// ```text
+/// 000  0a 00 00 00 00 00 00 01  fib.ebr:1  LDI 1
+/// ```
+fn listing(program: &Program) -> String {
+    let mut listing = String::new();
+    for (address, word) in program.rom.iter().enumerate() {
+        let bytes: Vec<String> = word
+            .pack()
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        listing.push_str(&format!("{:03}  {}", address, bytes.join(" ")));
+        if let Some(Some(source)) = program.sources.get(address) {
+            listing.push_str(&format!(
+                "  {}:{}  {}",
+                source.file,
+                source.line,
+                source.text.trim()
+            ));
+        }
+        listing.push('\n');
+    }
+    if !program.symbols.is_empty() {
+        listing.push_str("\nsymbols:\n");
+        listing.push_str(&symbol_table(&program.symbols));
+    }
+    listing
+}
+
+/// Turns the bytes of an .ebrc file back into source that assembles to the
+/// same bytes: its data and entry point, then an instruction a line with
+/// the address in a comment. A word no mnemonic assembles to, such as LDA
+/// with an operand past RAM, is written as `.bytes`, and so is a word with
+/// no instruction, which won't assemble. Unless `padding`, the HLT words
+/// after the last one the program needs are left to an `.org`. Assertions
+/// become `.assert` lines, which save where they are in the new source
+/// instead, and the source map isn't kept.
+fn disassemble(bytes: &[u8], padding: bool, legacy: bool) -> Result<String, String> {
+    let file = split_rom(bytes, legacy)?;
+    let words: Vec<&[u8]> = file.words.chunks(8).collect();
+    let halt = RomLayout::new(Instruction::HLT, 0);
+    let program = read_sections(vec![halt; words.len()], file.sections, file.count)?;
+
+    let mut source = String::new();
+    for block in &program.data {
+        let values: Vec<String> = block.values.iter().map(i64::to_string).collect();
+        source.push_str(&format!(".data {} {}\n", block.address, values.join(" ")));
+    }
+    if program.start != 0 {
+        source.push_str(&format!(".start {}\n", program.start));
+    }
+    // The words after `end` are padding that can be left out: HLT 0, with
+    // nothing starting or asserting there, after an HLT the program keeps.
+    let halt_bytes = halt.pack().unwrap();
+    let trailing = words.iter().rev().take_while(|word| ***word == halt_bytes);
+    let kept = words.len() - trailing.count();
+    let needed = program
+        .assertions
+        .iter()
+        .map(|assertion| assertion.address)
+        .chain([program.start as usize])
+        .max()
+        .unwrap_or(0);
+    let end = match padding {
+        true => words.len(),
+        false => words.len().min(kept.max(needed) + 1),
+    };
+    for address in 0..=end {
+        for assertion in &program.assertions {
+            if assertion.address == address {
+                source.push_str(&format!(
+                    ".assert {} {} {}\n",
+                    assertion.left, assertion.comparison, assertion.right
+                ));
+            }
+        }
+        let Some(word) = words.get(address).filter(|_| address < end) else {
+            continue;
+        };
+        source.push_str(&format!("{} ; {:03}\n", disassemble_word(word), address));
+    }
+    if end < words.len() {
+        source.push_str(&format!(".org {}\n", words.len()));
+    }
+    Ok(format_source(&source))
+}
+
+/// One ROM word as an instruction, or as `.bytes` if no instruction
+/// assembles to it.
+fn disassemble_word(bytes: &[u8]) -> String {
+    let raw = || {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+        format!(".bytes {}", bytes.join(" "))
+    };
+    let Ok(word) = RomLayout::unpack_from_slice(bytes) else {
+        return raw();
+    };
+    let value: i64 = word.value.into();
+    let fields = |count| (0..count).map(|index| field(value, index) as i64).collect();
+    let mut operands: Vec<i64> = match word.instruction {
+        Instruction::MCP => fields(3),
+        Instruction::MOV | Instruction::DJNZ | Instruction::SMB | Instruction::RMB => fields(2),
+        Instruction::STI => vec![value & 0xFF, value >> 8],
+        _ => Vec::new(),
+    };
+    let optional = matches!(
+        (word.instruction, value),
+        (Instruction::HLT | Instruction::OUT, 0) | (Instruction::DBG, -1)
+    );
+    if operands.is_empty() && !optional && operand_kind(word.instruction) != OperandKind::None {
+        operands.push(value);
+    }
+    let operands: Vec<String> = operands.iter().map(i64::to_string).collect();
+    let text = format!("{:?} {}", word.instruction, operands.join(" "));
+    match RomLayout::parse(&text) {
+        Ok((parsed, None)) if parsed.pack().unwrap()[..] == *bytes => text.trim_end().to_string(),
+        _ => raw(),
+    }
+}
+
+/// Rewrites .ebr source in the canonical style: labels and EQU constants at
+/// column 0, everything else indented by four spaces, mnemonics in upper
+/// case, one space between tokens, and trailing comments lined up within each
+/// run of lines between blank lines. A label before code gets a line of its
+/// own, except on a data directive, where it names the data rather than the
+/// next instruction. Formatting formatted source changes nothing.
+fn format_source(program: &str) -> String {
+    let macros: HashSet<&str> = program
+        .lines()
+        .filter_map(|line| match tokens(strip_comment(line))[..] {
+            [".macro", name, ..] => Some(name),
+            _ => None,
+        })
+        .collect();
+    let is_mnemonic = |word: &str| {
+        !macros.contains(word)
+            && Instruction::all_variants()
+                .iter()
+                .any(|variant| variant.to_display_str().eq_ignore_ascii_case(word))
+    };
+
+    // Each line as its code and its trailing comment.
+    let mut lines: Vec<(String, &str)> = Vec::new();
+    for text in program.lines() {
+        let code = strip_comment(text);
+        let comment = text[code.len()..].trim();
+        let mut code = code.trim();
+        if code.is_empty() && !comment.is_empty() {
+            // A comment on a line of its own keeps to the margin or not.
+            let indent = if text.starts_with(comment) {
+                ""
+            } else {
+                "    "
+            };
+            lines.push((format!("{}{}", indent, comment), ""));
+            continue;
+        }
+        let mut labels = Vec::new();
+        while let Some((label, rest)) = split_label(code) {
+            labels.push(format!("{}:", label));
+            code = rest;
+        }
+        let words = tokens(code);
+        let formatted = match words[..] {
+            [] => String::new(),
+            _ if split_equ(code).is_some() => match words[..] {
+                [name, equ, ..] if equ.eq_ignore_ascii_case("EQU") => {
+                    format!("{} EQU {}", name, words[2..].join(" "))
+                }
+                _ => words.join(" "),
+            },
+            [first, ..] if is_mnemonic(first) => {
+                let mut words = words.clone();
+                let mnemonic = first.to_ascii_uppercase();
+                words[0] = &mnemonic;
+                format!("    {}", words.join(" "))
+            }
+            _ => format!("    {}", words.join(" ")),
+        };
+        let data = matches!(
+            words.first(),
+            Some(&(".data" | ".word" | ".ascii" | ".asciz"))
+        );
+        match data {
+            true if !labels.is_empty() => {
+                lines.push((
+                    format!("{} {}", labels.join(" "), formatted.trim()),
+                    comment,
+                ));
+            }
+            _ => {
+                let last = labels.pop();
+                lines.extend(labels.into_iter().map(|label| (label, "")));
+                match last {
+                    Some(label) if formatted.is_empty() => lines.push((label, comment)),
+                    Some(label) => {
+                        lines.push((label, ""));
+                        lines.push((formatted, comment));
+                    }
+                    None => lines.push((formatted, comment)),
+                }
+            }
+        }
+    }
+
+    let mut formatted = String::new();
+    for block in lines.split(|(code, comment)| code.is_empty() && comment.is_empty()) {
+        let column = block
+            .iter()
+            .filter(|(code, comment)| !code.is_empty() && !comment.is_empty())
+            .map(|(code, _)| code.chars().count() + 1)
+            .max()
+            .unwrap_or(0);
+        for (code, comment) in block {
+            let line = match comment.is_empty() {
+                true => code.clone(),
+                false => format!("{:width$}{}", code, comment, width = column),
+            };
+            formatted.push_str(&line);
+            formatted.push('\n');
+        }
+        formatted.push('\n');
+    }
+    // The split adds a blank line after the last block.
+    formatted.truncate(formatted.trim_end().len());
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// `e64br fmt [--check] FILE...` formats each file in place. With --check
+/// it changes nothing, and fails if any file isn't formatted.
+fn format_command(arguments: &[String]) -> Result<(), Box<dyn Error>> {
+    let check = arguments.iter().any(|argument| argument == "--check");
+    let files: Vec<&String> = arguments
+        .iter()
+        .filter(|argument| *argument != "--check")
+        .collect();
+    if files.is_empty() {
+        return Err("usage: e64br fmt [--check] FILE...".into());
+    }
+    let mut unformatted = false;
+    for file in files {
+        let program = std::fs::read_to_string(file)?;
+        let formatted = format_source(&program);
+        if formatted == program {
+            continue;
+        }
+        match check {
+            true => {
+                eprintln!("{} is not formatted", file);
+                unformatted = true;
+            }
+            false => std::fs::write(file, formatted)?,
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// What a symbol names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SymbolKind {
+    /// A ROM address.
+    Label,
+    /// An EQU value.
+    Constant,
+    /// The RAM address of a .data block.
+    Data,
+    /// The RAM address of this many cells allocated by `.var`.
+    Variable(usize),
+}
+
+impl SymbolKind {
+    fn name(self) -> &'static str {
+        match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Data => "data",
+            SymbolKind::Variable(_) => "variable",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Symbol {
+    name: String,
+    value: i64,
+    kind: SymbolKind,
+    /// The file and line that defined it, if the assembler did. Not saved.
+    definition: Option<(Rc<str>, usize)>,
+}
+
+/// A symbol file has a `name value kind` line for each symbol, with the
+/// value in decimal and the kind one of `label`, `constant`, `data` or
+/// `variable`, which is followed by the variable's size in cells:
+///
+/// ```text
+/// loop 3 label
+/// total 0 variable 2
+/// ```
+fn symbol_table(symbols: &[Symbol]) -> String {
+    symbols
+        .iter()
+        .map(|symbol| {
+            let line = format!("{} {} {}", symbol.name, symbol.value, symbol.kind.name());
+            match symbol.kind {
+                SymbolKind::Variable(size) => format!("{} {}\n", line, size),
+                _ => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+/// Reads a file written by symbol_table.
+fn read_symbols(text: &str) -> Result<Vec<Symbol>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let invalid = || format!("symbol file line {}: expected `name value kind`", index + 1);
+            let (name, value, kind) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, value, "label"] => (name, value, SymbolKind::Label),
+                [name, value, "constant"] => (name, value, SymbolKind::Constant),
+                [name, value, "data"] => (name, value, SymbolKind::Data),
+                [name, value, "variable", size] => {
+                    let size = size.parse().map_err(|_| invalid())?;
+                    (name, value, SymbolKind::Variable(size))
+                }
+                _ => return Err(invalid()),
+            };
+            Ok(Symbol {
+                name: name.to_string(),
+                value: value.parse().map_err(|_| invalid())?,
+                kind,
+                definition: None,
+            })
+        })
+        .collect()
+}
+
+/// Names a ROM address after the closest label at or before it, as `loop`
+/// or `loop+2`.
+fn symbolic_address(symbols: &[Symbol], address: u8) -> Option<String> {
+    let label = symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Label && symbol.value <= address as i64)
+        .max_by_key(|symbol| symbol.value)?;
+    Some(match address as i64 - label.value {
+        0 => label.name.clone(),
+        offset => format!("{}+{}", label.name, offset),
+    })
+}
+
+fn section_header(tag: u8, length: usize) -> [u8; 8] {
+    let mut header = [SECTION_MARKER, tag, 0, 0, 0, 0, 0, 0];
+    header[4..].copy_from_slice(&(length as u32).to_be_bytes());
+    header
+}
+
+/// Evaluates each operand, keeping its text for error messages.
+fn evaluate(
+    symbols: &HashMap<&str, i64>,
+    operands: Vec<String>,
+) -> Result<Vec<(String, i64)>, CompileErrorKind> {
+    operands
+        .into_iter()
+        .map(|operand| {
+            let value = resolve(symbols, &operand)?;
+            Ok((operand, value))
+        })
+        .collect()
+}
+
+/// Evaluates a line's operands and assembles it, warning about an operand
+/// given to an instruction that takes none.
+fn assemble_line(
+    symbols: &HashMap<&str, i64>,
+    source: &SourceLine,
+    line: &str,
+    warnings: &mut Vec<CompileError>,
+) -> Result<RomLayout, CompileErrorKind> {
+    let tokens = tokens(line);
+    let Some((mnemonic, rest)) = tokens.split_first() else {
+        return RomLayout::from_str("");
+    };
+    let operands = evaluate(symbols, operands(rest))?;
+    if *mnemonic == ".bytes" {
+        return raw_word(&operands);
+    }
+    let mut resolved = mnemonic.to_string();
+    for (_, value) in &operands {
+        resolved.push(' ');
+        resolved.push_str(&value.to_string());
+    }
+    let (word, ignored) = RomLayout::parse(&resolved).map_err(|kind| kind.respelled(&operands))?;
+    if ignored.is_some() {
+        let kind = CompileErrorKind::IgnoredOperand {
+            instruction: word.instruction,
+            operand: operands[0].0.clone(),
+        };
+        warnings.push(CompileError::new(source, kind));
+    }
+    Ok(word)
+}
+
+/// Assembles `.bytes b0 b1 b2 b3 b4 b5 b6 b7`, a ROM word given byte by
+/// byte, which can hold an operand the instruction's mnemonic wouldn't
+/// accept. The disassembler writes words that way when it has to.
+fn raw_word(operands: &[(String, i64)]) -> Result<RomLayout, CompileErrorKind> {
+    let usage = CompileErrorKind::InvalidDirective(".bytes b0 b1 b2 b3 b4 b5 b6 b7");
+    let mut bytes = [0; 8];
+    if operands.len() != bytes.len() {
+        return Err(usage);
+    }
+    for (byte, (_, value)) in bytes.iter_mut().zip(operands) {
+        *byte = u8::try_from(*value).map_err(|_| {
+            CompileErrorKind::out_of_range("byte", *value, "0..=255").respelled(operands)
+        })?;
+    }
+    RomLayout::unpack_from_slice(&bytes).map_err(|_| CompileErrorKind::UnknownOpcode(bytes[0]))
+}
+
+/// Adds a symbol, unless it's already defined. The first definition stands,
+/// and the error points back at it.
+fn define<'a>(
+    symbols: &mut HashMap<&'a str, i64>,
+    definitions: &mut HashMap<&'a str, (SymbolKind, &'a SourceLine)>,
+    source: &'a SourceLine,
+    name: &'a str,
+    value: i64,
+    kind: SymbolKind,
+    errors: &mut Vec<CompileError>,
+) {
+    let duplicate = CompileError::new(source, CompileErrorKind::DuplicateSymbol(name.to_string()));
+    match definitions.get(name) {
+        Some((_, first)) => errors.push(duplicate.noting(first, "first defined here")),
+        // Defined with --define, which overrides an EQU so that the EQU can
+        // give a default.
+        None if symbols.contains_key(name) && kind == SymbolKind::Constant => {}
+        None if symbols.contains_key(name) => errors.push(duplicate),
+        None => {
+            symbols.insert(name, value);
+            definitions.insert(name, (kind, source));
+        }
+    }
+}
+
+/// Parses `.var name [size]`, which allocates `size` RAM cells, 1 by
+/// default, from `next`. Cells are allocated from 0 up, and LINK_CELL is
+/// never allocated; the stack grows down towards the variables.
+fn variable<'a>(
+    symbols: &HashMap<&str, i64>,
+    line: &'a str,
+    next: usize,
+) -> Result<(&'a str, usize), CompileErrorKind> {
+    let tokens = tokens(line);
+    let (name, size) = match tokens[1..] {
+        [name] if is_identifier(name) => (name, 1),
+        [name, ref size @ ..] if is_identifier(name) => {
+            let size = size.join(" ");
+            let value = resolve(symbols, &size)?;
+            match usize::try_from(value) {
+                Ok(count @ 1..=LINK_CELL) => (name, count),
+                _ => return Err(CompileErrorKind::out_of_range("size", value, "1..=255")),
+            }
+        }
+        _ => return Err(CompileErrorKind::InvalidDirective(".var name [size]")),
+    };
+    if next + size > LINK_CELL {
+        return Err(CompileErrorKind::RamExhausted {
+            name: name.to_string(),
+            size,
+            free: LINK_CELL - next,
+        });
+    }
+    Ok((name, size))
+}
+
+/// Parses the directives that move assembly forward from `location`,
+/// returning where they move it to and the instruction to fill the gap with,
+/// HLT 0 by default:
+///
+/// - `.org address [fill]` moves to `address`
+/// - `.fill count [fill]` moves past `count` copies of `fill`
+/// - `.align n [fill]` moves to the next multiple of `n`
+fn padding(
+    symbols: &HashMap<&str, i64>,
+    line: &str,
+    location: usize,
+) -> Result<(usize, String), CompileErrorKind> {
+    let tokens = tokens(line);
+    let usage = match tokens[0] {
+        ".org" => ".org address [fill]",
+        ".fill" => ".fill count [fill]",
+        _ => ".align n [fill]",
+    };
+    let operands = operands(&tokens[1..]);
+    let Some(operand) = operands.first() else {
+        return Err(CompileErrorKind::InvalidDirective(usage));
+    };
+    // The fill instruction is whatever follows the first operand.
+    let fill = match &tokens[1 + self::tokens(operand).len()..] {
+        [] => "HLT 0".to_string(),
+        fill => fill.join(" "),
+    };
+    let value = resolve(symbols, operand)?;
+    let location = location as i64;
+    let out_of_range = |what, range: String| {
+        CompileErrorKind::out_of_range(what, value, range).respelled(&[(operand.clone(), value)])
+    };
+    let end = match tokens[0] {
+        ".org" if !(location..=ROM_SIZE as i64).contains(&value) => {
+            return Err(out_of_range(
+                "origin",
+                format!("{}..=256, as it can't move backwards", location),
+            ));
+        }
+        ".org" => value,
+        ".fill" if !(0..=ROM_SIZE as i64 - location).contains(&value) => {
+            return Err(out_of_range(
+                "fill count",
+                format!("0..={}, the space left in ROM", ROM_SIZE as i64 - location),
+            ));
+        }
+        ".fill" => location + value,
+        _ if !(1..=ROM_SIZE as i64).contains(&value) => {
+            return Err(out_of_range("alignment", "1..=256".to_string()));
+        }
+        _ => match (location + value - 1) / value * value {
+            end if end > ROM_SIZE as i64 => {
+                return Err(CompileErrorKind::out_of_range(
+                    "aligned address",
+                    end,
+                    "0..=256, the end of ROM",
+                ));
+            }
+            end => end,
+        },
+    };
+    Ok((end as usize, fill))
+}
+
+/// Recognises `NAME EQU value` and `.equ NAME value`, returning the name and
+/// value expression.
+fn split_equ(line: &str) -> Option<Result<(&str, String), CompileErrorKind>> {
+    let tokens = tokens(line);
+    let usage = CompileErrorKind::InvalidDirective("NAME EQU value");
+    match tokens.as_slice() {
+        [".equ", name, value @ ..] if is_identifier(name) && operands(value).len() == 1 => {
+            Some(Ok((name, value.join(" "))))
+        }
+        [name, equ, value @ ..]
+            if equ.eq_ignore_ascii_case("equ")
+                && is_identifier(name)
+                && operands(value).len() == 1 =>
+        {
+            Some(Ok((name, value.join(" "))))
+        }
+        [".equ", ..] => Some(Err(usage)),
+        [_, equ, ..] if equ.eq_ignore_ascii_case("equ") => Some(Err(usage)),
+        _ => None,
+    }
+}
+
+/// A macro invocation or `.include` that a line was reached through.
+#[derive(Clone, Debug, PartialEq)]
+struct Context {
+    file: Rc<str>,
+    line: usize,
+    text: String,
+    note: &'static str,
+}
+
+/// A line of source ready for assembly, remembering where it came from.
+#[derive(Clone, Debug)]
+struct SourceLine {
+    file: Rc<str>,
+    /// 1-based, counting every line of the file.
+    line: usize,
+    /// The line as written.
+    text: String,
+    /// The line without its comment, and with any macro arguments substituted.
+    code: String,
+    /// The macro invocations and includes the line was reached through,
+    /// innermost first.
+    context: Vec<Context>,
+}
+
+impl SourceLine {
+    /// This line as the context of the lines it brings in.
+    fn as_context(&self, note: &'static str) -> Vec<Context> {
+        let mut context = vec![Context {
+            file: self.file.clone(),
+            line: self.line,
+            text: self.text.clone(),
+            note,
+        }];
+        context.extend(self.context.iter().cloned());
+        context
+    }
+}
+
+/// Splits `program` into source lines, replacing each `.include "path"` with
+/// the lines of that file. Paths are relative to the including file.
+/// `including` holds the files being read, outermost first, by canonical
+/// path and by name, and every file read is added to `files`.
+fn read_source(
+    program: &str,
+    path: &Path,
+    context: Vec<Context>,
+    including: &mut Vec<(PathBuf, Rc<str>)>,
+    files: &mut Vec<SourceFile>,
+    errors: &mut Vec<CompileError>,
+) -> Vec<SourceLine> {
+    let file: Rc<str> = path.display().to_string().into();
+    if files.iter().all(|read| read.name != file) {
+        files.push(SourceFile {
+            name: file.clone(),
+            hash: source_hash(program),
+        });
+    }
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    including.push((canonical, file.clone()));
+    let mut lines = Vec::new();
+    for (index, text) in program.lines().enumerate() {
+        let source = SourceLine {
+            file: file.clone(),
+            line: index + 1,
+            text: text.to_string(),
+            code: strip_comment(text).trim().to_string(),
+            context: context.clone(),
+        };
+        let Some(argument) = source.code.strip_prefix(".include") else {
+            lines.push(source);
+            continue;
+        };
+        let argument = argument.trim();
+        let Some(included) = argument
+            .strip_prefix('"')
+            .and_then(|argument| argument.strip_suffix('"'))
+        else {
+            errors.push(CompileError::new(
+                &source,
+                CompileErrorKind::InvalidDirective(".include \"path\""),
+            ));
+            continue;
+        };
+        let included = path.parent().unwrap_or(Path::new("")).join(included);
+        let failed = |reason| CompileErrorKind::IncludeFailed {
+            path: included.display().to_string(),
+            reason,
+        };
+        let program = match included
+            .canonicalize()
+            .and_then(|canonical| Ok((std::fs::read_to_string(&canonical)?, canonical)))
+        {
+            Ok((_, canonical)) if including.iter().any(|(path, _)| *path == canonical) => {
+                let mut chain: Vec<String> =
+                    including.iter().map(|(_, file)| file.to_string()).collect();
+                chain.push(included.display().to_string());
+                errors.push(CompileError::new(
+                    &source,
+                    CompileErrorKind::IncludeCycle(chain),
+                ));
+                continue;
+            }
+            Ok((program, _)) => program,
+            Err(error) => {
+                errors.push(CompileError::new(&source, failed(error.kind())));
+                continue;
+            }
+        };
+        let context = source.as_context("included from here");
+        lines.extend(read_source(
+            &program, &included, context, including, files, errors,
+        ));
+    }
+    including.pop();
+    lines
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+/// Replaces the identifiers in `code` that `substitute` has a value for,
+/// leaving everything else, including quoted text, as written.
+fn substitute_identifiers(code: &str, substitute: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = code;
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    while let Some(start) = rest.find(|c: char| is_word(c) || c == '"' || c == '\'') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'');
+        let end = match quote {
+            Some(quote) => rest[1..].find(quote).map_or(rest.len(), |end| end + 2),
+            None => rest.find(|c: char| !is_word(c)).unwrap_or(rest.len()),
+        };
+        let token = &rest[..end];
+        match substitute(token) {
+            Some(value) if quote.is_none() => result.push_str(&value),
+            _ => result.push_str(token),
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Gives local labels, written `.name`, their full names. A local belongs to
+/// the region after the last global label, which is `main.loop` after
+/// `main:`, and each included file and macro expansion has regions of its
+/// own, so a macro can use the same locals every time it's invoked.
+fn localize(source: Vec<SourceLine>, errors: &mut Vec<CompileError>) -> Vec<SourceLine> {
+    // Lines reached through the same includes and macro invocations are in
+    // the same file or expansion, numbered from 0 for the main file.
+    let mut expansions = HashMap::new();
+    let mut expansion = |source: &SourceLine| {
+        let key: Vec<_> = source
+            .context
+            .iter()
+            .map(|context| (context.file.clone(), context.line))
+            .collect();
+        let next = expansions.len();
+        *expansions.entry(key).or_insert(next)
+    };
+    let full_name = |expansion: usize, scope: Option<&String>, label: &str| {
+        let name = format!("{}{}", scope.map_or("", |scope| scope.as_str()), label);
+        match expansion {
+            0 => name,
+            _ => format!("{}.{}", name, expansion),
+        }
+    };
+
+    // The global label each line's locals belong to, and the locals defined.
+    let mut scopes: HashMap<usize, String> = HashMap::new();
+    let mut regions = Vec::new();
+    let mut defined = HashSet::new();
+    for source in &source {
+        let expansion = expansion(source);
+        let mut code = source.code.as_str();
+        let mut labels = Vec::new();
+        while let Some((label, rest)) = split_label(code) {
+            if is_identifier(label) {
+                scopes.insert(expansion, label.to_string());
+            }
+            labels.push(label);
+            code = rest;
+        }
+        let scope = scopes.get(&expansion);
+        for label in labels.into_iter().filter(|label| label.starts_with('.')) {
+            defined.insert(full_name(expansion, scope, label));
+        }
+        regions.push((expansion, scope.cloned()));
+    }
+
+    let mut lines = Vec::new();
+    for (source, (expansion, scope)) in source.into_iter().zip(regions) {
+        let mut code = source.code.as_str();
+        let mut localized = String::new();
+        while let Some((label, rest)) = split_label(code) {
+            match label.starts_with('.') {
+                true => localized.push_str(&full_name(expansion, scope.as_ref(), label)),
+                false => localized.push_str(label),
+            }
+            localized.push_str(": ");
+            code = rest;
+        }
+        // The first token is the instruction or directive, which is left be.
+        let (first, operands) = code.split_at(code.find(char::is_whitespace).unwrap_or(code.len()));
+        localized.push_str(first);
+        let mut rest = operands;
+        let mut quote = None;
+        let mut escaped = false;
+        // The identifier after a `.` at the start of `rest`.
+        let local = |rest: &str| {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .map_or(rest.len(), |end| end + 1);
+            rest[1..end].to_string()
+        };
+        while let Some(c) = rest.chars().next() {
+            let follows_word = localized
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(open) if c == open => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '.' && !follows_word && is_identifier(&local(rest)) => {
+                    let label = &rest[..local(rest).len() + 1];
+                    let name = full_name(expansion, scope.as_ref(), label);
+                    if !defined.contains(&name) {
+                        errors.push(CompileError::new(
+                            &source,
+                            CompileErrorKind::UndefinedLocal {
+                                label: label.to_string(),
+                                scope: scope.clone(),
+                            },
+                        ));
+                    }
+                    localized.push_str(&name);
+                    rest = &rest[label.len()..];
+                    continue;
+                }
+                None => {}
+            }
+            localized.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        lines.push(SourceLine {
+            code: localized,
+            ..source
+        });
+    }
+    lines
+}
+
+/// Expands macros. `.macro NAME params...` up to
+/// `.endmacro` defines a macro, which must come before its first use; an
+/// invocation `NAME args...` is replaced by the body with each parameter
+/// substituted by its argument. Macros can invoke other macros but not,
+/// directly or indirectly, themselves.
+fn preprocess(source: Vec<SourceLine>, errors: &mut Vec<CompileError>) -> Vec<SourceLine> {
+    let mut macros = HashMap::new();
+    let mut lines = Vec::new();
+    let mut definition: Option<(SourceLine, String, Macro)> = None;
+    for source in source {
+        let tokens: Vec<&str> = source.code.split_whitespace().collect();
+        match (tokens.first().copied(), &mut definition) {
+            (Some(".macro"), Some(_)) => errors.push(CompileError::new(
+                &source,
+                CompileErrorKind::MisplacedDirective(".macro"),
+            )),
+            (Some(".macro"), None) => match tokens.get(1) {
+                Some(name) if is_identifier(name) => {
+                    let parameters = tokens[2..].iter().map(|p| p.to_string()).collect();
+                    let body = Vec::new();
+                    let name = name.to_string();
+                    definition = Some((source, name, Macro { parameters, body }));
+                }
+                _ => errors.push(CompileError::new(
+                    &source,
+                    CompileErrorKind::InvalidDirective(".macro NAME params..."),
+                )),
+            },
+            (Some(".endmacro"), definition @ Some(_)) => {
+                let (_, name, body) = definition.take().unwrap();
+                macros.insert(name, body);
+            }
+            (Some(".endmacro"), None) => errors.push(CompileError::new(
+                &source,
+                CompileErrorKind::MisplacedDirective(".endmacro"),
+            )),
+            (_, Some((_, _, body))) => body.body.push(source),
+            (_, None) => expand(source, &macros, &mut Vec::new(), &mut lines, errors),
+        }
+    }
+    if let Some((source, name, _)) = definition {
+        errors.push(CompileError::new(
+            &source,
+            CompileErrorKind::UnterminatedMacro(name),
+        ));
+    }
+    lines
+}
+
+/// Appends `source` to `lines`, expanding it first if it invokes a macro.
+/// `active` holds the macros being expanded, outermost first.
+fn expand(
+    source: SourceLine,
+    macros: &HashMap<String, Macro>,
+    active: &mut Vec<String>,
+    lines: &mut Vec<SourceLine>,
+    errors: &mut Vec<CompileError>,
+) {
+    let mut code = source.code.as_str();
+    let mut labels = Vec::new();
+    while let Some((label, rest)) = split_label(code) {
+        labels.push(label);
+        code = rest;
+    }
+    let tokens = tokens(code);
+    let Some((name, definition)) = tokens.first().and_then(|name| macros.get_key_value(*name))
+    else {
+        lines.push(source.clone());
+        return;
+    };
+    // Labels in front of an invocation name the first line of its body.
+    for label in labels {
+        lines.push(SourceLine {
+            code: format!("{}:", label),
+            ..source.clone()
+        });
+    }
+
+    if active.contains(name) {
+        let mut chain = active.clone();
+        chain.push(name.clone());
+        errors.push(CompileError::new(
+            &source,
+            CompileErrorKind::RecursiveMacro(chain),
+        ));
+        return;
+    }
+    let arguments = &tokens[1..];
+    if arguments.len() != definition.parameters.len() {
+        errors.push(CompileError::new(
+            &source,
+            CompileErrorKind::MacroArguments {
+                name: name.clone(),
+                expected: definition.parameters.len(),
+                found: arguments.len(),
+            },
+        ));
+        return;
+    }
+
+    active.push(name.clone());
+    for line in &definition.body {
+        let code = substitute_identifiers(&line.code, |token| {
+            let index = definition.parameters.iter().position(|p| p == token)?;
+            Some(arguments[index].to_string())
+        });
+        let line = SourceLine {
+            code,
+            context: source.as_context("in the macro invoked here"),
+            ..line.clone()
+        };
+        expand(line, macros, active, lines, errors);
+    }
+    active.pop();
+}
+
+/// Expands `.rep count [counter]` ... `.endrep` into `count` copies of the
+/// lines between, appending them to `lines`, with `counter`, if given,
+/// replaced by 0, 1, ... in each copy. The count is worked out here, so it
+/// can only use the --define constants and the EQU constants above it,
+/// which are collected in `constants`.
+fn repeat(
+    source: &[SourceLine],
+    constants: &mut HashMap<String, i64>,
+    lines: &mut Vec<SourceLine>,
+    errors: &mut Vec<CompileError>,
+) {
+    let first_word = |line: &SourceLine| tokens(&line.code).first().map(|word| word.to_string());
+    let mut index = 0;
+    while let Some(line) = source.get(index) {
+        index += 1;
+        match first_word(line).as_deref() {
+            Some(".rep") => {}
+            Some(".endrep") => {
+                errors.push(CompileError::new(
+                    line,
+                    CompileErrorKind::MisplacedDirective(".endrep"),
+                ));
+                continue;
+            }
+            _ => {
+                let mut code = line.code.as_str();
+                while let Some((_, rest)) = split_label(code) {
+                    code = rest;
+                }
+                if let Some(Ok((name, value))) = split_equ(code) {
+                    let symbols = constants
+                        .iter()
+                        .map(|(name, &value)| (name.as_str(), value))
+                        .collect();
+                    if let Ok(value) = resolve(&symbols, &value) {
+                        constants.entry(name.to_string()).or_insert(value);
+                    }
+                }
+                lines.push(line.clone());
+                continue;
+            }
+        }
+
+        // The matching `.endrep`, past any nested ones.
+        let start = index;
+        let mut depth = 0;
+        let end = loop {
+            let Some(inner) = source.get(index) else {
+                break None;
+            };
+            index += 1;
+            match first_word(inner).as_deref() {
+                Some(".rep") => depth += 1,
+                Some(".endrep") if depth == 0 => break Some(index - 1),
+                Some(".endrep") => depth -= 1,
+                _ => {}
+            }
+        };
+        let Some(end) = end else {
+            errors.push(CompileError::new(line, CompileErrorKind::UnterminatedRep));
+            return;
+        };
+        let body = &source[start..end];
+
+        let words = tokens(&line.code);
+        let operands = operands(&words[1..]);
+        let symbols = constants
+            .iter()
+            .map(|(name, &value)| (name.as_str(), value))
+            .collect();
+        let repetition = match operands.as_slice() {
+            [count] => resolve(&symbols, count).map(|count| (count, None)),
+            [count, counter] if is_identifier(counter) => {
+                resolve(&symbols, count).map(|count| (count, Some(counter)))
+            }
+            _ => Err(CompileErrorKind::InvalidDirective(".rep count [counter]")),
+        };
+        let (count, counter) = match repetition {
+            Ok((count @ 0..=256, counter)) => (count, counter),
+            Ok((count, _)) => {
+                let kind = CompileErrorKind::out_of_range("repeat count", count, "0..=256");
+                errors.push(CompileError::new(
+                    line,
+                    kind.respelled(&[(operands[0].clone(), count)]),
+                ));
+                continue;
+            }
+            Err(kind) => {
+                errors.push(CompileError::new(line, kind));
+                continue;
+            }
+        };
+        for iteration in 0..count {
+            let copy: Vec<SourceLine> = body
+                .iter()
+                .map(|line| SourceLine {
+                    code: match counter {
+                        Some(counter) => substitute_identifiers(&line.code, |word| {
+                            (word == counter).then(|| iteration.to_string())
+                        }),
+                        None => line.code.clone(),
+                    },
+                    ..line.clone()
+                })
+                .collect();
+            repeat(&copy, constants, lines, errors);
+        }
+    }
+}
+
+/// Assembles in two passes. The first records every symbol: the address of
+/// each label (a label names the instruction after it and takes no ROM
+/// slot) and the value of each EQU constant. The second substitutes symbol
+/// operands with their values, so labels can be referenced before they are
+/// defined. An EQU value is a number or a symbol defined above it.
+///
+/// `.if expression` ... `.else` ... `.endif` assembles only the lines of the
+/// branch chosen by the expression, which like an EQU value can only use
+/// the symbols above it. `defines` are constants defined before the first
+/// line, which take the place of any EQU of the same name. Macros and
+/// includes are expanded before this, so a `.macro` or `.include` in a
+/// branch not taken is still read.
+///
+/// Several files are linked into one program: each is assembled with only
+/// its own symbols, at the addresses after the file before it. A file
+/// shares a symbol with `.export name, ...`, and uses one shared by
+/// another file with `.import name, ...`. Imported symbols are only known
+/// once every file is read, so like labels they can't be used in EQU values
+/// or `.if`.
+///
+/// `.start address`, in at most one file, has the program start there
+/// rather than at address 0.
+///
+/// `.var name [size]` names RAM cells allocated from 0 up, across every
+/// file and include, so no two variables share a cell.
+///
+/// `.assert condition` takes no ROM slot: it's checked, when the program is
+/// run, each time the next instruction is about to run.
+///
+/// With `optimize`, the assembled program goes through the peephole pass.
+fn compile_rom(
+    files: &[(String, &Path)],
+    defines: &[(String, i64)],
+    optimize: bool,
+) -> Result<Program, Vec<CompileError>> {
+    let mut errors = Vec::new();
+    let mut source_files = Vec::new();
+    // Each file is read and expanded on its own, and has symbols of its own.
+    let units: Vec<Vec<SourceLine>> = files
+        .iter()
+        .map(|(program, path)| {
+            let source = read_source(
+                program,
+                path,
+                Vec::new(),
+                &mut Vec::new(),
+                &mut source_files,
+                &mut errors,
+            );
+            let source = preprocess(source, &mut errors);
+            let mut repeated = Vec::new();
+            let mut constants = defines.iter().cloned().collect();
+            repeat(&source, &mut constants, &mut repeated, &mut errors);
+            localize(repeated, &mut errors)
+        })
+        .collect();
+
+    let defined: HashMap<&str, i64> = defines
+        .iter()
+        .map(|(name, value)| (name.as_str(), *value))
+        .collect();
+    let mut tables = vec![defined; units.len()];
+    // Each symbol's kind and the line that defined it.
+    let mut definitions = vec![HashMap::new(); units.len()];
+    let mut exports = Vec::new();
+    let mut imports = Vec::new();
+    let mut starts = Vec::new();
+    // The RAM cells `.var` has allocated, from 0 up, and by which lines.
+    let mut variables: Vec<(&SourceLine, &str, Range<usize>)> = Vec::new();
+    let mut assertions = Vec::new();
+    let mut lines = Vec::new();
+    let mut data_lines = Vec::new();
+    let mut data_labels = Vec::new();
+    let mut fills = Vec::new();
+    // The address the next instruction will be assembled at. Files are laid
+    // out one after another.
+    let mut location = 0;
+    // The line whose instruction no longer fits in ROM.
+    let mut overflow = None;
+    for (unit, source) in units.iter().enumerate() {
+        let symbols = &mut tables[unit];
+        let definitions = &mut definitions[unit];
+        // The `.if`s around the current line, with whether each one's
+        // condition held and whether its `.else` has been reached.
+        let mut conditions: Vec<(&SourceLine, bool, bool)> = Vec::new();
+        for source in source {
+            let active = conditions.iter().all(|&(_, holds, other)| holds != other);
+            let words = tokens(&source.code);
+            let directive = words.first().copied();
+            match directive {
+                Some(".if") if !active => conditions.push((source, false, false)),
+                Some(".if") => {
+                    let condition = match &words[1..] {
+                        [] => Err(CompileErrorKind::InvalidDirective(".if expression")),
+                        expression => resolve(symbols, &expression.join(" ")),
+                    };
+                    let condition = condition.unwrap_or_else(|kind| {
+                        errors.push(CompileError::new(source, kind));
+                        0
+                    });
+                    conditions.push((source, condition != 0, false));
+                }
+                Some(".else") => match conditions.last_mut() {
+                    Some((_, _, other @ false)) => *other = true,
+                    _ => errors.push(CompileError::new(
+                        source,
+                        CompileErrorKind::MisplacedDirective(".else"),
+                    )),
+                },
+                Some(".endif") => match conditions.pop() {
+                    Some(_) => {}
+                    None => errors.push(CompileError::new(
+                        source,
+                        CompileErrorKind::MisplacedDirective(".endif"),
+                    )),
+                },
+                _ => {}
+            }
+            if !active || matches!(directive, Some(".if" | ".else" | ".endif")) {
+                continue;
+            }
+
+            let mut line = source.code.as_str();
+            let mut defined = Vec::new();
+            while let Some((label, rest)) = split_label(line) {
+                defined.push((label, Ok(location as i64), SymbolKind::Label));
+                line = rest;
+            }
+            match split_equ(line) {
+                Some(Ok((name, value))) => {
+                    defined.push((name, resolve(symbols, &value), SymbolKind::Constant));
+                }
+                Some(Err(kind)) => errors.push(CompileError::new(source, kind)),
+                None if matches!(
+                    line.split_whitespace().next(),
+                    Some(".data" | ".word" | ".ascii" | ".asciz")
+                ) =>
+                {
+                    // Labels on a .data line name the block's RAM address, which
+                    // is only known once every constant is.
+                    if let Some(address) = operands(&tokens(line)[1..]).first() {
+                        for (name, _, _) in defined.drain(..) {
+                            data_labels.push((unit, source, name, address.clone()));
+                        }
+                    }
+                    data_lines.push((unit, source, line))
+                }
+                None if matches!(
+                    line.split_whitespace().next(),
+                    Some(".org" | ".fill" | ".align")
+                ) =>
+                {
+                    match padding(symbols, line, location) {
+                        Ok((end, fill)) => {
+                            fills.push((unit, source, location..end, fill));
+                            location = end;
+                        }
+                        Err(kind) => errors.push(CompileError::new(source, kind)),
+                    }
+                }
+                None if line.split_whitespace().next() == Some(".var") => {
+                    let next = variables.last().map_or(0, |(_, _, cells)| cells.end);
+                    match variable(symbols, line, next) {
+                        Ok((name, size)) => {
+                            let kind = SymbolKind::Variable(size);
+                            defined.push((name, Ok(next as i64), kind));
+                            variables.push((source, name, next..next + size));
+                        }
+                        Err(kind) => errors.push(CompileError::new(source, kind)),
+                    }
+                }
+                None if line.split_whitespace().next() == Some(".assert") => {
+                    assertions.push((unit, source, location, line[7..].trim()));
+                }
+                None if line.split_whitespace().next() == Some(".start") => {
+                    match line[6..].trim() {
+                        "" => errors.push(CompileError::new(
+                            source,
+                            CompileErrorKind::InvalidDirective(".start address"),
+                        )),
+                        address => starts.push((unit, source, address)),
+                    }
+                }
+                None if matches!(line.split_whitespace().next(), Some(".export" | ".import")) => {
+                    let (directive, listed) = line.split_at(7);
+                    let (usage, names) = match directive {
+                        ".export" => (".export name, ...", &mut exports),
+                        _ => (".import name, ...", &mut imports),
+                    };
+                    let listed: Vec<&str> = listed
+                        .split(|c: char| c.is_whitespace() || c == ',')
+                        .filter(|name| !name.is_empty())
+                        .collect();
+                    match !listed.is_empty() && listed.iter().all(|name| is_symbol(name)) {
+                        true => names.extend(listed.into_iter().map(|name| (unit, source, name))),
+                        false => errors.push(CompileError::new(
+                            source,
+                            CompileErrorKind::InvalidDirective(usage),
+                        )),
+                    }
+                }
+                None if !line.is_empty() => {
+                    if location == ROM_SIZE {
+                        overflow = Some(source);
+                    }
+                    lines.push((unit, source, location, line));
+                    location += 1;
+                }
+                None => {}
+            }
+            for (name, value, kind) in defined {
+                match value {
+                    Ok(value) => {
+                        define(symbols, definitions, source, name, value, kind, &mut errors)
+                    }
+                    Err(kind) => errors.push(CompileError::new(source, kind)),
+                }
+            }
+        }
+
+        for (source, _, _) in conditions {
+            errors.push(CompileError::new(source, CompileErrorKind::UnterminatedIf));
+        }
+    }
+
+    for (unit, source, name, address) in data_labels {
+        // A bad address is reported with the rest of the .data line.
+        let Ok(address) = resolve(&tables[unit], &address) else {
+            continue;
+        };
+        define(
+            &mut tables[unit],
+            &mut definitions[unit],
+            source,
+            name,
+            address,
+            SymbolKind::Data,
+            &mut errors,
+        );
+    }
+
+    // Link: each file's exports are looked up in that file, and each import
+    // is defined in its file as what another file exports. Symbols that
+    // aren't exported are private to their file.
+    let mut exported: HashMap<&str, (i64, SymbolKind, &SourceLine)> = HashMap::new();
+    for (unit, source, name) in exports {
+        let Some(&value) = tables[unit].get(name) else {
+            let suggestions = closest_symbols(&tables[unit], name);
+            let name = name.to_string();
+            let kind = CompileErrorKind::UndefinedSymbol { name, suggestions };
+            errors.push(CompileError::new(source, kind));
+            continue;
+        };
+        match exported.get(name) {
+            Some(&(_, _, first)) => errors.push(
+                CompileError::new(source, CompileErrorKind::DuplicateExport(name.to_string()))
+                    .noting(first, "first exported here"),
+            ),
+            None => {
+                let kind = definitions[unit]
+                    .get(name)
+                    .map_or(SymbolKind::Constant, |&(kind, _)| kind);
+                exported.insert(name, (value, kind, source));
+            }
+        }
+    }
+    for (unit, source, name) in imports {
+        match exported.get(name) {
+            Some(&(value, kind, _)) => define(
+                &mut tables[unit],
+                &mut definitions[unit],
+                source,
+                name,
+                value,
+                kind,
+                &mut errors,
+            ),
+            None => {
+                let mut names: Vec<&str> = exported.keys().copied().collect();
+                names.sort();
+                let suggestions = closest(name, names, 3);
+                let name = name.to_string();
+                let kind = CompileErrorKind::UnresolvedImport { name, suggestions };
+                errors.push(CompileError::new(source, kind));
+            }
+        }
+    }
+
+    // Execution starts at address 0 unless a file says otherwise.
+    let mut start = 0;
+    if let Some(&(unit, source, address)) = starts.first() {
+        match resolve(&tables[unit], address)
+            .and_then(|address| OperandKind::RomAddress.check(address))
+        {
+            Ok(address) => start = address as u8,
+            Err(kind) => errors.push(CompileError::new(source, kind)),
+        }
+    }
+    for &(_, source, _) in starts.iter().skip(1) {
+        errors.push(
+            CompileError::new(source, CompileErrorKind::RepeatedDirective(".start"))
+                .noting(starts[0].1, "first given here"),
+        );
+    }
+
+    // With -O, a program too long for ROM can still fit once it's optimized.
+    let mut too_long = overflow
+        .map(|source| CompileError::new(source, CompileErrorKind::ProgramTooLong(location)));
+    if !optimize {
+        errors.extend(too_long.take());
+    }
+
+    let mut warnings = Vec::new();
+    let mut rom = vec![RomLayout::new(Instruction::HLT, 0); location];
+    let mut sources = vec![None; location];
+    let mut filled = HashSet::new();
+    for (unit, source, gap, fill) in fills {
+        filled.extend(gap.clone());
+        match assemble_line(&tables[unit], source, &fill, &mut warnings) {
+            Ok(word) => rom[gap.clone()].fill(word),
+            Err(kind) => errors.push(CompileError::new(source, kind)),
+        }
+        sources[gap].fill(Some(source.clone()));
+    }
+    for (unit, source, address, line) in lines {
+        sources[address] = Some(source.clone());
+        let word = match assemble_line(&tables[unit], source, line, &mut warnings) {
+            Ok(word) => word,
+            Err(kind) => {
+                errors.push(CompileError::new(source, kind));
+                continue;
+            }
+        };
+        rom[address] = word;
+        let Some(target) = jump_target(word, address) else {
+            continue;
+        };
+        // Other jumps have their targets checked with their operands.
+        if !(0..ROM_SIZE as i64).contains(&target) {
+            let kind = CompileErrorKind::out_of_range("jump target", target, "0..=255");
+            errors.push(CompileError::new(source, kind));
+        }
+        let operand = tokens(line)[1..].join(" ");
+        if let Some((SymbolKind::Data | SymbolKind::Variable(_), _)) =
+            definitions[unit].get(operand.as_str())
+        {
+            let instruction = word.instruction;
+            let kind = CompileErrorKind::JumpToRam {
+                instruction,
+                operand,
+            };
+            warnings.push(CompileError::new(source, kind));
+        }
+    }
+    let mut checks = Vec::new();
+    for (unit, source, address, text) in assertions {
+        match parse_condition(text, |value| resolve(&tables[unit], value)) {
+            Ok((left, comparison, right)) => checks.push(Assertion {
+                address,
+                left,
+                comparison,
+                right,
+                location: format!("{}:{}", source.file, source.line),
+                text: text.to_string(),
+            }),
+            Err(kind) => errors.push(CompileError::new(source, kind)),
+        }
+    }
+    let mut data = Vec::new();
+    for (unit, source, line) in data_lines {
+        let block = match data_block(&tables[unit], line) {
+            Ok(block) => block,
+            Err(kind) => {
+                errors.push(CompileError::new(source, kind));
+                continue;
+            }
+        };
+        let cells = block.address..block.address + block.values.len();
+        for &(allocated, name, ref variable) in &variables {
+            if cells.start < variable.end && variable.start < cells.end {
+                let kind = CompileErrorKind::DataOverlapsVariable {
+                    name: name.to_string(),
+                    cells: variable.clone(),
+                };
+                errors.push(CompileError::new(source, kind).noting(allocated, "allocated here"));
+            }
+        }
+        data.push(block);
+    }
+    if !errors.is_empty() {
+        sort_errors(&mut errors, files);
+        // An undefined local is reported again as an undefined symbol.
+        errors.dedup_by(|later, earlier| {
+            matches!(earlier.kind, CompileErrorKind::UndefinedLocal { .. })
+                && (&later.file, later.line) == (&earlier.file, earlier.line)
+        });
+        errors.extend(too_long);
+        return Err(errors);
+    }
+
+    // Code placed at fixed addresses has to stay where it is.
+    let checkpoints = checks.iter().map(|assertion| assertion.address).collect();
+    let moved = match optimize && filled.is_empty() {
+        true => peephole(&mut rom, &mut sources, &mut start, &checkpoints),
+        false => None,
+    };
+    if let Some(moved) = &moved {
+        for assertion in &mut checks {
+            assertion.address = moved[assertion.address];
+        }
+    }
+    if let Some(mut error) = too_long.filter(|_| rom.len() > ROM_SIZE) {
+        error.kind = CompileErrorKind::ProgramTooLong(rom.len());
+        return Err(vec![error]);
+    }
+
+    // Reading past the last instruction only ever sees the HLT padding.
+    for (word, source) in rom.iter().zip(&sources) {
+        let target: i64 = word.value.into();
+        if matches!(word.instruction, Instruction::LDR | Instruction::ADR)
+            && target as usize >= rom.len()
+        {
+            let kind = CompileErrorKind::ReadsPastEnd {
+                instruction: word.instruction,
+                address: target,
+                last: rom.len() - 1,
+            };
+            warnings.extend(
+                source
+                    .as_ref()
+                    .map(|source| CompileError::new(source, kind)),
+            );
+        }
+    }
+    // Jumps should land on code, not the data LDR reads or the HLT padding.
+    let read = data_words(&rom);
+    for (address, (word, source)) in rom.iter().zip(&sources).enumerate() {
+        let (Some(target), Some(source)) = (jump_target(*word, address), source) else {
+            continue;
+        };
+        let instruction = word.instruction;
+        let kind = match target as usize {
+            target if target >= rom.len() => CompileErrorKind::JumpPastEnd {
+                instruction,
+                target,
+                last: rom.len() - 1,
+            },
+            target if read.contains(&target) => CompileErrorKind::JumpToData {
+                instruction,
+                target,
+            },
+            _ => continue,
+        };
+        warnings.push(CompileError::new(source, kind));
+    }
+    // Without a HLT, the ROM only stops because the loader pads it with them.
+    if let (Some(word), Some(Some(source))) = (rom.last(), sources.last()) {
+        if falls_through(word.instruction) {
+            let kind = CompileErrorKind::FallsOffEnd(word.instruction);
+            warnings.push(CompileError::new(source, kind));
+        }
+    }
+    // Padding and the words LDR and ADR read are data, not dead code.
+    if let Some(reached) = reachable(&rom, start as usize) {
+        let mut data = data_words(&rom);
+        data.extend(filled);
+        let dead = |address: usize| match &sources[address] {
+            Some(source) => {
+                !reached[address] && !data.contains(&address) && !allows(source, "unreachable")
+            }
+            None => false,
+        };
+        let mut address = 0;
+        while address < rom.len() {
+            let run = (address..rom.len())
+                .take_while(|&address| dead(address))
+                .count();
+            if let (1.., Some(source)) = (run, &sources[address]) {
+                let kind = CompileErrorKind::Unreachable(run);
+                warnings.push(CompileError::new(source, kind));
+            }
+            address += run.max(1);
+        }
+    }
+    for (address, cell) in uninitialized_reads(&rom, &data).unwrap_or_default() {
+        let Some(source) = &sources[address] else {
+            continue;
+        };
+        if !allows(source, "uninitialized") {
+            let instruction = rom[address].instruction;
+            let kind = CompileErrorKind::UninitializedRead { instruction, cell };
+            warnings.push(CompileError::new(source, kind));
+        }
+    }
+
+    let mut symbols: Vec<_> = tables
+        .into_iter()
+        .zip(&definitions)
+        .flat_map(|(symbols, definitions)| {
+            symbols.into_iter().map(|(name, value)| {
+                let definition = definitions.get(name);
+                let kind = definition.map_or(SymbolKind::Constant, |&(kind, _)| kind);
+                // Labels move with the words they name.
+                let value = match (&moved, usize::try_from(value)) {
+                    (Some(moved), Ok(address)) if kind == SymbolKind::Label => {
+                        moved.get(address).map_or(value, |&address| address as i64)
+                    }
+                    _ => value,
+                };
+                Symbol {
+                    name: name.to_string(),
+                    value,
+                    kind,
+                    definition: definition.map(|(_, source)| (source.file.clone(), source.line)),
+                }
+            })
+        })
+        .collect();
+    symbols.sort_by(|a, b| a.value.cmp(&b.value).then(a.name.cmp(&b.name)));
+    // Imports and --define constants are in more than one file's symbols.
+    symbols.dedup_by(|a, b| (&a.name, a.value) == (&b.name, b.value));
+    sort_errors(&mut warnings, files);
+    Ok(Program {
+        rom,
+        data,
+        start,
+        assertions: checks,
+        sources,
+        source_files,
+        symbols,
+        warnings,
+    })
+}
+
+/// Puts errors in file and line order. Errors from macro bodies and
+/// included files are ordered by where they were brought into the file
+/// being assembled.
+fn sort_errors(errors: &mut [CompileError], files: &[(String, &Path)]) {
+    errors.sort_by_key(|error| {
+        let (file, outermost) = error
+            .context
+            .last()
+            .map_or((&error.file, error.line), |context| {
+                (&context.file, context.line)
+            });
+        let unit = files
+            .iter()
+            .position(|(_, path)| path.display().to_string() == **file);
+        (unit, outermost, error.line)
+    });
+}
+
+/// Reads an .ebrc file: see Program for the format. With `legacy`, a file
+/// from before the header still loads, as do the older ones from before
+/// sections, which are just the instruction words.
+fn read_rom(bytes: &[u8], legacy: bool) -> Result<Program, String> {
+    let file = split_rom(bytes, legacy)?;
+    let rom = file
+        .words
+        .chunks(8)
+        .enumerate()
+        .map(|(address, word)| {
+            RomLayout::unpack_from_slice(word).map_err(|_| {
+                format!(
+                    "ROM word {} has opcode {:#04x}, which no instruction has",
+                    address, word[0]
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    read_sections(rom, file.sections, file.count)
+}
+
+/// The parts of an .ebrc file.
+struct RomFile<'a> {
+    words: &'a [u8],
+    sections: &'a [u8],
+    /// How many sections the header says there are; a file without a
+    /// header doesn't say.
+    count: Option<usize>,
+}
+
+/// Splits an .ebrc file into its instruction words and its sections, after
+/// checking the header. With `legacy`, a file without a header is split at
+/// its first section.
+fn split_rom(bytes: &[u8], legacy: bool) -> Result<RomFile<'_>, String> {
+    if !bytes.starts_with(MAGIC) {
+        if !legacy {
+            return Err("this doesn't look like an ebrc file (if it's from before ebrc files had a header, try --legacy)".to_string());
+        }
+        let length = bytes
+            .chunks(8)
+            .take_while(|word| word.len() == 8 && word[0] != SECTION_MARKER)
+            .count();
+        let (words, sections) = bytes.split_at(length * 8);
+        return Ok(RomFile {
+            words,
+            sections,
+            count: None,
+        });
+    }
+    let header = bytes.get(..16).ok_or("truncated ebrc header")?;
+    let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+    match u16::from_be_bytes([header[4], header[5]]) {
+        1..=FORMAT_VERSION => {}
+        version => {
+            return Err(format!(
+                "this ebrc file is format version {}, but this e64br only reads up to version {}",
+                version, FORMAT_VERSION
+            ))
+        }
+    }
+    let (length, sections) = (number(&header[8..12]), number(&header[12..16]));
+    let words = bytes[16..]
+        .get(..length * 8)
+        .ok_or("the ebrc file ends in its instructions")?;
+    Ok(RomFile {
+        words,
+        sections: &bytes[16 + words.len()..],
+        count: Some(sections),
+    })
+}
+
+/// Reads the sections after the ROM of an .ebrc file into a program. With
+/// `count`, there have to be that many sections.
+fn read_sections(
+    rom: Vec<RomLayout>,
+    bytes: &[u8],
+    count: Option<usize>,
+) -> Result<Program, String> {
+    let mut words = bytes.chunks(8);
+    let mut program = Program {
+        rom,
+        data: Vec::new(),
+        start: 0,
+        assertions: Vec::new(),
+        sources: Vec::new(),
+        source_files: Vec::new(),
+        symbols: Vec::new(),
+        warnings: Vec::new(),
+    };
+    if program.rom.len() > ROM_SIZE {
+        return Err(format!(
+            "program is {} instructions, maximum is {}",
+            program.rom.len(),
+            ROM_SIZE
+        ));
+    }
+
+    let mut header = words.next();
+    let mut sections = 0;
+    while let Some(chunk) = header {
+        sections += 1;
+        let header_word: [u8; 8] = chunk.try_into().map_err(|_| "truncated section header")?;
+        if header_word[0] != SECTION_MARKER {
+            return Err("expected a section header after the last section".to_string());
+        }
+        let length = u32::from_be_bytes(header_word[4..].try_into().unwrap()) as usize;
+        let payload = words
+            .by_ref()
+            .take(length)
+            .map(|word| word.try_into().map(i64::from_be_bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "truncated section")?;
+        if payload.len() != length {
+            return Err("truncated section".to_string());
+        }
+        // Unknown sections are skipped, so newer files still run here.
+        if header_word[1] == DATA_SECTION {
+            let (&address, values) = payload.split_first().ok_or("empty data section")?;
+            if address < 0 || address as usize + values.len() > RAM_SIZE {
+                return Err(format!("data section at {} overruns RAM", address));
+            }
+            program.data.push(DataBlock {
+                address: address as usize,
+                values: values.to_vec(),
+            });
+        }
+        if header_word[1] == ENTRY_SECTION {
+            program.start = match payload[..] {
+                [start @ 0..=255] => start as u8,
+                _ => return Err("invalid entry section".to_string()),
+            };
+        }
+        if header_word[1] == ASSERTION_SECTION {
+            let assertion = read_assertion(&payload).ok_or("invalid assertion section")?;
+            program.assertions.push(assertion);
+        }
+        if header_word[1] == SOURCE_MAP_SECTION {
+            read_source_map(&mut program, &payload).ok_or("invalid source map section")?;
+        }
+        header = words.next();
+    }
+    match count {
+        Some(count) if count != sections => Err(format!(
+            "the ebrc header says there are {} sections, but there are {}",
+            count, sections
+        )),
+        _ => Ok(program),
+    }
+}
+
+/// Reads the payload of a source map section into `program`, which already
+/// has its ROM. Source lines have no text until Program::read_source_text.
+fn read_source_map(program: &mut Program, payload: &[i64]) -> Option<()> {
+    let mut words = payload.iter().map(|&word| word as u64);
+    for _ in 0..words.next()? {
+        let hash = words.next()?;
+        let name = read_string(&mut words)?;
+        program.source_files.push(SourceFile {
+            name: name.into(),
+            hash,
+        });
+    }
+    program.sources = vec![None; program.rom.len()];
+    for word in words {
+        let (address, file, line) = (word >> 48, word >> 32 & 0xFFFF, word & 0xFFFF_FFFF);
+        let file = program.source_files.get(file as usize)?;
+        *program.sources.get_mut(address as usize)? = Some(SourceLine {
+            file: file.name.clone(),
+            line: line as usize,
+            text: String::new(),
+            code: String::new(),
+            context: Vec::new(),
+        });
+    }
+    Some(())
+}
+
+/// Reads the payload of an assertion section.
+fn read_assertion(payload: &[i64]) -> Option<Assertion> {
+    let mut words = payload.iter().map(|&word| word as u64);
+    let address = usize::try_from(words.next()?).ok()?;
+    let location = read_string(&mut words)?;
+    let text = read_string(&mut words)?;
+    let condition = read_string(&mut words)?;
+    let (left, comparison, right) =
+        parse_condition(&condition, |value| resolve(&HashMap::new(), value)).ok()?;
+    Some(Assertion {
+        address,
+        left,
+        comparison,
+        right,
+        location,
+        text,
+    })
+}
+
+/// Where `token` is on `line`: the first place it appears as a whole word,
+/// so `x` isn't found inside `max`.
+fn find_token(line: &str, token: &str) -> Option<Range<usize>> {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    line.match_indices(token)
+        .map(|(start, _)| start..start + token.len())
+        .find(|span| {
+            let before = line[..span.start].chars().next_back();
+            let after = line[span.end..].chars().next();
+            let joined = token.starts_with(word) && before.is_some_and(word)
+                || token.ends_with(word) && after.is_some_and(word);
+            !joined
+        })
+}
+
+/// The part of an error's line to underline: the token it's about, or else
+/// all the line's code.
+fn highlight(error: &CompileError) -> Range<usize> {
+    let code = strip_comment(&error.text).trim_end();
+    let start = code.len() - code.trim_start().len();
+    match error.kind.token() {
+        Some(token) => find_token(code, token),
+        None => None,
+    }
+    .unwrap_or(start..code.len())
+}
+
+/// An assembler error or warning with the line it's about, the token it's
+/// about underlined, and where that line came from. With `color`, the
+/// severity and underline are in ANSI colors.
+fn render(error: &CompileError, warning: bool, color: bool) -> String {
+    let (severity, style) = match warning {
+        true => ("warning", "\x1b[1;33m"),
+        false => ("error", "\x1b[1;31m"),
+    };
+    let paint = |text: &str, style: &str| match color {
+        true => format!("{}{}\x1b[0m", style, text),
+        false => text.to_string(),
+    };
+    let mut rendered = String::new();
+    rendered.push_str(&format!(
+        "{}:{}: {}: {}\n",
+        error.file,
+        error.line,
+        paint(severity, style),
+        error.kind
+    ));
+    let indent = error.text.len() - error.text.trim_start().len();
+    rendered.push_str(&format!("    {}\n", error.text.trim()));
+    let span = highlight(error);
+    if !span.is_empty() {
+        // Tabs are kept so the carets line up with what they're under.
+        let margin: String = error.text[indent..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(error.text[span].chars().count());
+        match error.kind.help() {
+            Some(help) => rendered.push_str(&format!(
+                "    {}{} {}\n",
+                margin,
+                paint(&carets, style),
+                help
+            )),
+            None => rendered.push_str(&format!("    {}{}\n", margin, paint(&carets, style))),
+        }
+    }
+    for context in error.context.iter().chain(&error.notes) {
+        rendered.push_str(&format!(
+            "{}:{}: {}: {}\n",
+            context.file,
+            context.line,
+            paint("note", "\x1b[1;36m"),
+            context.note
+        ));
+        rendered.push_str(&format!("    {}\n", context.text.trim()));
+    }
+    rendered
+}
+
+/// Prints `render`'s rendering of `error` to stderr.
+fn report(error: &CompileError, warning: bool, color: bool) {
+    eprint!("{}", render(error, warning, color));
+}
+
+/// `text` as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Diagnostics, each an error or a warning, as a JSON array for editors:
+///
+/// ```text
+/// [{"file": "fib.ebr", "line": 3, "column": 5, "severity": "error",
+///   "message": "`lop` is not defined, did you mean `loop`?", "notes": []}]
+/// ```
+///
+/// The column is that of the token the error is about, the one the terminal
+/// report underlines, counted from 1. Each note has a file, line and message
+/// of its own.
+fn json_diagnostics(diagnostics: &[(&CompileError, bool)]) -> String {
+    let objects: Vec<String> = diagnostics
+        .iter()
+        .map(|&(diagnostic, warning)| {
+            let notes: Vec<String> = diagnostic
+                .context
+                .iter()
+                .chain(&diagnostic.notes)
+                .map(|note| {
+                    format!(
+                        "{{\"file\": {}, \"line\": {}, \"message\": {}}}",
+                        json_string(&note.file),
+                        note.line,
+                        json_string(note.note)
+                    )
+                })
+                .collect();
+            let column = diagnostic.text[..highlight(diagnostic).start]
+                .chars()
+                .count()
+                + 1;
+            format!(
+                "{{\"file\": {}, \"line\": {}, \"column\": {}, \"severity\": \"{}\", \"message\": {}, \"notes\": [{}]}}",
+                json_string(&diagnostic.file),
+                diagnostic.line,
+                column,
+                if warning { "warning" } else { "error" },
+                json_string(&diagnostic.kind.to_string()),
+                notes.join(", ")
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(", "))
+}
+
+/// `error` with the file and line of the instruction that faulted, and the
+/// line itself, if the program knows them.
+fn fault_message(program: &Program, error: &RuntimeError) -> String {
+    match program.sources.get(error.program_counter as usize) {
+        Some(Some(source)) if !source.text.trim().is_empty() => format!(
+            "{}:{}: {}\n    {}",
+            source.file,
+            source.line,
+            error,
+            source.text.trim()
+        ),
+        Some(Some(source)) => format!("{}:{}: {}", source.file, source.line, error),
+        _ => error.to_string(),
+    }
+}
+
+fn breakpoint(
+    state: &ProgramState,
+    program: &Program,
+    symbols: &[Symbol],
+    address: u8,
+) -> io::Result<()> {
+    let mut place: Vec<String> = symbolic_address(symbols, address).into_iter().collect();
+    if let Some(Some(source)) = program.sources.get(address as usize) {
+        place.push(format!("{}:{}", source.file, source.line));
+    }
+    match place.is_empty() {
+        true => eprintln!("BRK at {}: {}", address, state.describe()),
+        false => eprintln!(
+            "BRK at {} ({}): {}",
+            address,
+            place.join(", "),
+            state.describe()
+        ),
+    }
+    for (row, cells) in state.ram.chunks(8).take(2).enumerate() {
+        let cells: Vec<_> = cells.iter().map(|cell| format!("{:>6}", cell)).collect();
+        eprintln!("  ram[{:>3}] {}", row * 8, cells.join(" "));
+    }
+    eprint!("press Enter to continue");
+    io::stderr().flush()?;
+    io::stdin().lock().read_line(&mut String::new())?;
+    Ok(())
+}
+
+/// How -c assembles and what it writes.
+struct CompileOptions {
+    defines: Vec<(String, i64)>,
+    optimize: bool,
+    /// Warnings are errors.
+    strict: bool,
+    /// Diagnostics are printed as JSON rather than reported.
+    json: bool,
+    color: bool,
+    /// Nothing is written.
+    check: bool,
+    output: String,
+    source_map: bool,
+    listing: Option<String>,
+    symbols: Option<String>,
+}
+
+/// `e64br -c FILE...` assembles the files into one program, reporting what's
+/// wrong with them, and writes it and any listing or symbol table asked for,
+/// unless it's a --check. Whether the files assembled, without warnings if
+/// they're errors.
+fn compile_command(sources: &[String], options: &CompileOptions) -> io::Result<bool> {
+    let files = sources
+        .iter()
+        .map(|source| Ok((std::fs::read_to_string(source)?, Path::new(source))))
+        .collect::<io::Result<Vec<_>>>()?;
+    let result = compile_rom(&files, &options.defines, options.optimize);
+    // Errors, then warnings, which --strict makes errors.
+    let diagnostics: Vec<(&CompileError, bool)> = match &result {
+        Ok(program) => program
+            .warnings
+            .iter()
+            .map(|warning| (warning, !options.strict))
+            .collect(),
+        Err(errors) => errors.iter().map(|error| (error, false)).collect(),
+    };
+    match options.json {
+        true => println!("{}", json_diagnostics(&diagnostics)),
+        false => {
+            for &(diagnostic, warning) in &diagnostics {
+                report(diagnostic, warning, options.color);
+            }
+        }
+    }
+    let program = match result {
+        Ok(program) if !options.strict || program.warnings.is_empty() => program,
+        _ => return Ok(false),
+    };
+
+    if !options.check {
+        std::fs::write(&options.output, program.to_bytes(options.source_map))?;
+        if let Some(path) = &options.listing {
+            std::fs::write(path, listing(&program))?;
+        }
+        if let Some(path) = &options.symbols {
+            std::fs::write(path, symbol_table(&program.symbols))?;
+        }
+    }
+    Ok(true)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let arguments: Vec<String> = std::env::args().collect();
+    if arguments.get(1).map(String::as_str) == Some("fmt") {
+        return format_command(&arguments[2..]);
+    }
+    if arguments.get(1).map(String::as_str) == Some("lsp") {
+        return lsp::serve();
+    }
+
+    let mut args = Args::new("Emulator 64Bit (Rust)", "It isn't that exciting.");
+    args.option(
+        "c",
+        "compile",
+        "Used to compile a ebr file into bytecode. Given more than once, the files are linked into one program, in order.",
+        "FILE",
+        Occur::Multi,
+        None,
+    );
+    args.option(
+        "o",
+        "output",
+        "Used to name the outputted ebrc file from -c.",
+        "FILE",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "l",
+        "listing",
+        "Also write a listing of the program compiled with -c.",
+        "FILE",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "",
+        "symbols",
+        "Write the symbols of the program compiled with -c to FILE, or read them for --debug.",
+        "FILE",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "",
+        "define",
+        "Defines a constant for the program compiled with -c, e.g. --define DEBUG=1. Repeatable.",
+        "NAME=value",
+        Occur::Multi,
+        None,
+    );
+    args.option(
+        "d",
+        "disassemble",
+        "Print a ebrc file as ebr source that compiles back to it.",
+        "FILE",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "r",
+        "run",
+        "Used to run a ebrc file",
+        "FILE",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "",
+        "seed",
+        "Seeds RND so runs from -r are reproducible.",
+        "N",
+        Occur::Optional,
+        None,
+    );
+
+    args.flag(
+        "",
+        "strict",
+        "Treat warnings about the program compiled with -c as errors.",
+    );
+    args.flag(
+        "",
+        "check",
+        "Only report what's wrong with the program compiled with -c, writing nothing.",
+    );
+    args.flag(
+        "",
+        "json-diagnostics",
+        "Print the errors and warnings about the program compiled with -c as JSON, on stdout.",
+    );
+    args.flag(
+        "O",
+        "optimize",
+        "Remove redundant instructions from the program compiled with -c, unless it uses .org, .fill or .align.",
+    );
+    args.option(
+        "",
+        "color",
+        "Color the errors and warnings about the program compiled with -c: always, never, or auto, when stderr is a terminal.",
+        "WHEN",
+        Occur::Optional,
+        Some("auto".to_string()),
+    );
+    args.flag(
+        "",
+        "source-map",
+        "Save which source line each instruction compiled with -c came from, for -r to report.",
+    );
+    args.flag(
+        "",
+        "show-padding",
+        "Print the HLT padding at the end of the file disassembled with -d.",
+    );
+    args.flag(
+        "",
+        "legacy",
+        "Read a file for -r or -d from before ebrc files had a header. This goes away in the next release.",
+    );
+    args.flag(
+        "",
+        "debug",
+        "Pause at BRK instructions in the program run with -r.",
+    );
+
+    args.flag(
+        "",
+        "no-dbg",
+        "Silence DBG instructions in the program run with -r.",
+    );
+
+    args.flag(
+        "",
+        "no-asserts",
+        "Skip the .assert checks of the program run with -r.",
+    );
+    args.flag(
+        "",
+        "no-sleep",
+        "Make SLP a no-op in the program run with -r.",
+    );
+    args.flag(
+        "",
+        "allow-self-modify",
+        "Let STRI overwrite whole ROM words, opcodes included.",
+    );
+    args.flag(
+        "",
+        "wrap-pc",
+        "Wrap the program counter from 255 back to 0 instead of halting.",
+    );
+    args.flag(
+        "",
+        "no-padding-warning",
+        "Don't warn when execution runs past the last instruction into the padding.",
+    );
+    args.flag(
+        "",
+        "describe-isa",
+        "Print every instruction's opcode and effect on the flags, then exit.",
+    );
+    args.option(
+        "",
+        "port",
+        "Maps an OUT port to a file, e.g. --port 2=results.txt. Repeatable.",
+        "N=FILE",
+        Occur::Multi,
+        None,
+    );
+
+    args.parse(arguments)?;
+
+    let sources = args.values_of::<String>("compile");
+    let output = args.value_of::<String>("output");
+    let listing_path = args.value_of::<String>("listing");
+    let symbols_path = args.value_of::<String>("symbols");
+    let strict = args.value_of::<bool>("strict")?;
+    let source_map = args.value_of::<bool>("source-map")?;
+    let optimize = args.value_of::<bool>("optimize")?;
+    let no_asserts = args.value_of::<bool>("no-asserts")?;
+    let check = args.value_of::<bool>("check")?;
+    let json = args.value_of::<bool>("json-diagnostics")?;
+    let color = match args.value_of::<String>("color")?.as_str() {
+        "always" => true,
+        "never" => false,
+        "auto" => io::stderr().is_terminal(),
+        other => {
+            return Err(format!("--color expects always, never or auto, got `{}`", other).into())
+        }
+    };
+    let disassembled = args.value_of::<String>("disassemble");
+    let show_padding = args.value_of::<bool>("show-padding")?;
+    let legacy = args.value_of::<bool>("legacy")?;
+    let rom = args.value_of::<String>("run");
+    let seed = args.optional_value_of::<u64>("seed")?;
+    let debug = args.value_of::<bool>("debug")?;
+    let no_dbg = args.value_of::<bool>("no-dbg")?;
+    let no_sleep = args.value_of::<bool>("no-sleep")?;
+    let allow_self_modify = args.value_of::<bool>("allow-self-modify")?;
+    let wrap_pc = args.value_of::<bool>("wrap-pc")?;
+    let no_padding_warning = args.value_of::<bool>("no-padding-warning")?;
+    let ports = args.values_of::<String>("port").unwrap_or_default();
+    let defines = args
+        .values_of::<String>("define")
+        .unwrap_or_default()
+        .iter()
+        .map(|define| {
+            let (name, value) = define
+                .split_once('=')
+                .ok_or_else(|| format!("--define expects NAME=value, got `{}`", define))?;
+            if !is_identifier(name) {
+                return Err(format!("--define: `{}` is not a valid name", name));
+            }
+            let value = resolve(&HashMap::new(), value)
+                .map_err(|kind| format!("--define {}: {}", name, kind))?;
+            Ok((name.to_string(), value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if args.value_of::<bool>("describe-isa")? {
+        describe_isa();
+        return Ok(());
+    }
+
+    if let Ok(sources) = sources {
+        let options = CompileOptions {
+            defines,
+            optimize,
+            strict,
+            json,
+            color,
+            check,
+            output: output.unwrap_or_else(|_| "a.ebrc".to_string()),
+            source_map,
+            listing: listing_path.ok(),
+            symbols: symbols_path.as_ref().ok().cloned(),
+        };
+        if !compile_command(&sources, &options)? {
+            std::process::exit(1);
+        }
+    }
+
+    if let Ok(path) = disassembled {
+        print!(
+            "{}",
+            disassemble(&std::fs::read(path)?, show_padding, legacy)?
+        );
+    }
+
+    if let Ok(run) = rom {
+        let rom = std::fs::read(run)?;
+        let mut program = read_rom(&rom, legacy)?;
+        program.read_source_text();
+        let symbols = match &symbols_path {
+            Ok(path) => read_symbols(&std::fs::read_to_string(path)?)?,
+            Err(_) => Vec::new(),
+        };
+        let mut state = ProgramState::loaded(&program);
+        state.warn_padding = !no_padding_warning;
+        if let Some(seed) = seed {
+            state = state.seeded(seed);
+        }
+        state.dbg = !no_dbg;
+        state.sleep = !no_sleep;
+        state.allow_self_modify = allow_self_modify;
+        state.wrap_pc = wrap_pc;
+        for mapping in ports {
+            let (port, path) = mapping
+                .split_once('=')
+                .ok_or_else(|| format!("--port expects N=FILE, got `{}`", mapping))?;
+            let port = port
+                .parse::<i64>()
+                .map_err(|_| format!("--port: `{}` is not a port number", port))?;
+            state
+                .ports
+                .insert(port, Box::new(std::fs::File::create(path)?));
+        }
+        loop {
+            let program_counter = state.program_counter;
+            if let Some(message) = failed_assertion(&program, &state).filter(|_| !no_asserts) {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+            match state.step() {
+                Ok(_) if state.is_halted() => break,
+                Ok(Instruction::BRK) if debug => {
+                    breakpoint(&state, &program, &symbols, program_counter)?
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("{}", fault_message(&program, &e));
+                    std::process::exit(1);
+                }
+            }
+        }
+        std::process::exit(state.exit_code.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    /// An output port that keeps what's written to it.
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Capture {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).into_owned()
+        }
+    }
+
+    impl Write for Capture {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn assemble(source: &str) -> Result<Program, Vec<CompileError>> {
+        compile_rom(&[(source.to_string(), Path::new("test.ebr"))], &[], false)
+    }
+
+    fn compile(source: &str) -> Program {
+        assemble(source).unwrap_or_else(|errors| {
+            let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            panic!("failed to assemble:\n{}", errors.join("\n"))
+        })
+    }
+
+    /// What `source` fails to assemble with.
+    fn errors(source: &str) -> Vec<CompileErrorKind> {
+        match assemble(source) {
+            Ok(_) => panic!("assembled without errors"),
+            Err(errors) => errors.into_iter().map(|error| error.kind).collect(),
+        }
+    }
+
+    /// A machine loaded with `program` as -r loads it, seeded, without sleeps
+    /// and with DBG kept quiet, and what it writes to port 0. Port 1 is
+    /// captured too, to keep test output quiet.
+    fn machine(program: &Program) -> (ProgramState, Capture) {
+        let mut state = ProgramState::loaded(program).seeded(0);
+        state.sleep = false;
+        state.dbg = false;
+        let stdout = Capture::default();
+        state.ports.insert(0, Box::new(stdout.clone()));
+        state.ports.insert(1, Box::new(Capture::default()));
+        (state, stdout)
+    }
+
+    /// Steps `state` until it halts.
+    fn finish(state: &mut ProgramState) -> Result<(), RuntimeError> {
+        for _ in 0..100_000 {
+            state.step()?;
+            if state.is_halted() {
+                return Ok(());
+            }
+        }
+        panic!("still running after 100000 steps");
+    }
+
+    /// Runs `source` until it halts, returning the machine and its output.
+    fn run(source: &str) -> (ProgramState, String) {
+        let (mut state, stdout) = machine(&compile(source));
+        finish(&mut state).unwrap_or_else(|error| panic!("{}", error));
+        (state, stdout.text())
+    }
+
+    /// The error `source` stops with.
+    fn fault(source: &str) -> RuntimeError {
+        let (mut state, _) = machine(&compile(source));
+        finish(&mut state).expect_err("ran to HLT")
+    }
+
+    fn flags(state: &ProgramState) -> ProgramFlags {
+        state.flags.difference(ProgramFlags::JUMP)
+    }
+
+    #[test]
+    fn sub_to_zero_sets_zero() {
+        let (state, _) = run("LDI 5\nSTA 0\nSUB 0\nHLT\n");
+        assert_eq!(state.reg_a, 0);
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+    }
+
+    #[test]
+    fn sub_below_zero_goes_negative() {
+        let (state, _) = run("LDI 5\nSTA 0\nLDI 3\nSUB 0\nHLT\n");
+        assert_eq!(state.reg_a, -2);
+        assert_eq!(flags(&state), ProgramFlags::CARRY | ProgramFlags::NEGATIVE);
+    }
+
+    #[test]
+    fn sub_at_i64_min() {
+        let (mut state, _) = machine(&compile("SUB 0\nSUB 1\nHLT\n"));
+        state.reg_a = i64::MIN;
+        state.ram[0] = 1;
+        state.ram[1] = i64::MAX;
+        state.step().unwrap();
+        assert_eq!(state.reg_a, i64::MAX);
+        assert_eq!(flags(&state), ProgramFlags::OVERFLOW);
+        state.step().unwrap();
+        assert_eq!(state.reg_a, 0);
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+
+        let (mut state, _) = machine(&compile("SUB 0\nHLT\n"));
+        state.ram[0] = i64::MIN;
+        state.step().unwrap();
+        assert_eq!(state.reg_a, i64::MIN);
+        assert_eq!(
+            flags(&state),
+            ProgramFlags::CARRY | ProgramFlags::NEGATIVE | ProgramFlags::OVERFLOW
+        );
+    }
+
+    #[test]
+    fn jc_after_sub_means_borrow() {
+        for (start, end) in [(0, 1), (5, 4)] {
+            let (mut state, _) = machine(&compile("SUB 0\nJC 4\nHLT\nHLT\nLDI 1\nHLT\n"));
+            state.reg_a = start;
+            state.ram[0] = 1;
+            finish(&mut state).unwrap();
+            assert_eq!(state.reg_a, end);
+        }
+    }
+
+    #[test]
+    fn countdown_runs_to_completion() {
+        let (state, _) = run(include_str!("../examples/countdown.ebr"));
+        assert_eq!(state.reg_a, 0);
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+    }
+
+    #[test]
+    fn adi_adds_a_negative_immediate() {
+        let (state, _) = run("LDI 5\nADI -5\nJZ 5\nLDI 1\nHLT\nLDI 2\nHLT\n");
+        assert_eq!(state.reg_a, 2);
+    }
+
+    #[test]
+    fn adi_sets_flags_like_add() {
+        for (a, b) in [(5, -5), (-1, 1), (3, 4), (-3, -4)] {
+            let (add, _) = run(&format!("LDI {}\nSTA 0\nLDI {}\nADD 0\nHLT\n", b, a));
+            let (adi, _) = run(&format!("LDI {}\nADI {}\nHLT\n", a, b));
+            assert_eq!(adi.reg_a, a + b);
+            assert_eq!(adi.reg_a, add.reg_a);
+            assert_eq!(adi.flags, add.flags, "{} + {}", a, b);
+        }
+    }
+
+    #[test]
+    fn adr_adds_a_constant_from_rom() {
+        // The constant is the value field of the word after the HLT.
+        let (state, _) = run("LDI 5\nADR 3\nHLT\nLDI 37\n");
+        assert_eq!(state.reg_a, 42);
+        assert_eq!(flags(&state), ProgramFlags::NONE);
+    }
+
+    #[test]
+    fn adr_sets_flags_like_add() {
+        let (add, _) = run("LDI 1\nSTA 0\nLDI -1\nADD 0\nHLT\n");
+        let (adr, _) = run("LDI -1\nADR 3\nHLT\nLDI 1\n");
+        assert_eq!(adr.reg_a, 0);
+        assert_eq!(adr.flags, add.flags);
+    }
+
+    #[test]
+    fn sui_below_zero_goes_negative() {
+        let (state, _) = run("LDI 0\nSUI 1\nHLT\n");
+        assert_eq!(state.reg_a, -1);
+        assert_eq!(flags(&state), ProgramFlags::CARRY | ProgramFlags::NEGATIVE);
+
+        let (mut state, _) = machine(&compile("SUI 1\nHLT\n"));
+        state.reg_a = i64::MIN;
+        state.step().unwrap();
+        assert_eq!(state.reg_a, i64::MAX);
+        assert_eq!(flags(&state), ProgramFlags::OVERFLOW);
+    }
+
+    #[test]
+    fn sui_then_jz() {
+        let program = |a| format!("LDI {}\nSUI 3\nJZ 4\nHLT\nLDI 9\nHLT\n", a);
+        assert_eq!(run(&program(3)).0.reg_a, 9);
+        assert_eq!(run(&program(2)).0.reg_a, -1);
+        assert_eq!(run(&program(4)).0.reg_a, 1);
+    }
+
+    #[test]
+    fn mul_overflow_at_i64_boundaries() {
+        let cases = [
+            (i64::MAX, 1, i64::MAX, false),
+            (i64::MAX, 2, -2, true),
+            (i64::MIN, -1, i64::MIN, true),
+            (i64::MIN, 1, i64::MIN, false),
+            (1 << 32, 1 << 31, i64::MIN, true),
+            (-(1 << 31), 1 << 32, i64::MIN, false),
+        ];
+        for (a, b, product, overflow) in cases {
+            let (mut state, _) = machine(&compile("MUL 0\nHLT\n"));
+            state.reg_a = a;
+            state.ram[0] = b;
+            state.step().unwrap();
+            assert_eq!(state.reg_a, product, "{} * {}", a, b);
+            assert_eq!(
+                state.flags.contains(ProgramFlags::CARRY),
+                overflow,
+                "{} * {}",
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn mli_multiplies_by_an_immediate() {
+        let (state, _) = run("LDI -6\nMLI 7\nJC 4\nHLT\nLDI 1\nHLT\n");
+        assert_eq!(state.reg_a, -42);
+        assert_eq!(flags(&state), ProgramFlags::NEGATIVE);
+    }
+
+    #[test]
+    fn div_and_mod() {
+        let (state, _) = run("LDI 5\nSTA 0\nLDI -17\nDIV 0\nSTA 1\nLDI -17\nMOD 0\nHLT\n");
+        assert_eq!((state.ram[1], state.reg_a), (-3, -2));
+    }
+
+    #[test]
+    fn div_and_mod_by_zero_fault() {
+        for instruction in ["DIV", "MOD"] {
+            let error = fault(&format!("LDI 7\n{} 0\nHLT\n", instruction));
+            assert_eq!(error.fault, RuntimeFault::DivideByZero);
+            assert_eq!(error.program_counter, 1);
+            assert_eq!(
+                error.to_string(),
+                format!("runtime error at pc 1 ({}): divide by zero", instruction)
+            );
+        }
+    }
+
+    #[test]
+    fn div_and_mod_of_i64_min_by_minus_one_fault() {
+        for instruction in ["DIV", "MOD"] {
+            let (mut state, _) = machine(&compile(&format!("{} 0\nHLT\n", instruction)));
+            state.reg_a = i64::MIN;
+            state.ram[0] = -1;
+            let error = state.step().unwrap_err();
+            assert_eq!(error.fault, RuntimeFault::DivideOverflow);
+            assert_eq!(state.reg_a, i64::MIN);
+        }
+    }
+
+    #[test]
+    fn bitwise_ops_set_zero_for_jz() {
+        // Each is 0b1100 against 0b1010, then against itself.
+        for (instruction, result, with_itself) in [
+            ("AND", 0b1000, 0b1100),
+            ("OR", 0b1110, 0b1100),
+            ("XOR", 0b0110, 0),
+        ] {
+            let (mut state, _) = machine(&compile(&format!("{0} 0\n{0} 1\nHLT\n", instruction)));
+            state.reg_a = 0b1100;
+            state.ram[0] = 0b1010;
+            state.flags = ProgramFlags::CARRY;
+            state.step().unwrap();
+            assert_eq!(state.reg_a, result, "{}", instruction);
+            assert_eq!(flags(&state), ProgramFlags::NONE, "{}", instruction);
+
+            state.reg_a = 0b1100;
+            state.ram[1] = 0b1100;
+            state.step().unwrap();
+            assert_eq!(state.reg_a, with_itself, "{}", instruction);
+            assert_eq!(
+                state.flags.contains(ProgramFlags::ZERO),
+                with_itself == 0,
+                "{}",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn and_mask_match_with_jz() {
+        let program = |value| {
+            format!(
+                "LDI 4\nSTA 0\nLDI {}\nAND 0\nJZ 6\nHLT\nLDI 9\nHLT\n",
+                value
+            )
+        };
+        assert_eq!(run(&program(6)).0.reg_a, 4);
+        assert_eq!(run(&program(3)).0.reg_a, 9);
+    }
+
+    #[test]
+    fn ani_masks_with_an_immediate() {
+        let (state, _) = run("LDI 255\nANI 15\nHLT\n");
+        assert_eq!(state.reg_a, 15);
+        assert!(!state.flags.contains(ProgramFlags::ZERO));
+    }
+
+    #[test]
+    fn immediate_bitwise_ops_match_the_memory_forms() {
+        for (memory, immediate) in [("AND", "ANI"), ("OR", "ORI"), ("XOR", "XRI")] {
+            for (a, b) in [(0b1100, 0b1010), (5, 5), (-1, 0), (-8, 3)] {
+                let (from_ram, _) =
+                    run(&format!("LDI {}\nSTA 0\nLDI {}\n{} 0\nHLT\n", b, a, memory));
+                let (from_rom, _) = run(&format!("LDI {}\n{} {}\nHLT\n", a, immediate, b));
+                assert_eq!(from_rom.reg_a, from_ram.reg_a, "{} {} {}", a, immediate, b);
+                assert_eq!(from_rom.flags, from_ram.flags, "{} {} {}", a, immediate, b);
+            }
+        }
+    }
+
+    #[test]
+    fn not_and_neg_parse_bare() {
+        for (text, instruction) in [("NOT", Instruction::NOT), ("NEG", Instruction::NEG)] {
+            let word = RomLayout::from_str(text).unwrap();
+            assert_eq!(word.instruction, instruction);
+            assert_eq!(i64::from(word.value), 0);
+        }
+    }
+
+    #[test]
+    fn not_complements() {
+        let (state, _) = run("LDI 0\nNOT\nSTA 0\nNOT\nHLT\n");
+        assert_eq!((state.ram[0], state.reg_a), (-1, 0));
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+    }
+
+    #[test]
+    fn neg_edge_cases() {
+        let (state, _) = run("LDI 5\nNEG\nHLT\n");
+        assert_eq!(state.reg_a, -5);
+        assert_eq!(flags(&state), ProgramFlags::NEGATIVE);
+
+        let (state, _) = run("LDI 0\nNEG\nHLT\n");
+        assert_eq!(state.reg_a, 0);
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+
+        let (mut state, _) = machine(&compile("NEG\nHLT\n"));
+        state.reg_a = i64::MIN;
+        state.step().unwrap();
+        assert_eq!(state.reg_a, i64::MIN);
+        assert_eq!(
+            flags(&state),
+            ProgramFlags::CARRY | ProgramFlags::NEGATIVE | ProgramFlags::OVERFLOW
+        );
+    }
+
+    /// reg_a and the flags after `instruction` runs with reg_a = `a` and
+    /// the given flags.
+    fn after(instruction: &str, a: i64, before: ProgramFlags) -> (i64, ProgramFlags) {
+        let (mut state, _) = machine(&compile(&format!("{}\nHLT\n", instruction)));
+        state.reg_a = a;
+        state.flags = before;
+        state.step().unwrap();
+        (state.reg_a, flags(&state))
+    }
+
+    #[test]
+    fn shifts() {
+        use ProgramFlags as F;
+        let cases = [
+            ("SHL 1", 0b101, 0b1010, F::NONE),
+            ("SHL 1", i64::MIN, 0, F::CARRY | F::ZERO),
+            ("SHL 63", 1, i64::MIN, F::NEGATIVE),
+            ("SHR 1", 0b101, 0b10, F::CARRY),
+            ("SHR 1", -1, i64::MAX, F::CARRY),
+            ("SHR 63", i64::MIN, 1, F::NONE),
+            ("SHL 0", 0b101, 0b101, F::NONE),
+            ("SHR 0", -1, -1, F::NEGATIVE),
+            ("SHL 64", 1, 0, F::CARRY | F::ZERO),
+            ("SHR 64", i64::MIN, 0, F::CARRY | F::ZERO),
+            ("SHR 100", 0, 0, F::ZERO),
+        ];
+        for (instruction, a, result, expected) in cases {
+            assert_eq!(
+                after(instruction, a, F::CARRY),
+                (result, expected),
+                "{} of {:#x}",
+                instruction,
+                a
+            );
+        }
+    }
+
+    #[test]
+    fn rotates_go_through_carry() {
+        use ProgramFlags as F;
+        assert_eq!(after("ROL", i64::MIN, F::NONE), (0, F::CARRY | F::ZERO));
+        assert_eq!(after("ROL", 0, F::CARRY), (1, F::NONE));
+        assert_eq!(after("ROR", 1, F::NONE), (0, F::CARRY | F::ZERO));
+        assert_eq!(after("ROR", 0, F::CARRY), (i64::MIN, F::NEGATIVE));
+    }
+
+    #[test]
+    fn sixty_five_rotations_restore_the_register() {
+        for (instruction, carry) in [("ROL", false), ("ROL", true), ("ROR", false), ("ROR", true)] {
+            let source = format!("{}\n", instruction).repeat(65) + "HLT\n";
+            let (mut state, _) = machine(&compile(&source));
+            let value = 0x0123_4567_89AB_CDEF;
+            state.reg_a = value;
+            state.flags.set(ProgramFlags::CARRY, carry);
+            finish(&mut state).unwrap();
+            assert_eq!(state.reg_a, value, "{} with carry {}", instruction, carry);
+            assert_eq!(state.flags.contains(ProgramFlags::CARRY), carry);
+        }
+    }
+
+    #[test]
+    fn inc_and_dec_count_a_loop() {
+        let (state, _) = run("LDI 3\nSTA 0\nLDI 42\nINC 1\nDEC 0\nJZ 7\nJMP 3\nHLT\n");
+        assert_eq!(state.reg_a, 42);
+        assert_eq!(state.ram[0], 0);
+        assert_eq!(state.ram[1], 3);
+    }
+
+    #[test]
+    fn inc_and_dec_wrap_with_carry() {
+        let (mut state, _) = machine(&compile("INC 0\nDEC 1\nHLT\n"));
+        state.ram[0] = -1;
+        state.step().unwrap();
+        assert_eq!(state.ram[0], 0);
+        assert_eq!(flags(&state), ProgramFlags::CARRY | ProgramFlags::ZERO);
+        state.step().unwrap();
+        assert_eq!(state.ram[1], -1);
+        assert_eq!(flags(&state), ProgramFlags::CARRY | ProgramFlags::NEGATIVE);
+        assert_eq!(state.reg_a, 0);
+    }
+
+    #[test]
+    fn inc_out_of_ram_faults() {
+        let (mut state, _) = machine(&compile("NOP\nHLT\n"));
+        state.rom[0] = RomLayout::new(Instruction::INC, 256);
+        let error = state.step().unwrap_err();
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(256));
+    }
+
+    #[test]
+    fn cmp_keeps_reg_a() {
+        use ProgramFlags as F;
+        for (a, b, expected) in [
+            (5, 5, F::ZERO),
+            (3, 5, F::CARRY | F::NEGATIVE),
+            (7, 5, F::NONE),
+        ] {
+            let (cmp, _) = run(&format!("LDI {}\nSTA 0\nLDI {}\nCMP 0\nHLT\n", b, a));
+            let (sub, _) = run(&format!("LDI {}\nSTA 0\nLDI {}\nSUB 0\nHLT\n", b, a));
+            assert_eq!(cmp.reg_a, a);
+            assert_eq!(cmp.ram[0], b);
+            assert_eq!(flags(&cmp), expected, "{} vs {}", a, b);
+            assert_eq!(cmp.flags, sub.flags);
+        }
+    }
+
+    #[test]
+    fn cpi_sets_the_flags_cmp_does() {
+        for (a, b) in [(5, 5), (3, 5), (9, 5), (-2, -2), (-3, -2)] {
+            let (cpi, _) = run(&format!("LDI {}\nCPI {}\nHLT\n", a, b));
+            let (cmp, _) = run(&format!("LDI {}\nSTA 0\nLDI {}\nCMP 0\nHLT\n", b, a));
+            assert_eq!(cpi.reg_a, a);
+            assert_eq!(cpi.flags, cmp.flags, "{} vs {}", a, b);
+            assert_eq!(cpi.flags.contains(ProgramFlags::ZERO), a == b);
+        }
+    }
+
+    #[test]
+    fn cpi_takes_negative_immediates() {
+        let word = RomLayout::from_str("CPI -5").unwrap();
+        assert_eq!(i64::from(word.value), -5);
+        let word = RomLayout::from_str(&format!("CPI {}", -(1i64 << 55))).unwrap();
+        assert_eq!(i64::from(word.value), -(1 << 55));
+    }
+
+    #[test]
+    fn jnz_exits_a_countdown() {
+        let (state, _) = run("LDI 3\nINC 0\nSUI 1\nJNZ 1\nHLT\n");
+        assert_eq!(state.ram[0], 3);
+        assert_eq!(state.reg_a, 0);
+    }
+
+    #[test]
+    fn jnc_jumps_without_carry() {
+        for (a, jumped) in [(5, true), (-1, false)] {
+            let (mut state, _) = machine(&compile("ADI 1\nJNC 3\nHLT\nINC 0\nHLT\n"));
+            state.reg_a = a;
+            finish(&mut state).unwrap();
+            assert_eq!(state.ram[0] == 1, jumped, "{}", a);
+        }
+    }
+
+    #[test]
+    fn negative_flag_around_zero() {
+        let program = |a, b| format!("LDI {}\nADI {}\nJN 4\nJP 6\nLDI 1\nHLT\nLDI 2\nHLT\n", a, b);
+        assert_eq!(run(&program(0, -1)).0.reg_a, 1);
+        assert_eq!(run(&program(-1, 1)).0.reg_a, 2);
+        assert_eq!(run(&program(-1, 2)).0.reg_a, 2);
+        assert_eq!(run(&program(1, -2)).0.reg_a, 1);
+    }
+
+    #[test]
+    fn negative_flag_around_i64_min_and_max() {
+        use ProgramFlags as F;
+        assert_eq!(
+            after("ADI 1", i64::MAX, F::NONE),
+            (i64::MIN, F::NEGATIVE | F::OVERFLOW)
+        );
+        assert_eq!(after("SUI 1", i64::MIN, F::NONE), (i64::MAX, F::OVERFLOW));
+        assert_eq!(after("ADI 0", i64::MIN, F::NONE), (i64::MIN, F::NEGATIVE));
+        assert_eq!(after("ADI 0", i64::MAX, F::NEGATIVE), (i64::MAX, F::NONE));
+        assert_eq!(after("CPI 0", i64::MIN, F::NONE), (i64::MIN, F::NEGATIVE));
+    }
+
+    #[test]
+    fn b_register_loads_stores_and_transfers() {
+        let (state, _) = run("LDI 7\nSTA 0\nLDI 0\nLDB 0\nSTB 1\nTBA\nHLT\n");
+        assert_eq!((state.reg_a, state.reg_b, state.ram[1]), (7, 7, 7));
+
+        let (state, _) = run("LDI -5\nTAB\nLDI 0\nHLT\n");
+        assert_eq!((state.reg_a, state.reg_b), (0, -5));
+    }
+
+    #[test]
+    fn adb_adds_b_to_a_like_add() {
+        let (adb, _) = run("LDI 3\nTAB\nLDI -3\nADB\nHLT\n");
+        let (add, _) = run("LDI 3\nSTA 0\nLDI -3\nADD 0\nHLT\n");
+        assert_eq!((adb.reg_a, adb.reg_b), (0, 3));
+        assert_eq!(adb.flags, add.flags);
+    }
+
+    #[test]
+    fn push_and_pop_are_lifo() {
+        let (state, _) = run(concat!(
+            "LDI 1\n", "PUSH\n", "LDI 2\n", "PUSH\n", "LDI 3\n", "PUSH\n", "POP\n", "STA 0\n",
+            "POP\n", "STA 1\n", "POP\n", "HLT\n",
+        ));
+        assert_eq!((state.ram[0], state.ram[1], state.reg_a), (3, 2, 1));
+        assert_eq!(state.stack_pointer, STACK_TOP);
+    }
+
+    #[test]
+    fn pop_from_an_empty_stack_underflows() {
+        let error = fault("LDI 1\nPUSH\nPOP\nPOP\nHLT\n");
+        assert_eq!(error.fault, RuntimeFault::StackUnderflow);
+        assert_eq!(error.program_counter, 3);
+    }
+
+    #[test]
+    fn push_onto_a_full_stack_overflows() {
+        let error = fault("PUSH\nJMP 0\n");
+        assert_eq!(error.fault, RuntimeFault::StackOverflow);
+    }
+
+    #[test]
+    fn calls_nest_two_deep() {
+        // Each step appends a digit to ram[0], to show the order they ran in.
+        let step = |digit| format!("LDA 0\nMLI 10\nADI {}\nSTA 0\n", digit);
+        let source = [
+            "CALL 6\n".to_string(),
+            step(4),
+            "HLT\n".to_string(),
+            step(1),
+            "CALL 16\n".to_string(),
+            step(3),
+            "RET\n".to_string(),
+            step(2),
+            "RET\n".to_string(),
+        ]
+        .concat();
+        let (state, _) = run(&source);
+        assert_eq!(state.ram[0], 1234);
+        assert_eq!(state.stack_pointer, STACK_TOP);
+    }
+
+    #[test]
+    fn ret_with_an_empty_stack_underflows() {
+        let error = fault("RET\n");
+        assert_eq!(error.fault, RuntimeFault::StackUnderflow);
+        assert_eq!(error.instruction, Instruction::RET);
+    }
+
+    #[test]
+    fn ldp_sums_an_array_through_a_pointer() {
+        let (mut state, _) = machine(&compile(
+            "LDI 0\nSTA 2\nLDP 0\nADD 2\nINC 0\nDEC 1\nJNZ 1\nHLT\n",
+        ));
+        for (cell, value) in (10..20).zip(1..) {
+            state.ram[cell] = value;
+        }
+        state.ram[0] = 10;
+        state.ram[1] = 10;
+        finish(&mut state).unwrap();
+        assert_eq!(state.reg_a, 55);
+    }
+
+    #[test]
+    fn stp_stores_through_a_pointer() {
+        let (mut state, _) = machine(&compile("LDI 7\nSTP 0\nHLT\n"));
+        state.ram[0] = 200;
+        finish(&mut state).unwrap();
+        assert_eq!(state.ram[200], 7);
+
+        let (mut state, _) = machine(&compile("LDP 0\nHLT\n"));
+        state.ram[0] = -1;
+        let error = state.step().unwrap_err();
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(-1));
+    }
+
+    #[test]
+    fn memcpy_with_the_index_register() {
+        let (mut state, _) = machine(&compile("LDX 0\nLAX 16\nSAX 64\nINX\nDEC 0\nJNZ 1\nHLT\n"));
+        state.ram[0] = 16;
+        for (cell, value) in state.ram[16..32].iter_mut().zip(100..) {
+            *cell = value;
+        }
+        finish(&mut state).unwrap();
+        assert_eq!(state.ram[64..80], state.ram[16..32]);
+        assert_eq!(state.ram[80], 0);
+        assert_eq!(state.reg_x, 16);
+    }
+
+    #[test]
+    fn indexed_access_past_ram_faults() {
+        let error = fault("LDX 10\nLAX 250\nHLT\n");
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(260));
+    }
+
+    #[test]
+    fn in_reads_successive_values_and_skips_malformed_ones() {
+        let (mut state, _) = machine(&compile("IN\nSTA 0\nIN\nSTA 1\nIN\nHLT\n"));
+        // Only what's queued is read, so the test never waits on stdin.
+        state.input = ["12", "abc", "4x", "-7", "-3"].map(str::to_string).into();
+        finish(&mut state).unwrap();
+        assert_eq!((state.ram[0], state.ram[1], state.reg_a), (12, -7, -3));
+        assert!(state.flags.contains(ProgramFlags::NEGATIVE));
+        assert!(state.input.is_empty());
+    }
+
+    #[test]
+    fn hello_world_writes_its_text_with_outc() {
+        let rom = compile(include_str!("../examples/hello.ebr")).rom;
+        let bytes: Vec<u8> = rom
+            .windows(2)
+            .filter(|pair| pair[1].instruction == Instruction::OUTC)
+            .map(|pair| i64::from(pair[0].value) as u8)
+            .collect();
+        assert_eq!(bytes, b"Hello, world!\n");
+        assert_eq!(rom.last().unwrap().instruction, Instruction::HLT);
+    }
+
+    #[test]
+    fn outh_and_outb_leave_the_machine_alone() {
+        let (state, _) = run("LDI -1\nOUTH\nOUTB\nHLT\n");
+        assert_eq!(state.reg_a, -1);
+        assert_eq!(flags(&state), ProgramFlags::NEGATIVE);
+        assert_eq!(state.program_counter, 3);
+    }
+
+    #[test]
+    fn outs_past_ram_faults() {
+        let (mut state, _) = machine(&compile("OUTS 0\nHLT\n"));
+        state.rom[0] = RomLayout::new(Instruction::OUTS, 256);
+        let error = state.step().unwrap_err();
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(256));
+        assert_eq!(error.instruction, Instruction::OUTS);
+    }
+
+    #[test]
+    fn jr_is_relative_to_itself() {
+        let (state, _) = run("JR 3\nLDI 1\nHLT\nLDI 2\nHLT\n");
+        assert_eq!(state.reg_a, 2);
+    }
+
+    #[test]
+    fn jr_loops_backwards() {
+        let (state, _) = run("LDI 3\nINC 0\nSUI 1\nJZ 5\nJR -3\nHLT\n");
+        assert_eq!(state.ram[0], 3);
+        assert_eq!(state.reg_a, 0);
+    }
+
+    #[test]
+    fn jr_out_of_rom_faults() {
+        // The assembler rejects this, so it's patched in.
+        let (mut state, _) = machine(&compile("NOP\nNOP\nHLT\n"));
+        state.rom[1] = RomLayout::new(Instruction::JR, -2);
+        state.step().unwrap();
+        let error = state.step().unwrap_err();
+        assert_eq!(error.fault, RuntimeFault::JumpOutOfRange(-1));
+        assert_eq!(error.program_counter, 1);
+    }
+
+    #[test]
+    fn hlt_sets_the_exit_code() {
+        assert_eq!(run("HLT\n").0.exit_code, 0);
+        assert_eq!(run("HLT 3\n").0.exit_code, 3);
+        assert_eq!(run("HLT 255\n").0.exit_code, 255);
+        // Exit statuses are a byte, so the rest is clamped.
+        assert_eq!(run("HLT 300\n").0.exit_code, 255);
+        assert_eq!(run("HLT -5\n").0.exit_code, 0);
+    }
+
+    fn random_outputs(seed: u64) -> String {
+        let (state, stdout) = machine(&compile(&("RND\nOUT\n".repeat(8) + "HLT\n")));
+        let mut state = state.seeded(seed);
+        finish(&mut state).unwrap();
+        stdout.text()
+    }
+
+    #[test]
+    fn rnd_is_reproducible_with_a_seed() {
+        assert_eq!(random_outputs(7), random_outputs(7));
+        assert_eq!(random_outputs(0), random_outputs(0));
+        assert_ne!(random_outputs(7), random_outputs(8));
+        let outputs = random_outputs(7);
+        let values: HashSet<&str> = outputs.lines().collect();
+        assert_eq!(values.len(), 8);
+    }
+
+    #[test]
+    fn jc_after_sec_and_clc() {
+        let program = |set| format!("{}\nJC 3\nHLT 1\nHLT 2\n", set);
+        assert_eq!(run(&program("SEC")).0.exit_code, 2);
+        assert_eq!(run(&program("CLC")).0.exit_code, 1);
+    }
+
+    #[test]
+    fn flag_instructions_touch_only_their_flag() {
+        use ProgramFlags as F;
+        let all = F::CARRY | F::ZERO | F::NEGATIVE;
+        assert_eq!(after("CLC", 5, all), (5, F::ZERO | F::NEGATIVE));
+        assert_eq!(after("SEC", 5, F::NONE), (5, F::CARRY));
+        assert_eq!(after("CLZ", 5, all), (5, F::CARRY | F::NEGATIVE));
+        let (state, _) = run("SEC\nCLZ\nCLC\nHLT\n");
+        assert_eq!(flags(&state), F::NONE);
+    }
+
+    #[test]
+    fn jma_dispatches_through_a_table() {
+        // ram[10..13] holds the addresses of the three HLTs at the end.
+        let program = |index| {
+            format!(
+                concat!(
+                    "LDI 11\n", "STA 10\n", "LDI 12\n", "STA 11\n", "LDI 13\n", "STA 12\n",
+                    "LDI {}\n", "ADI 10\n", "STA 0\n", "LDP 0\n", "JMA\n", "HLT 1\n", "HLT 2\n",
+                    "HLT 3\n",
+                ),
+                index
+            )
+        };
+        for index in 0..3 {
+            assert_eq!(run(&program(index)).0.exit_code, index as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn jma_out_of_rom_faults() {
+        let error = fault("LDI 300\nJMA\n");
+        assert_eq!(error.fault, RuntimeFault::JumpOutOfRange(300));
+        assert_eq!(
+            error.to_string(),
+            "runtime error at pc 1 (JMA): jump target 300 is out of range 0..=255"
+        );
+    }
+
+    #[test]
+    fn brk_is_a_nop_that_the_run_loop_can_see() {
+        let source = |pause| format!("LDI 5\n{}\nSTA 0\nHLT 3\n", pause);
+        let (nop, _) = run(&source("NOP"));
+        let (brk, _) = run(&source("BRK"));
+        assert_eq!(
+            (brk.describe(), brk.ram, brk.exit_code),
+            (nop.describe(), nop.ram, nop.exit_code)
+        );
+
+        let (mut state, _) = machine(&compile(&source("BRK")));
+        state.step().unwrap();
+        let before = (state.reg_a, state.flags, state.ram);
+        assert_eq!(state.step().unwrap(), Instruction::BRK);
+        assert_eq!((state.reg_a, state.flags, state.ram), before);
+        assert_eq!(state.program_counter, 2);
+    }
+
+    #[test]
+    fn describe_shows_every_register() {
+        let (state, _) = run("LDI 9\nTAB\nLDX 4\nLDI 2\nPUSH\nHLT\n");
+        assert_eq!(state.describe(), "pc=5 a=2 b=9 x=4 sp=254 flags=[]");
+    }
+
+    #[test]
+    fn dbg_line_format() {
+        let (mut state, _) = machine(&compile("LDI -3\nSTA 4\nDBG 4\nDBG\nHLT\n"));
+        state.step().unwrap();
+        state.step().unwrap();
+        assert_eq!(
+            state.debug_line(4),
+            "DBG pc=2 a=-3 b=0 x=0 sp=255 flags=[NEGATIVE] ram[4]=-3"
+        );
+        assert_eq!(
+            state.debug_line(-1),
+            "DBG pc=2 a=-3 b=0 x=0 sp=255 flags=[NEGATIVE]"
+        );
+        let before = state.flags;
+        state.dbg = true;
+        finish(&mut state).unwrap();
+        assert_eq!(state.flags, before);
+    }
+
+    #[test]
+    fn describe_and_dbg_show_b() {
+        let (state, _) = run("LDI 9\nTAB\nLDI 2\nSTA 0\nHLT\n");
+        assert_eq!(
+            state.debug_line(0),
+            "DBG pc=4 a=2 b=9 x=0 sp=255 flags=[] ram[0]=2"
+        );
+    }
+
+    #[test]
+    fn xchg_swaps_two_cells() {
+        let (mut state, _) = machine(&compile("LDA 0\nXCHG 1\nSTA 0\nHLT\n"));
+        state.ram[..2].copy_from_slice(&[1, 2]);
+        finish(&mut state).unwrap();
+        assert_eq!(state.ram[..2], [2, 1]);
+    }
+
+    #[test]
+    fn xchg_leaves_the_flags() {
+        use ProgramFlags as F;
+        assert_eq!(
+            after("XCHG 0", 5, F::CARRY | F::ZERO),
+            (0, F::CARRY | F::ZERO)
+        );
+    }
+
+    #[test]
+    fn bubblesort_example() {
+        let (state, _) = run(include_str!("../examples/bubblesort.ebr"));
+        assert_eq!(state.ram[10..15], [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn abs() {
+        use ProgramFlags as F;
+        assert_eq!(after("ABS", 7, F::NONE), (7, F::NONE));
+        assert_eq!(after("ABS", -7, F::NONE), (7, F::NONE));
+        assert_eq!(after("ABS", 0, F::CARRY), (0, F::ZERO));
+        assert_eq!(
+            after("ABS", i64::MIN, F::NONE),
+            (i64::MIN, F::CARRY | F::NEGATIVE | F::OVERFLOW)
+        );
+        assert_eq!(
+            RomLayout::from_str("ABS").unwrap().instruction,
+            Instruction::ABS
+        );
+    }
+
+    #[test]
+    fn bit_probes_without_changing_anything() {
+        for (cell, set) in [(0b0100, true), (0b1011, false)] {
+            let (mut state, _) = machine(&compile("BIT 0\nHLT\n"));
+            state.reg_a = 0b0100;
+            state.ram[0] = cell;
+            state.step().unwrap();
+            assert_eq!(state.flags.contains(ProgramFlags::ZERO), !set);
+            assert_eq!(state.reg_a, 0b0100);
+            assert_eq!(state.ram[0], cell);
+        }
+    }
+
+    #[test]
+    fn tfa_after_add_is_stable() {
+        // CARRY is bit 0, ZERO bit 1, NEGATIVE bit 2 and OVERFLOW bit 3.
+        let (state, _) = run("LDI 1\nSTA 0\nLDI -1\nADD 0\nTFA\nSTA 1\nLDI 2\nADD 0\nTFA\nHLT\n");
+        assert_eq!((state.ram[1], state.reg_a), (3, 0));
+        let (mut state, _) = machine(&compile("ADD 0\nTFA\nHLT\n"));
+        state.reg_a = i64::MAX;
+        state.ram[0] = 1;
+        finish(&mut state).unwrap();
+        assert_eq!(state.reg_a, 12);
+    }
+
+    #[test]
+    fn taf_restores_flags_but_not_jump() {
+        let (state, _) = run("LDI 31\nTAF\nHLT\n");
+        assert_eq!(
+            state.flags,
+            ProgramFlags::CARRY
+                | ProgramFlags::ZERO
+                | ProgramFlags::NEGATIVE
+                | ProgramFlags::OVERFLOW
+        );
+        assert_eq!(state.program_counter, 2);
+    }
+
+    #[test]
+    fn outc_prints_hello_world() {
+        let mut source = String::new();
+        for byte in "Hello, world!\n".bytes() {
+            source += &format!("LDI {}\nOUTC\n", byte);
+        }
+        source += "HLT\n";
+        assert_eq!(run(&source).1, "Hello, world!\n");
+        assert_eq!(
+            run(include_str!("../examples/hello.ebr")).1,
+            "Hello, world!\n"
+        );
+    }
+
+    #[test]
+    fn outc_writes_only_the_low_byte() {
+        assert_eq!(run("LDI 321\nOUTC\nHLT\n").1, "A");
+    }
+
+    #[test]
+    fn outh_and_outb() {
+        let (_, output) = run("LDI 255\nOUTH\nOUTB\nLDI 0\nOUTH\nOUTB\nHLT\n");
+        assert_eq!(output, "0xff\n0b11111111\n0x0\n0b0\n");
+        let (_, output) = run("LDI -1\nOUTH\nLDI -2\nOUTB\nHLT\n");
+        assert_eq!(
+            output,
+            format!("0xffffffffffffffff\n0b{}0\n", "1".repeat(63))
+        );
+    }
+
+    #[test]
+    fn outs_prints_up_to_the_terminator() {
+        let (mut state, stdout) = machine(&compile("OUTS 10\nHLT\n"));
+        for (cell, byte) in state.ram[10..].iter_mut().zip(b"hi\0there") {
+            *cell = i64::from(*byte);
+        }
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "hi");
+    }
+
+    #[test]
+    fn outs_without_a_terminator_stops_at_the_end_of_ram() {
+        let (mut state, stdout) = machine(&compile("OUTS 252\nHLT\n"));
+        state.ram[0] = i64::from(b'x');
+        for (cell, byte) in state.ram[252..].iter_mut().zip(b"abcd") {
+            *cell = i64::from(*byte);
+        }
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "abcd");
+    }
+
+    #[test]
+    fn ports_interleave() {
+        let (mut state, stdout) = machine(&compile(
+            "LDI 1\nOUT\nLDI 2\nOUT 2\nLDI 3\nOUT 0\nLDI 4\nOUT 2\nHLT\n",
+        ));
+        let results = Capture::default();
+        state.ports.insert(2, Box::new(results.clone()));
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "1\n3\n");
+        assert_eq!(results.text(), "2\n4\n");
+    }
+
+    #[test]
+    fn unmapped_port_faults() {
+        let error = fault("LDI 1\nOUT 3\nHLT\n");
+        assert_eq!(error.fault, RuntimeFault::UnmappedPort(3));
+        assert_eq!(
+            error.to_string(),
+            "runtime error at pc 1 (OUT): output port 3 is not mapped"
+        );
+    }
+
+    #[test]
+    fn slp_is_a_nop_without_sleep() {
+        let started = std::time::Instant::now();
+        let (mut state, _) = machine(&compile("SLP 10000\nSLP 10000\nHLT\n"));
+        assert!(!state.sleep);
+        let before = (state.reg_a, state.flags);
+        finish(&mut state).unwrap();
+        assert_eq!((state.reg_a, state.flags), before);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn slp_range_is_checked_when_assembling() {
+        assert!("SLP 10000".parse::<RomLayout>().is_ok());
+        assert!("SLP 10001".parse::<RomLayout>().is_err());
+        assert!("SLP -1".parse::<RomLayout>().is_err());
+    }
+
+    #[test]
+    fn slp_out_of_range_faults_instead_of_sleeping() {
+        // The assembler rejects these, so they're patched in.
+        for ms in [-1, MAX_SLEEP_MS + 1, IMMEDIATE_MAX] {
+            let (mut state, _) = machine(&compile("NOP\nHLT\n"));
+            state.sleep = true;
+            state.rom[0] = RomLayout::new(Instruction::SLP, ms);
+            let error = state.step().unwrap_err();
+            assert_eq!(error.fault, RuntimeFault::SleepOutOfRange(ms));
+        }
+    }
+
+    /// RAM 10..18 after `source` runs with 1..=8 in those cells.
+    fn copied(source: &str) -> Vec<i64> {
+        let (mut state, _) = machine(&compile(source));
+        for (cell, value) in state.ram[10..18].iter_mut().zip(1..) {
+            *cell = value;
+        }
+        finish(&mut state).unwrap();
+        state.ram[10..18].to_vec()
+    }
+
+    #[test]
+    fn mcp_copies_overlapping_regions_like_memmove() {
+        // Forwards, onto the end of the source.
+        assert_eq!(copied("MCP 10 12 4\nHLT\n"), [1, 2, 1, 2, 3, 4, 7, 8]);
+        // Backwards, onto its start.
+        assert_eq!(copied("MCP 12 10 4\nHLT\n"), [3, 4, 5, 6, 5, 6, 7, 8]);
+        assert_eq!(copied("MCP 10 14 0\nHLT\n"), [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn mcp_past_ram_faults() {
+        let error = fault("MCP 250 0 10\nHLT\n");
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(259));
+        let error = fault("MCP 0 250 10\nHLT\n");
+        assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(259));
+    }
+
+    #[test]
+    fn asr_keeps_the_sign() {
+        use ProgramFlags as F;
+        let cases = [
+            ("ASR 1", -8, -4, F::NEGATIVE),
+            ("ASR 1", -7, -4, F::CARRY | F::NEGATIVE),
+            ("ASR 2", 13, 3, F::NONE),
+            ("ASR 63", i64::MIN, -1, F::NEGATIVE),
+            ("ASR 0", -8, -8, F::NEGATIVE),
+            ("ASR 64", -8, -1, F::CARRY | F::NEGATIVE),
+            ("ASR 64", 8, 0, F::ZERO),
+            ("ASR 100", i64::MAX, 0, F::ZERO),
+        ];
+        for (instruction, a, result, expected) in cases {
+            assert_eq!(
+                after(instruction, a, F::CARRY),
+                (result, expected),
+                "{} of {}",
+                instruction,
+                a
+            );
+        }
+        // SHR of the same negative value fills with zeros instead.
+        assert_eq!(after("SHR 1", -8, F::NONE).0, (-8i64 as u64 >> 1) as i64);
+    }
+
+    #[test]
+    fn jsr_returns_to_each_caller() {
+        let (state, output) = run("LDI 1\nJSR 5\nLDI 2\nJSR 5\nHLT\nOUT\nRSR\n");
+        assert_eq!(output, "1\n2\n");
+        assert_eq!(state.ram[LINK_CELL], 4);
+    }
+
+    #[test]
+    fn rsr_checks_the_return_address() {
+        let error = fault("LDI 256\nSTA 255\nRSR\n");
+        assert_eq!(error.fault, RuntimeFault::JumpOutOfRange(256));
+    }
+
+    #[test]
+    fn str_rewrites_an_ldi_operand() {
+        let (state, output) = run("LDI 1\nOUT\nADI 1\nSTR 0\nCPI 4\nJNZ 0\nHLT\n");
+        assert_eq!(output, "1\n2\n3\n");
+        assert_eq!(state.rom[0].instruction, Instruction::LDI);
+        assert_eq!(i64::from(state.rom[0].value), 4);
+    }
+
+    #[test]
+    fn str_checks_the_value_fits() {
+        let (mut state, _) = machine(&compile("STR 1\nHLT\n"));
+        state.reg_a = IMMEDIATE_MAX + 1;
+        let error = state.step().unwrap_err();
+        assert_eq!(
+            error.fault,
+            RuntimeFault::ValueOutOfRange(IMMEDIATE_MAX + 1)
+        );
+    }
+
+    #[test]
+    fn stri_needs_self_modify_and_a_valid_opcode() {
+        let word = |instruction, value| {
+            i64::from_be_bytes(RomLayout::new(instruction, value).pack().unwrap())
+        };
+        let source = "LDI 0\nSTRI 2\nHLT\n";
+        let (mut state, _) = machine(&compile(source));
+        state.step().unwrap();
+        state.reg_a = word(Instruction::HLT, 9);
+        assert_eq!(
+            state.step().unwrap_err().fault,
+            RuntimeFault::SelfModifyDisabled
+        );
+
+        let (mut state, _) = machine(&compile(source));
+        state.allow_self_modify = true;
+        state.step().unwrap();
+        state.reg_a = word(Instruction::HLT, 9);
+        finish(&mut state).unwrap();
+        assert_eq!(state.exit_code, 9);
+
+        let (mut state, _) = machine(&compile(source));
+        state.allow_self_modify = true;
+        state.step().unwrap();
+        state.reg_a = -1;
+        assert_eq!(
+            state.step().unwrap_err().fault,
+            RuntimeFault::InvalidOpcode(0xFF)
+        );
+    }
+
+    #[test]
+    fn mov_copies_a_cell_and_leaves_a_and_the_flags() {
+        let (state, _) = run("LDI -6\nSTA 3\nLDI 0\nMOV 3 200\nHLT\n");
+        assert_eq!((state.ram[3], state.ram[200]), (-6, -6));
+        assert_eq!(state.reg_a, 0);
+        assert_eq!(flags(&state), ProgramFlags::ZERO);
+    }
+
+    #[test]
+    fn mov_addresses_are_checked_when_assembling() {
+        for source in ["MOV 256 0", "MOV 0 256", "MOV -1 0"] {
+            assert!(source.parse::<RomLayout>().is_err(), "{}", source);
+        }
+        assert!("MOV 0 255".parse::<RomLayout>().is_ok());
+    }
+
+    #[test]
+    fn adc_chains_the_carry() {
+        use ProgramFlags as F;
+        let cases = [
+            // Only the carry in carries out.
+            (-1, 0, F::CARRY, 0, F::CARRY | F::ZERO),
+            // The largest sum there is, which still only carries once.
+            (-1, -1, F::CARRY, -1, F::CARRY | F::NEGATIVE),
+            (-1, -1, F::NONE, -2, F::CARRY | F::NEGATIVE),
+            (1, 2, F::CARRY, 4, F::NONE),
+            (i64::MAX, 0, F::CARRY, i64::MIN, F::NEGATIVE | F::OVERFLOW),
+            (i64::MIN, -1, F::CARRY, i64::MIN, F::CARRY | F::NEGATIVE),
+        ];
+        for (a, b, carry, result, expected) in cases {
+            let (mut state, _) = machine(&compile("ADC 0\nHLT\n"));
+            state.reg_a = a;
+            state.ram[0] = b;
+            state.flags = carry;
+            state.step().unwrap();
+            assert_eq!(
+                (state.reg_a, flags(&state)),
+                (result, expected),
+                "{} + {} + {:?}",
+                a,
+                b,
+                carry
+            );
+        }
+    }
+
+    #[test]
+    fn sbc_chains_the_borrow() {
+        use ProgramFlags as F;
+        let cases = [
+            // Only the borrow in borrows.
+            (0, 0, F::CARRY, -1, F::CARRY | F::NEGATIVE),
+            // The largest borrow there is, which still only borrows once.
+            (0, -1, F::CARRY, 0, F::CARRY | F::ZERO),
+            (0, -1, F::NONE, 1, F::CARRY),
+            (5, 2, F::CARRY, 2, F::NONE),
+            (i64::MIN, 0, F::CARRY, i64::MAX, F::OVERFLOW),
+        ];
+        for (a, b, borrow, result, expected) in cases {
+            let (mut state, _) = machine(&compile("SBC 0\nHLT\n"));
+            state.reg_a = a;
+            state.ram[0] = b;
+            state.flags = borrow;
+            state.step().unwrap();
+            assert_eq!(
+                (state.reg_a, flags(&state)),
+                (result, expected),
+                "{} - {} - {:?}",
+                a,
+                b,
+                borrow
+            );
+        }
+    }
+
+    #[test]
+    fn djnz_loops_exactly_n_times() {
+        for n in [1, 2, 5] {
+            let (state, output) = run(&format!(
+                "LDI {}\nSTA 0\nLDI 7\nINC 1\nDJNZ 0 3\nOUT\nHLT\n",
+                n
+            ));
+            assert_eq!(state.ram[1], n);
+            assert_eq!(state.ram[0], 0);
+            assert_eq!(output, "7\n");
+        }
+    }
+
+    #[test]
+    fn sti_stores_without_touching_reg_a() {
+        let (state, _) = run("LDI -1\nSTI 9 -123456\nSTI 255 2147483647\nHLT\n");
+        assert_eq!(state.ram[9], -123456);
+        assert_eq!(state.ram[255], i32::MAX as i64);
+        assert_eq!((state.reg_a, flags(&state)), (-1, ProgramFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn sti_is_one_word_where_ldi_sta_is_two() {
+        let before = compile("LDI 5\nSTA 0\nLDI 6\nSTA 1\nHLT\n");
+        let after = compile("STI 0 5\nSTI 1 6\nHLT\n");
+        assert_eq!(before.rom.len(), 5);
+        assert_eq!(after.rom.len(), 3);
+        assert_eq!(run("STI 0 5\nSTI 1 6\nHLT\n").0.ram[..2], [5, 6]);
+    }
+
+    #[test]
+    fn sti_fields_are_checked_when_assembling() {
+        assert!("STI 256 1".parse::<RomLayout>().is_err());
+        assert!("STI 0 2147483648".parse::<RomLayout>().is_err());
+        assert!("STI 0 -2147483648".parse::<RomLayout>().is_ok());
+    }
+
+    #[test]
+    fn smb_and_rmb_toggle_single_bits() {
+        for bit in [0, 31, 63] {
+            let (mut state, _) = machine(&compile(&format!("SMB 0 {0}\nRMB 1 {0}\nHLT\n", bit)));
+            state.ram[0] = 0x5555_5555_5555_5555 & !(1 << bit);
+            state.ram[1] = -1;
+            let before = state.ram[0];
+            finish(&mut state).unwrap();
+            assert_eq!(state.ram[0], before | 1 << bit, "SMB {}", bit);
+            assert_eq!(state.ram[1], !(1 << bit), "RMB {}", bit);
+            assert_eq!(state.reg_a, 0);
+        }
+    }
+
+    #[test]
+    fn smb_bit_index_is_checked_when_assembling() {
+        assert!("SMB 0 64".parse::<RomLayout>().is_err());
+        assert!("RMB 0 -1".parse::<RomLayout>().is_err());
+        assert!("RMB 255 63".parse::<RomLayout>().is_ok());
+    }
+
+    /// LDI and LDH that load `value`: the low 56 bits, sign extended, then
+    /// the top byte.
+    fn ldi_ldh(value: i64) -> String {
+        let low = value << 8 >> 8;
+        format!("LDI {}\nLDH {}\nHLT\n", low, (value as u64) >> 56)
+    }
+
+    #[test]
+    fn ldh_materializes_full_64_bit_constants() {
+        for value in [
+            i64::MIN,
+            i64::MAX,
+            0xDEAD_BEEF_CAFE_F00Du64 as i64,
+            -1,
+            0x0100_0000_0000_0000,
+        ] {
+            let (state, _) = run(&ldi_ldh(value));
+            assert_eq!(state.reg_a, value, "{:#x}", value);
+            assert_eq!(state.flags.contains(ProgramFlags::NEGATIVE), value < 0);
+        }
+    }
+
+    #[test]
+    fn ldh_takes_a_byte() {
+        assert!("LDH 256".parse::<RomLayout>().is_err());
+        assert!("LDH -1".parse::<RomLayout>().is_err());
+    }
+
+    #[test]
+    fn carry_and_overflow_matrix() {
+        use ProgramFlags as F;
+        let cases = [
+            (i64::MAX, 1, F::NEGATIVE | F::OVERFLOW),
+            (i64::MAX, -1, F::CARRY),
+            (i64::MIN, -1, F::CARRY | F::OVERFLOW),
+            (i64::MIN, 1, F::NEGATIVE),
+            (i64::MIN, i64::MIN, F::CARRY | F::ZERO | F::OVERFLOW),
+            (i64::MAX, i64::MAX, F::NEGATIVE | F::OVERFLOW),
+            (i64::MAX, i64::MIN, F::NEGATIVE),
+            (-1, 1, F::CARRY | F::ZERO),
+            (-1, -1, F::CARRY | F::NEGATIVE),
+            (5, -3, F::CARRY),
+            (3, -5, F::NEGATIVE),
+            (2, 3, F::NONE),
+        ];
+        for (a, b, expected) in cases {
+            let (mut state, _) = machine(&compile("ADD 0\nHLT\n"));
+            state.reg_a = a;
+            state.ram[0] = b;
+            state.step().unwrap();
+            assert_eq!(state.reg_a, a.wrapping_add(b));
+            assert_eq!(flags(&state), expected, "{} + {}", a, b);
+        }
+    }
+
+    #[test]
+    fn jo_and_jno() {
+        for (jump, a, exit_code) in [
+            ("JO", i64::MAX, 2),
+            ("JO", 0, 1),
+            ("JNO", i64::MAX, 1),
+            ("JNO", 0, 2),
+        ] {
+            let (mut state, _) = machine(&compile(&format!("ADI 1\n{} 3\nHLT 1\nHLT 2\n", jump)));
+            state.reg_a = a;
+            finish(&mut state).unwrap();
+            assert_eq!(state.exit_code, exit_code, "{} after {} + 1", jump, a);
+        }
+    }
+
+    /// Runs a 128-bit `instruction` (ADC or SBC) of `a` and `b` through RAM,
+    /// each split into a low and a high word.
+    fn wide(instruction: &str, a: i128, b: i128) -> i128 {
+        let (mut state, _) = machine(&compile(&format!(
+            "CLC\nLDA 0\n{0} 2\nSTA 4\nLDA 1\n{0} 3\nSTA 5\nHLT\n",
+            instruction
+        )));
+        state.ram[..4].copy_from_slice(&[a as i64, (a >> 64) as i64, b as i64, (b >> 64) as i64]);
+        finish(&mut state).unwrap();
+        (state.ram[5] as i128) << 64 | state.ram[4] as u64 as i128
+    }
+
+    #[test]
+    fn adc_adds_128_bit_numbers() {
+        let cases = [
+            (u64::MAX as i128, 1),
+            (
+                0x1234_5678_9ABC_DEF0_0FED_CBA9_8765_4321,
+                0x1111_1111_1111_1111_FFFF_FFFF_FFFF_FFFF,
+            ),
+            (-1, 1),
+            (-5, -7),
+            (i128::MAX, 0),
+        ];
+        for (a, b) in cases {
+            assert_eq!(wide("ADC", a, b), a.wrapping_add(b), "{:#x} + {:#x}", a, b);
+        }
+    }
+
+    #[test]
+    fn sbc_subtracts_128_bit_numbers() {
+        let cases = [
+            (1 << 64, 1),
+            (0, 1),
+            (
+                0x1234_5678_9ABC_DEF0_0FED_CBA9_8765_4321,
+                0x1111_1111_1111_1111_FFFF_FFFF_FFFF_FFFF,
+            ),
+            (-5, -7),
+            (i128::MIN, 1),
+        ];
+        for (a, b) in cases {
+            assert_eq!(wide("SBC", a, b), a.wrapping_sub(b), "{:#x} - {:#x}", a, b);
+        }
+    }
+
+    const JUMPS: [Instruction; 11] = {
+        use Instruction::*;
+        [JMP, JC, JZ, JNZ, JNC, JN, JP, CALL, JSR, JO, JNO]
+    };
+
+    #[test]
+    fn every_jump_target_is_checked_when_assembling() {
+        for jump in JUMPS {
+            for target in [256, -1] {
+                let source = format!("{:?} {}", jump, target);
+                assert!(source.parse::<RomLayout>().is_err(), "{}", source);
+            }
+            let source = format!("{:?} 255", jump);
+            assert!(source.parse::<RomLayout>().is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn every_jump_out_of_rom_faults_taken_or_not() {
+        // The assembler rejects these, so they're patched in.
+        for jump in JUMPS {
+            for target in [256, -1] {
+                let (mut state, _) = machine(&compile("NOP\nHLT\n"));
+                state.rom[0] = RomLayout::new(jump, target);
+                let error = state.step().unwrap_err();
+                assert_eq!(error.fault, RuntimeFault::JumpOutOfRange(target));
+                assert_eq!(
+                    error.to_string(),
+                    format!(
+                        "runtime error at pc 0 ({:?}): jump target {} is out of range 0..=255",
+                        jump, target
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ram_operands_at_the_edges() {
+        let (state, output) = run("LDI 9\nSTA 255\nLDI 0\nADD 255\nOUT\nHLT\n");
+        assert_eq!(output, "9\n");
+        assert_eq!(state.ram[255], 9);
+        for operand in ["-1", "256"] {
+            for instruction in ["LDA", "STA", "ADD"] {
+                assert!(matches!(
+                    errors(&format!("    {} {}\n    HLT\n", instruction, operand))[..],
+                    [CompileErrorKind::OutOfRange { .. }]
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn ram_operands_out_of_range_fault() {
+        // The assembler rejects these, so they're patched in.
+        for address in [-1, 256] {
+            for instruction in [Instruction::LDA, Instruction::STA, Instruction::ADD] {
+                let (mut state, _) = machine(&compile("NOP\nHLT\n"));
+                state.rom[0] = RomLayout::new(instruction, address);
+                let error = state.step().unwrap_err();
+                assert_eq!(error.fault, RuntimeFault::AddressOutOfRange(address));
+                assert_eq!((error.program_counter, error.instruction), (0, instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn running_off_the_last_slot_halts() {
+        let (mut state, _) = machine(&compile("LDI 1\nJMP 255\n"));
+        state.rom[255] = RomLayout::new(Instruction::NOP, 0);
+        finish(&mut state).unwrap();
+        assert!(state.is_halted());
+        assert_eq!(state.program_counter, 255);
+        assert_eq!(state.exit_code, 0);
+    }
+
+    #[test]
+    fn wrap_pc_goes_back_to_slot_0() {
+        let (mut state, _) = machine(&compile("LDI 1\nJMP 255\n"));
+        state.rom[255] = RomLayout::new(Instruction::NOP, 0);
+        state.wrap_pc = true;
+        for _ in 0..3 {
+            state.step().unwrap();
+        }
+        assert!(!state.is_halted());
+        assert_eq!(state.program_counter, 0);
+    }
+
+    #[test]
+    fn adr_out_of_rom_faults_like_ldr() {
+        // The assembler rejects these, so they're patched in.
+        for instruction in [Instruction::ADR, Instruction::LDR] {
+            let (mut state, _) = machine(&compile("NOP\nHLT\n"));
+            state.rom[0] = RomLayout::new(instruction, 256);
+            let error = state.step().unwrap_err();
+            assert_eq!(error.fault, RuntimeFault::RomAddressOutOfRange(256));
+            assert!(format!("{:?} 256", instruction)
+                .parse::<RomLayout>()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn reading_past_the_last_instruction_warns() {
+        for instruction in [Instruction::LDR, Instruction::ADR] {
+            assert_eq!(
+                warnings(&format!("    {:?} 9\n    HLT\n", instruction)),
+                [CompileErrorKind::ReadsPastEnd {
+                    instruction,
+                    address: 9,
+                    last: 1,
+                }]
+            );
+            let in_program = format!("    {:?} table\n    HLT\ntable:\n    HLT 5\n", instruction);
+            assert_eq!(warnings(&in_program), []);
+        }
+        // The last word is still in the program.
+        assert_eq!(warnings("    LDR 1\n    HLT\n"), []);
+    }
+
+    #[test]
+    fn loads_set_zero_and_negative() {
+        use ProgramFlags as F;
+        // Each load, and how to give it the value to load.
+        type Give = fn(&mut ProgramState, i64);
+        let loads: [(&str, Give); 8] = [
+            ("LDA 0", |state, value| state.ram[0] = value),
+            ("LDI 0", |state, value| state.rom[0].value = value.into()),
+            ("LDR 1", |state, value| state.rom[1].value = value.into()),
+            ("LDP 0", |state, value| {
+                state.ram[..2].copy_from_slice(&[1, value])
+            }),
+            ("LAX 0", |state, value| state.ram[0] = value),
+            ("POP", |state, value| {
+                state.stack_pointer = STACK_TOP - 1;
+                state.ram[STACK_TOP as usize - 1] = value;
+            }),
+            ("TBA", |state, value| state.reg_b = value),
+            ("LDH 0", |state, value| {
+                state.reg_a = value;
+                state.rom[0].value = ((value as u64 >> 56) as i64).into();
+            }),
+        ];
+        for (load, give) in loads {
+            for value in [0, -4, 4] {
+                let (mut state, _) = machine(&compile(&format!("{}\nHLT\n", load)));
+                state.reg_a = 99;
+                give(&mut state, value);
+                state.flags = F::CARRY | F::OVERFLOW;
+                state.step().unwrap();
+                assert_eq!(state.reg_a, value, "{}", load);
+                let mut expected = F::CARRY | F::OVERFLOW;
+                expected.set(F::ZERO, value == 0);
+                expected.set(F::NEGATIVE, value < 0);
+                assert_eq!(flags(&state), expected, "{} of {}", load, value);
+            }
+        }
+    }
+
+    #[test]
+    fn jz_after_a_load() {
+        let program = |value| format!("STI 0 {}\nLDA 0\nJZ 4\nHLT 1\nHLT 2\n", value);
+        assert_eq!(run(&program(0)).0.exit_code, 2);
+        assert_eq!(run(&program(3)).0.exit_code, 1);
+    }
+
+    /// An operand for `instruction` that's valid to run, or None if `value`
+    /// isn't one.
+    fn representative(instruction: Instruction, value: i64) -> Option<RomLayout> {
+        use Instruction::*;
+        let operand = match instruction {
+            MCP => "0 1 1".to_string(),
+            MOV | DJNZ => "0 1".to_string(),
+            STI => format!("0 {}", value.clamp(i32::MIN.into(), i32::MAX.into())),
+            SMB | RMB => format!("0 {}", value.rem_euclid(64)),
+            _ => value.to_string(),
+        };
+        RomLayout::from_str(&format!("{:?} {}", instruction, operand)).ok()
+    }
+
+    #[test]
+    fn every_instruction_keeps_to_flags_affected() {
+        use ProgramFlags as F;
+        let values = [0, 1, -1, 3, 255, i64::MAX, i64::MIN];
+        let starting = [
+            F::NONE,
+            F::CARRY | F::ZERO | F::NEGATIVE | F::OVERFLOW,
+            F::CARRY,
+            F::ZERO,
+        ];
+        for &instruction in Instruction::all_variants().iter() {
+            let effect = flags_affected(instruction);
+            let mut ran = 0;
+            for (a, b, before) in values
+                .iter()
+                .flat_map(|&a| values.iter().map(move |&b| (a, b)))
+                .flat_map(|(a, b)| starting.iter().map(move |&before| (a, b, before)))
+            {
+                let Some(word) = representative(instruction, b) else {
+                    continue;
+                };
+                let (mut state, _) = machine(&compile("HLT\n"));
+                state.rom[0] = word;
+                state.warn_padding = false;
+                state.allow_self_modify = true;
+                state.reg_a = a;
+                state.reg_b = b;
+                state.ram[0] = b;
+                state.ram[1] = a;
+                // Something for POP and RET, and RSR's return address.
+                state.push(a).unwrap();
+                state.ram[LINK_CELL] = 1;
+                state.input = VecDeque::from([a.to_string()]);
+                state.flags = before;
+                if state.step().is_err() {
+                    continue;
+                }
+                ran += 1;
+                assert!(
+                    effect.allows(before, flags(&state)),
+                    "{:?} with a = {}, operand {} took the flags from {:?} to {:?}",
+                    instruction,
+                    a,
+                    i64::from(word.value),
+                    before,
+                    flags(&state)
+                );
+            }
+            assert!(ran > 0, "{:?} never ran", instruction);
+        }
+    }
+
+    #[test]
+    fn immediates_must_fit_56_bits() {
+        for instruction in ["LDI", "ADI", "SUI", "CPI", "ANI"] {
+            for value in [IMMEDIATE_MAX, -IMMEDIATE_MAX, IMMEDIATE_MIN] {
+                let word = RomLayout::from_str(&format!("{} {}", instruction, value)).unwrap();
+                assert_eq!(i64::from(word.value), value);
+            }
+            for value in [IMMEDIATE_MAX + 1, IMMEDIATE_MIN - 1, 1 << 56] {
+                let source = format!("NOP\n{} {}\nHLT\n", instruction, value);
+                assert_eq!(
+                    errors(&source),
+                    [CompileErrorKind::OutOfRange {
+                        what: "operand",
+                        operand: value.to_string(),
+                        range: immediate_range(),
+                    }],
+                    "{}",
+                    source
+                );
+            }
+        }
+        assert_eq!(
+            run(&format!("LDI {}\nHLT\n", IMMEDIATE_MIN)).0.reg_a,
+            -(1 << 55)
+        );
+    }
+
+    #[test]
+    fn steps_past_hlt_change_nothing() {
+        let (mut state, stdout) = machine(&compile("LDI 4\nHLT 2\nOUT\nSTA 0\n"));
+        state.step().unwrap();
+        assert_eq!(state.step().unwrap(), Instruction::HLT);
+        assert!(state.is_halted());
+        let before = (state.describe(), state.ram, state.exit_code);
+        assert_eq!(state.program_counter, 1);
+        for _ in 0..3 {
+            assert_eq!(state.step().unwrap(), Instruction::HLT);
+            assert!(state.is_halted());
+            assert_eq!((state.describe(), state.ram, state.exit_code), before);
+        }
+        assert_eq!(stdout.text(), "");
+    }
+
+    #[test]
+    fn falling_into_the_padding_is_reported() {
+        let (mut state, _) = machine(&compile("LDI 1\nOUT\n"));
+        assert_eq!(state.program_length, 2);
+        state.step().unwrap();
+        assert_eq!(state.padding_warning(0, false), None);
+        state.step().unwrap();
+        assert_eq!(
+            state.padding_warning(1, false).unwrap(),
+            "warning: execution fell off the end of the program at 1 into the HLT padding"
+        );
+        finish(&mut state).unwrap();
+        assert_eq!(state.exit_code, 0);
+    }
+
+    #[test]
+    fn jumping_into_the_padding_is_reported() {
+        let (mut state, _) = machine(&compile("JMP 100\nHLT\n"));
+        state.step().unwrap();
+        assert_eq!(state.program_counter, 100);
+        assert_eq!(
+            state.padding_warning(0, true).unwrap(),
+            "warning: JMP at 0 jumped to 100, past the last instruction (1) into the HLT padding"
+        );
+        // Running on inside the padding isn't reported again.
+        assert_eq!(state.padding_warning(100, false), None);
+    }
+
+    #[test]
+    fn labels_branch_forwards_and_loop_backwards() {
+        let (_, output) = run("JMP start\nHLT 1\nstart: LDI 2\nloop:\nOUT\nSUI 1\nJNZ loop\nHLT\n");
+        assert_eq!(output, "2\n1\n");
+        let rom = compile("JMP start\nstart:\nHLT\n").rom;
+        assert_eq!(rom.len(), 2);
+        assert_eq!(i64::from(rom[0].value), 1);
+        // Numbers still work.
+        assert_eq!(run("JMP 2\nHLT 1\nHLT 2\n").0.exit_code, 2);
+    }
+
+    #[test]
+    fn undefined_label_is_an_error() {
+        assert_eq!(
+            errors("JMP nowhere\nHLT\n"),
+            [CompileErrorKind::UndefinedSymbol {
+                name: "nowhere".to_string(),
+                suggestions: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_label_is_an_error() {
+        assert_eq!(
+            errors("here:\nNOP\nhere:\nHLT\n"),
+            [CompileErrorKind::DuplicateSymbol("here".to_string())]
+        );
+    }
+
+    #[test]
+    fn comment_only_file() {
+        let rom = compile("; nothing here\n\n// or here\n   ; indented\n").rom;
+        assert!(rom.is_empty());
+    }
+
+    #[test]
+    fn comments_after_operands() {
+        let (_, output) = run(concat!(
+            "    LDI 7 ; seven\n",
+            "\n",
+            "    OUT// no space\n",
+            "    ADI 1 // eight\n",
+            "    OUT ;\n",
+            "    HLT\n",
+        ));
+        assert_eq!(output, "7\n8\n");
+    }
+
+    #[test]
+    fn semicolon_in_a_string_is_not_a_comment() {
+        let (_, output) = run(".asciz 10 \"a;b//c\" ; the text\n    OUTS 10\n    HLT\n");
+        assert_eq!(output, "a;b//c");
+        assert_eq!(strip_comment("LDI ';' ; a semicolon"), "LDI ';' ");
+        assert_eq!(strip_comment("\"a;b//c\" ; the text"), "\"a;b//c\" ");
+        assert_eq!(strip_comment("'\\'' ; quote"), "'\\'' ");
+    }
+
+    #[test]
+    fn error_columns_count_from_the_original_line() {
+        let errors = assemble("  LDA 5 6 ; oops\n").unwrap_err();
+        assert_eq!(errors[0].text, "  LDA 5 6 ; oops");
+        assert_eq!(highlight(&errors[0]), 8..9);
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let errors = assemble(concat!(
+            "    LDI 1\n",
+            "    FOO 2\n",
+            "    OUT\n",
+            "    LDA\n",
+            "    LDI 12x\n",
+            "    HLT\n",
+        ))
+        .unwrap_err();
+        let reported: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            reported,
+            [
+                "test.ebr:2: unknown instruction `FOO`, did you mean `NOP`?",
+                "test.ebr:4: LDA is missing an operand",
+                "test.ebr:5: `12x` is not a number",
+            ]
+        );
+        assert_eq!(errors[1].text, "    LDA");
+    }
+
+    #[test]
+    fn unknown_instructions() {
+        assert_eq!(
+            errors("    LDAA 5\n    HLT\n"),
+            [CompileErrorKind::UnknownInstruction {
+                instruction: "LDAA".to_string(),
+                suggestion: Some("LDA".to_string()),
+            }]
+        );
+        assert_eq!(
+            errors("    ldaa 5\n    HLT\n"),
+            [CompileErrorKind::UnknownInstruction {
+                instruction: "ldaa".to_string(),
+                suggestion: Some("LDA".to_string()),
+            }]
+        );
+        assert_eq!(
+            errors("    XYZZYPLUGH\n    HLT\n"),
+            [CompileErrorKind::UnknownInstruction {
+                instruction: "XYZZYPLUGH".to_string(),
+                suggestion: None,
+            }]
+        );
+        assert_eq!(
+            "".parse::<RomLayout>().unwrap_err(),
+            CompileErrorKind::MissingInstruction
+        );
+        assert_eq!(
+            "   ".parse::<RomLayout>().unwrap_err(),
+            CompileErrorKind::MissingInstruction
+        );
+    }
+
+    #[test]
+    fn radix_literals() {
+        let word = |source: &str| source.parse::<RomLayout>().unwrap().pack().unwrap();
+        assert_eq!(word("LDI 0xFF"), word("LDI 255"));
+        let decoded = RomLayout::unpack_from_slice(&word("LDI 0xFF")).unwrap();
+        assert_eq!(decoded.instruction, Instruction::LDI);
+        assert_eq!(i64::from(decoded.value), 255);
+        for (literal, value) in [
+            ("0xff", 255),
+            ("0b1111_1111", 255),
+            ("0o377", 255),
+            ("1_000", 1000),
+            ("-0x10", -16),
+            ("-0b1", -1),
+        ] {
+            assert_eq!(
+                word(&format!("LDI {}", literal)),
+                word(&format!("LDI {}", value)),
+                "{}",
+                literal
+            );
+        }
+        assert_eq!(
+            errors("    LDI 0x80_0000_0000_0000\n    HLT\n"),
+            [CompileErrorKind::OutOfRange {
+                what: "operand",
+                operand: "0x80_0000_0000_0000".to_string(),
+                range: immediate_range(),
+            }]
+        );
+        assert_eq!(
+            errors("    LDI 0b102\n    HLT\n"),
+            [CompileErrorKind::InvalidNumber("0b102".to_string())]
+        );
+    }
+
+    #[test]
+    fn equ_names_ram_cells() {
+        let (state, output) = run(concat!(
+            "x EQU 10\n",
+            "y equ 11\n",
+            ".equ sum 12\n",
+            "    LDI 3\n",
+            "    STA x\n",
+            "    LDI 4\n",
+            "    STA y\n",
+            "    LDA x\n",
+            "    ADD y\n",
+            "    STA sum\n",
+            "    OUT\n",
+            "    HLT\n",
+        ));
+        assert_eq!(output, "7\n");
+        assert_eq!(state.ram[10..13], [3, 4, 7]);
+    }
+
+    #[test]
+    fn equ_errors() {
+        assert_eq!(
+            errors("x EQU 1\nx EQU 2\n    HLT\n"),
+            [CompileErrorKind::DuplicateSymbol("x".to_string())]
+        );
+        assert_eq!(
+            errors("    LDA counter\n    HLT\n"),
+            [CompileErrorKind::UndefinedSymbol {
+                name: "counter".to_string(),
+                suggestions: vec![],
+            }]
+        );
+        let error = &assemble("    LDA counter\n    HLT\n").unwrap_err()[0];
+        assert!(error.to_string().contains("`counter`"), "{}", error);
+    }
+
+    #[test]
+    fn data_table_is_in_ram_before_the_first_step() {
+        let source = concat!(
+            ".data 20 5 -7 0x10\n",
+            "    LDA 20\n",
+            "    ADD 21\n",
+            "    ADD 22\n",
+            "    OUT\n",
+            "    HLT\n",
+        );
+        let (state, output) = run(source);
+        assert_eq!(output, "14\n");
+        assert_eq!(state.ram[20..23], [5, -7, 16]);
+
+        let program = read_rom(&compile(source).to_bytes(false), false).unwrap();
+        assert_eq!(program.data.len(), 1);
+        assert_eq!(program.data[0].address, 20);
+        assert_eq!(program.data[0].values, [5, -7, 16]);
+
+        let program = read_rom(&compile("    HLT\n").to_bytes(false), false).unwrap();
+        assert!(program.data.is_empty());
+    }
+
+    #[test]
+    fn org_places_code_at_its_address() {
+        let program = compile(concat!(
+            "    JMP table\n",
+            ".org 0x10\n",
+            "code:\n",
+            "    LDI 1\n",
+            "    HLT\n",
+            ".org 0xF0 NOP\n",
+            "table:\n",
+            "    JMP code\n",
+        ));
+        let image: Vec<u8> = program
+            .padded_rom()
+            .iter()
+            .flat_map(|word| word.pack().unwrap())
+            .collect();
+        assert_eq!(image.len(), 2048);
+        let word = |source: &str| source.parse::<RomLayout>().unwrap().pack().unwrap();
+        let at = |address: usize| &image[address * 8..address * 8 + 8];
+        assert_eq!(at(0), word("JMP 0xF0"));
+        assert_eq!(at(1), word("HLT 0"));
+        assert_eq!(at(0x10), word("LDI 1"));
+        assert_eq!(at(0x12), word("NOP"));
+        assert_eq!(at(0xF0), word("JMP 0x10"));
+        assert_eq!(at(0xF1), word("HLT 0"));
+    }
+
+    #[test]
+    fn org_cannot_move_backwards_or_past_rom() {
+        assert_eq!(
+            errors("    NOP\n    NOP\n.org 1\n    HLT\n"),
+            [CompileErrorKind::OutOfRange {
+                what: "origin",
+                operand: "1".to_string(),
+                range: "2..=256, as it can't move backwards".to_string(),
+            }]
+        );
+        assert!(matches!(
+            errors(".org 257\n")[..],
+            [CompileErrorKind::OutOfRange { what: "origin", .. }]
+        ));
+    }
+
+    #[test]
+    fn org_can_pad_to_the_end_of_rom() {
+        let program = compile("    HLT\n.org 256 NOP\n");
+        assert_eq!(program.rom.len(), ROM_SIZE);
+        assert_eq!(program.rom[255].instruction, Instruction::NOP);
+    }
+
+    #[test]
+    fn whitespace_and_case_are_tolerated() {
+        let word = |source: &str| source.parse::<RomLayout>().unwrap().pack().unwrap();
+        for source in [
+            "\tLDA\t5",
+            "LDA  5",
+            "  \t LDA \t  5",
+            "lda 5",
+            "lDa 5",
+            "LDA 5   ",
+            "LDA 5\t",
+        ] {
+            assert_eq!(word(source), word("LDA 5"), "{:?}", source);
+        }
+        assert_eq!(word("out"), word("OUT 0"));
+
+        let (_, output) = run(concat!(
+            "x Equ 4\n",
+            "\tldi  x \n",
+            "loop:\n",
+            "\t\tOut\n",
+            "\t\tadi\t-1\n",
+            "\t\tJnz loop   \n",
+            "\tHLT\n",
+        ));
+        assert_eq!(output, "4\n3\n2\n1\n");
+    }
+
+    #[test]
+    fn two_argument_macro_used_three_times() {
+        let (state, output) = run(concat!(
+            ".macro addto dst src\n",
+            "    LDA dst\n",
+            "    ADD src\n",
+            "    STA dst\n",
+            ".endmacro\n",
+            ".macro show cell\n",
+            "    LDA cell\n",
+            "    OUT\n",
+            ".endmacro\n",
+            ".data 0 1 10 100\n",
+            "    addto 0 1\n",
+            "    addto 0 2\n",
+            "    addto 1 0\n",
+            "    show 0\n",
+            "    show 1\n",
+            "    HLT\n",
+        ));
+        assert_eq!(output, "111\n121\n");
+        assert_eq!(state.ram[0..3], [111, 121, 100]);
+    }
+
+    #[test]
+    fn recursive_macros_are_an_error() {
+        let source = concat!(
+            ".macro ping\n",
+            "    pong\n",
+            ".endmacro\n",
+            ".macro pong\n",
+            "    ping\n",
+            ".endmacro\n",
+            "    ping\n",
+            "    HLT\n",
+        );
+        assert_eq!(
+            errors(source),
+            [CompileErrorKind::RecursiveMacro(vec![
+                "ping".to_string(),
+                "pong".to_string(),
+                "ping".to_string(),
+            ])]
+        );
+    }
+
+    #[test]
+    fn errors_in_a_macro_point_at_the_invocation() {
+        let errors = assemble(concat!(
+            ".macro load cell\n",
+            "    LDA cell\n",
+            ".endmacro\n",
+            "    load nowhere\n",
+            "    HLT\n",
+        ))
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(
+            errors[0].context,
+            [Context {
+                file: "test.ebr".into(),
+                line: 4,
+                text: "    load nowhere".to_string(),
+                note: "in the macro invoked here",
+            }]
+        );
+    }
+
+    /// Writes `files` to a fresh directory named after `test` and assembles
+    /// the first of them.
+    fn assemble_files(test: &str, files: &[(&str, &str)]) -> Result<Program, Vec<CompileError>> {
+        let directory = std::env::temp_dir().join(format!("e64br-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        for (name, text) in files {
+            std::fs::write(directory.join(name), text).unwrap();
+        }
+        let main = directory.join(files[0].0);
+        let result = compile_rom(&[(files[0].1.to_string(), &main)], &[], false);
+        std::fs::remove_dir_all(&directory).unwrap();
+        result
+    }
+
+    #[test]
+    fn labels_defined_in_an_include() {
+        let program = assemble_files(
+            "include",
+            &[
+                (
+                    "main.ebr",
+                    "    LDI 6\n    JSR double\n    OUT\n    HLT\n.include \"lib.ebr\"\n",
+                ),
+                ("lib.ebr", "double:\n    STA 0\n    ADD 0\n    RSR\n"),
+            ],
+        )
+        .unwrap_or_else(|errors| panic!("{}", errors[0]));
+        let (mut state, stdout) = machine(&program);
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "12\n");
+        let source = program.sources[4].as_ref().unwrap();
+        assert!(source.file.ends_with("lib.ebr"), "{}", source.file);
+        assert_eq!(source.line, 2);
+    }
+
+    #[test]
+    fn missing_include() {
+        let errors = assemble_files(
+            "missing-include",
+            &[("main.ebr", "    NOP\n.include \"nowhere.ebr\"\n    HLT\n")],
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert!(matches!(
+            &errors[0].kind,
+            CompileErrorKind::IncludeFailed { path, reason: io::ErrorKind::NotFound }
+                if path.ends_with("nowhere.ebr")
+        ));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let errors = assemble_files(
+            "include-cycle",
+            &[
+                ("main.ebr", ".include \"a.ebr\"\n    HLT\n"),
+                ("a.ebr", ".include \"b.ebr\"\n"),
+                ("b.ebr", "    NOP\n.include \"a.ebr\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].file.ends_with("b.ebr"));
+        assert_eq!(errors[0].line, 2);
+        let CompileErrorKind::IncludeCycle(chain) = &errors[0].kind else {
+            panic!("{:?}", errors[0].kind);
+        };
+        let names: Vec<&str> = chain
+            .iter()
+            .map(|file| file.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(names, ["main.ebr", "a.ebr", "b.ebr", "a.ebr"]);
+    }
+
+    #[test]
+    fn ok_example_prints_ok() {
+        let (_, output) = run(include_str!("../examples/ok.ebr"));
+        assert_eq!(output, "OK\n");
+    }
+
+    #[test]
+    fn char_literals() {
+        for (literal, value) in [
+            ("'H'", 72),
+            ("' '", 32),
+            ("'\\n'", 10),
+            ("'\\t'", 9),
+            ("'\\''", 39),
+            ("'\\\\'", 92),
+            ("'\\0'", 0),
+        ] {
+            assert_eq!(parse_char(literal), Ok(value), "{}", literal);
+        }
+        for (literal, reason) in [
+            ("'AB'", "more than one character"),
+            ("'\\q'", "unsupported escape"),
+            ("''", "expected one character between single quotes"),
+        ] {
+            let errors = assemble(&format!("    LDI {}\n    HLT\n", literal)).unwrap_err();
+            assert_eq!(
+                errors[0].kind,
+                CompileErrorKind::InvalidLiteral {
+                    literal: literal.to_string(),
+                    reason,
+                }
+            );
+            assert_eq!(highlight(&errors[0]), 8..8 + literal.len());
+        }
+    }
+
+    #[test]
+    fn expression_precedence_and_parentheses() {
+        let symbols = HashMap::from([("size", 8), ("base", 100)]);
+        for (expression, value) in [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("20 / 3 % 4", 2),
+            ("-2 * -3", 6),
+            ("size*8", 64),
+            ("base + (size - 1) * 2", 114),
+            ("0x10 + 'A'", 81),
+        ] {
+            assert_eq!(resolve(&symbols, expression), Ok(value), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn labels_in_expressions() {
+        let source = concat!(
+            "SIZE EQU 3\n",
+            "    LDI SIZE*2+1\n",
+            "    JMP loop+1\n",
+            "loop:\n",
+            "    HLT\n",
+            "    OUT\n",
+            "    HLT table - loop\n",
+            "table:\n",
+        );
+        assert_eq!(i64::from(compile(source).rom[4].value), 3);
+        let (_, output) = run(source);
+        assert_eq!(output, "7\n");
+    }
+
+    #[test]
+    fn expression_errors() {
+        let symbols = HashMap::from([("big", i64::MAX)]);
+        let reason = |expression: &str| match resolve(&symbols, expression) {
+            Err(CompileErrorKind::InvalidExpression {
+                expression: text,
+                reason,
+            }) => {
+                assert_eq!(text, expression);
+                reason
+            }
+            other => panic!("{}: {:?}", expression, other),
+        };
+        assert_eq!(reason("big + 1"), "overflows 64 bits");
+        assert_eq!(reason("4 / (2 - 2)"), "division by zero");
+        assert_eq!(reason("1 % 0"), "division by zero");
+        assert_eq!(reason("(1 + 2"), "missing `)`");
+        assert_eq!(reason("nothing + 1"), "`nothing` is not defined");
+    }
+
+    #[test]
+    fn programs_longer_than_rom_are_rejected() {
+        let source = "    NOP\n".repeat(300);
+        let reported = assemble(&source).unwrap_err();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].kind, CompileErrorKind::ProgramTooLong(300));
+        assert_eq!(reported[0].line, 257);
+        assert_eq!(
+            reported[0].to_string(),
+            "test.ebr:257: program is 300 instructions, maximum is 256"
+        );
+        assert!(assemble(&"    NOP\n".repeat(256)).is_ok());
+        assert!(matches!(
+            errors(".org 200\n.fill 50\n.align 16\n    HLT\n")[..],
+            [CompileErrorKind::ProgramTooLong(257)]
+        ));
+    }
+
+    #[test]
+    fn loader_rejects_oversized_roms() {
+        let mut program = compile("    HLT\n");
+        program.rom = vec![RomLayout::new(Instruction::NOP, 0); 300];
+        let message = "program is 300 instructions, maximum is 256";
+        assert_eq!(
+            read_rom(&program.to_bytes(false), false).unwrap_err(),
+            message
+        );
+        let legacy: Vec<u8> = program
+            .rom
+            .iter()
+            .flat_map(|word| word.pack().unwrap())
+            .collect();
+        assert_eq!(read_rom(&legacy, true).unwrap_err(), message);
+    }
+
+    #[test]
+    fn operand_counts_for_each_arity() {
+        let unexpected = |tokens: &[&str]| {
+            Err(CompileErrorKind::UnexpectedOperands(
+                tokens.iter().map(|token| token.to_string()).collect(),
+            ))
+        };
+        let parsed =
+            |line| RomLayout::parse(line).map(|(word, ignored)| (word.pack().unwrap(), ignored));
+        let word = |instruction, value| RomLayout::new(instruction, value).pack().unwrap();
+        let missing =
+            |instruction: &str| Err(CompileErrorKind::MissingOperand(instruction.to_string()));
+
+        // No operand: one is ignored (and warned about), more are an error.
+        assert_eq!(parsed("NOP"), Ok((word(Instruction::NOP, 0), None)));
+        assert_eq!(parsed("NOP 3"), Ok((word(Instruction::NOP, 0), Some("3"))));
+        assert_eq!(parsed("NOP 3 4"), unexpected(&["3", "4"]));
+        // An optional operand.
+        assert_eq!(parsed("HLT"), Ok((word(Instruction::HLT, 0), None)));
+        assert_eq!(parsed("HLT 3"), Ok((word(Instruction::HLT, 3), None)));
+        assert_eq!(parsed("OUT 99 1"), unexpected(&["1"]));
+        // One operand.
+        assert_eq!(parsed("LDA"), missing("LDA"));
+        assert_eq!(parsed("LDA 5"), Ok((word(Instruction::LDA, 5), None)));
+        assert_eq!(parsed("LDA 5 6"), unexpected(&["6"]));
+        assert_eq!(parsed("LDA 5 OUT"), unexpected(&["OUT"]));
+        // Two operands.
+        assert_eq!(parsed("MOV 1"), missing("MOV"));
+        assert!(parsed("MOV 1 2").is_ok());
+        assert_eq!(parsed("MOV 1 2 3"), unexpected(&["3"]));
+        // Three operands.
+        assert_eq!(parsed("MCP 1 2"), missing("MCP"));
+        assert!(parsed("MCP 1 2 3").is_ok());
+        assert_eq!(parsed("MCP 1 2 3 4 5"), unexpected(&["4", "5"]));
+
+        let program = compile("    NOP 3\n    HLT\n");
+        assert_eq!(
+            program.warnings[0].kind,
+            CompileErrorKind::IgnoredOperand {
+                instruction: Instruction::NOP,
+                operand: "3".to_string(),
+            }
+        );
+        assert_eq!(program.warnings[0].line, 1);
+    }
+
+    #[test]
+    fn listing_matches_the_golden_file() {
+        let source = include_str!("../examples/countdown.ebr");
+        let program = compile_rom(
+            &[(source.to_string(), Path::new("countdown.ebr"))],
+            &[],
+            false,
+        )
+        .unwrap();
+        assert_eq!(listing(&program), include_str!("../examples/countdown.lst"));
+    }
+
+    #[test]
+    fn listing_ends_with_the_symbols() {
+        let program = compile(concat!(
+            "ONE EQU 1\n",
+            "    LDI 3\n",
+            "loop:\n",
+            "    SUI ONE  ; count down\n",
+            "    JNZ loop\n",
+            "    HLT\n",
+        ));
+        assert_eq!(
+            listing(&program),
+            concat!(
+                "000  0a 00 00 00 00 00 00 03  test.ebr:2  LDI 3\n",
+                "001  0e 00 00 00 00 00 00 01  test.ebr:4  SUI ONE  ; count down\n",
+                "002  23 00 00 00 00 00 00 01  test.ebr:5  JNZ loop\n",
+                "003  09 00 00 00 00 00 00 00  test.ebr:6  HLT\n",
+                "\n",
+                "symbols:\n",
+                "ONE 1 constant\n",
+                "loop 1 label\n",
+            )
+        );
+    }
+
+    #[test]
+    fn symbol_table_round_trips() {
+        let program = compile(concat!(
+            "LIMIT EQU 3\n",
+            "table: .data 40 1 2 3\n",
+            "main:\n",
+            "    LDI LIMIT\n",
+            ".loop:\n",
+            "    SUI 1\n",
+            "    JNZ .loop\n",
+            "    HLT\n",
+        ));
+        let text = symbol_table(&program.symbols);
+        assert_eq!(
+            text,
+            concat!(
+                "main 0 label\n",
+                "main.loop 1 label\n",
+                "LIMIT 3 constant\n",
+                "table 40 data\n",
+            )
+        );
+        let symbols = read_symbols(&text).unwrap();
+        let without_definitions: Vec<Symbol> = program
+            .symbols
+            .iter()
+            .map(|symbol| Symbol {
+                definition: None,
+                ..symbol.clone()
+            })
+            .collect();
+        assert_eq!(symbols, without_definitions);
+        assert_eq!(
+            read_symbols("total 0 variable 2\n").unwrap()[0].kind,
+            SymbolKind::Variable(2)
+        );
+        assert_eq!(
+            read_symbols("loop 3 label\nloop three label\n").unwrap_err(),
+            "symbol file line 2: expected `name value kind`"
+        );
+    }
+
+    #[test]
+    fn operand_kinds_decide_what_assembles() {
+        use Instruction::*;
+        for (instruction, kind) in [
+            (NOP, OperandKind::None),
+            (RET, OperandKind::None),
+            (LDA, OperandKind::RamAddress),
+            (DBG, OperandKind::RamAddress),
+            (JMP, OperandKind::RomAddress),
+            (LDR, OperandKind::RomAddress),
+            (LDI, OperandKind::Immediate),
+            (JR, OperandKind::Immediate),
+            (MCP, OperandKind::Fields),
+            (STI, OperandKind::Fields),
+        ] {
+            assert_eq!(operand_kind(instruction), kind, "{:?}", instruction);
+        }
+
+        let what = |source: &str| match &errors(&format!("    {}\n    HLT\n", source))[..] {
+            [CompileErrorKind::OutOfRange { what, .. }] => *what,
+            errors => panic!("{}: {:?}", source, errors),
+        };
+        assert_eq!(what("LDA 256"), "RAM address");
+        assert_eq!(what("STA -1"), "RAM address");
+        assert_eq!(what("LDR 256"), "ROM address");
+        assert_eq!(what("MCP 0 256 1"), "operand");
+        for source in ["LDA 255", "LDR 255", "LDI -300", "MCP 0 255 1"] {
+            assert!(
+                assemble(&format!("    {}\n    HLT\n", source)).is_ok(),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            OperandKind::Immediate.check(IMMEDIATE_MIN),
+            Ok(IMMEDIATE_MIN)
+        );
+    }
+
+    #[test]
+    fn hello_string_example() {
+        let (state, output) = run(include_str!("../examples/hello_string.ebr"));
+        assert_eq!(output, "Hello, world!\n");
+        assert_eq!(state.ram[13..15], ['\n' as i64, 0]);
+    }
+
+    #[test]
+    fn ascii_and_asciz() {
+        let (state, _) = run(".ascii 10 \"a\\tb\"\n.asciz 20 \"\\\"q\\\"\"\n    HLT\n");
+        assert_eq!(state.ram[10..14], ['a' as i64, 9, 'b' as i64, 0]);
+        assert_eq!(state.ram[20..24], ['"' as i64, 'q' as i64, '"' as i64, 0]);
+        assert!(matches!(
+            errors(".asciz 250 \"abcdef\"\n    HLT\n")[..],
+            [CompileErrorKind::OutOfRange { .. }]
+        ));
+        assert!(assemble(".ascii 250 \"abcdef\"\n    HLT\n").is_ok());
+    }
+
+    #[test]
+    fn locals_are_scoped_to_their_global_label() {
+        let (_, output) = run(concat!(
+            "first:\n",
+            "    LDI 2\n",
+            ".loop:\n",
+            "    OUT\n",
+            "    SUI 1\n",
+            "    JNZ .loop\n",
+            "second:\n",
+            "    LDI 12\n",
+            ".loop:\n",
+            "    OUT\n",
+            "    SUI 1\n",
+            "    CPI 10\n",
+            "    JNZ .loop\n",
+            "    HLT\n",
+        ));
+        assert_eq!(output, "2\n1\n12\n11\n");
+    }
+
+    #[test]
+    fn each_macro_expansion_has_its_own_locals() {
+        let (_, output) = run(concat!(
+            ".macro countdown from\n",
+            "    LDI from\n",
+            ".again:\n",
+            "    OUT\n",
+            "    SUI 1\n",
+            "    JNZ .again\n",
+            ".endmacro\n",
+            "main:\n",
+            "    countdown 2\n",
+            "    countdown 1\n",
+            "    HLT\n",
+        ));
+        assert_eq!(output, "2\n1\n1\n");
+    }
+
+    #[test]
+    fn locals_out_of_scope() {
+        let source = concat!(
+            "first:\n",
+            ".loop:\n",
+            "    NOP\n",
+            "second:\n",
+            "    JMP .loop\n",
+            "    HLT\n",
+        );
+        assert_eq!(
+            errors(source)[0],
+            CompileErrorKind::UndefinedLocal {
+                label: ".loop".to_string(),
+                scope: Some("second".to_string()),
+            }
+        );
+        assert_eq!(
+            errors("    JMP .loop\nfirst:\n.loop:\n    HLT\n")[0],
+            CompileErrorKind::UndefinedLocal {
+                label: ".loop".to_string(),
+                scope: None,
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_label_across_an_include() {
+        let errors = assemble_files(
+            "duplicate-include",
+            &[
+                (
+                    "main.ebr",
+                    "start:\n    NOP\n.include \"lib.ebr\"\n    HLT\n",
+                ),
+                ("lib.ebr", "    NOP\nstart:\n    RSR\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].kind,
+            CompileErrorKind::DuplicateSymbol("start".to_string())
+        );
+        assert!(errors[0].file.ends_with("lib.ebr"));
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].notes.len(), 1);
+        assert!(errors[0].notes[0].file.ends_with("main.ebr"));
+        assert_eq!(errors[0].notes[0].line, 1);
+        assert_eq!(errors[0].notes[0].text, "start:");
+    }
+
+    #[test]
+    fn undefined_labels_suggest_close_names() {
+        let source = concat!(
+            "loop:\n",
+            "    NOP\n",
+            "lope:\n",
+            "    JMP lop\n",
+            "    JMP elsewhere\n",
+            "    HLT\n",
+        );
+        let reported = assemble(source).unwrap_err();
+        assert_eq!(
+            reported[0].kind,
+            CompileErrorKind::UndefinedSymbol {
+                name: "lop".to_string(),
+                suggestions: vec!["loop".to_string(), "lope".to_string()],
+            }
+        );
+        assert_eq!(
+            reported[1].kind,
+            CompileErrorKind::UndefinedSymbol {
+                name: "elsewhere".to_string(),
+                suggestions: vec![],
+            }
+        );
+        assert_eq!(
+            errors("loop:\n    JMP lop\n")[0].to_string(),
+            "`lop` is not defined, did you mean `loop`?"
+        );
+    }
+
+    /// Runs `source` assembled with `defines`, returning its output.
+    fn run_defined(source: &str, defines: &[(&str, i64)]) -> String {
+        let defines: Vec<(String, i64)> = defines
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value))
+            .collect();
+        let files = [(source.to_string(), Path::new("test.ebr"))];
+        let (mut state, stdout) = machine(&compile_rom(&files, &defines, false).unwrap());
+        finish(&mut state).unwrap();
+        stdout.text()
+    }
+
+    #[test]
+    fn if_takes_one_branch() {
+        let source = concat!(
+            ".if DEBUG\n",
+            "    LDI 1\n",
+            "    OUT\n",
+            ".else\n",
+            "    LDI 2\n",
+            "    OUT\n",
+            ".endif\n",
+            "    HLT\n",
+        );
+        assert_eq!(run_defined(source, &[("DEBUG", 1)]), "1\n");
+        assert_eq!(run_defined(source, &[("DEBUG", 0)]), "2\n");
+    }
+
+    #[test]
+    fn nested_ifs() {
+        let source = concat!(
+            "LEVEL EQU 2\n",
+            ".if LEVEL\n",
+            "    LDI 1\n",
+            "    OUT\n",
+            ".if LEVEL - 1\n",
+            "    LDI 2\n",
+            "    OUT\n",
+            ".else\n",
+            "    LDI 3\n",
+            "    OUT\n",
+            ".endif\n",
+            ".else\n",
+            ".if LEVEL - 1\n",
+            "    LDI 4\n",
+            "    OUT\n",
+            ".endif\n",
+            ".endif\n",
+            "    HLT\n",
+        );
+        assert_eq!(run(source).1, "1\n2\n");
+        assert_eq!(run(&source.replacen("EQU 2", "EQU 1", 1)).1, "1\n3\n");
+        assert_eq!(run(&source.replacen("EQU 2", "EQU 0", 1)).1, "4\n");
+    }
+
+    #[test]
+    fn labels_in_the_skipped_branch_are_not_defined() {
+        assert_eq!(
+            errors(".if 0\nhidden:\n    NOP\n.endif\n    JMP hidden\n    HLT\n"),
+            [CompileErrorKind::UndefinedSymbol {
+                name: "hidden".to_string(),
+                suggestions: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn unbalanced_ifs() {
+        let reported = assemble("    NOP\n.if 1\n.if 0\n.endif\n    HLT\n").unwrap_err();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].kind, CompileErrorKind::UnterminatedIf);
+        assert_eq!(reported[0].line, 2);
+        assert_eq!(
+            errors("    NOP\n.endif\n    HLT\n"),
+            [CompileErrorKind::MisplacedDirective(".endif")]
+        );
+        assert_eq!(
+            errors(".else\n    HLT\n"),
+            [CompileErrorKind::MisplacedDirective(".else")]
+        );
+    }
+
+    #[test]
+    fn rep_counter_varies_the_operands() {
+        let (state, _) = run(concat!(
+            ".rep 4 i\n",
+            "    LDI i * 10\n",
+            "    STA 20 + i\n",
+            ".endrep\n",
+            ".rep 0\n",
+            "    OUT\n",
+            ".endrep\n",
+            "    HLT\n",
+        ));
+        assert_eq!(state.ram[20..24], [0, 10, 20, 30]);
+        let program = compile(".rep 3 i\n    LDA i\n.endrep\n    HLT\n");
+        let operands: Vec<i64> = program.rom[..3]
+            .iter()
+            .map(|word| word.value.into())
+            .collect();
+        assert_eq!(operands, [0, 1, 2]);
+    }
+
+    #[test]
+    fn rep_errors() {
+        assert_eq!(
+            errors(".rep 2\n    NOP\n    HLT\n"),
+            [CompileErrorKind::UnterminatedRep]
+        );
+        assert!(matches!(
+            errors(".rep 200\n    NOP\n    NOP\n.endrep\n    HLT\n")[..],
+            [CompileErrorKind::ProgramTooLong(401)]
+        ));
+        assert!(matches!(
+            errors(".rep 300\n    NOP\n.endrep\n")[..],
+            [CompileErrorKind::OutOfRange {
+                what: "repeat count",
+                ..
+            }]
+        ));
+        assert!(matches!(
+            errors(".rep count\n    NOP\n.endrep\n")[..],
+            [CompileErrorKind::UndefinedSymbol { .. }]
+        ));
+    }
+
+    #[test]
+    fn labels_after_fill_and_align() {
+        let program = compile(concat!(
+            "    NOP\n",
+            ".fill 3 NOP\n",
+            "filled:\n",
+            "    NOP\n",
+            ".align 8\n",
+            "aligned:\n",
+            "    JMP filled\n",
+            "    JMP aligned\n",
+        ));
+        assert_eq!(program.rom.len(), 10);
+        assert_eq!(i64::from(program.rom[8].value), 4);
+        assert_eq!(i64::from(program.rom[9].value), 8);
+        assert!(program.rom[1..4]
+            .iter()
+            .all(|word| word.instruction == Instruction::NOP));
+        assert!(program.rom[5..8]
+            .iter()
+            .all(|word| word.instruction == Instruction::HLT && i64::from(word.value) == 0));
+        // Already aligned, so nothing is added.
+        assert_eq!(compile(".align 4\n    HLT\n").rom.len(), 1);
+    }
+
+    #[test]
+    fn overfilling_is_an_error() {
+        assert!(matches!(
+            errors("    NOP\n.fill 256\n")[..],
+            [CompileErrorKind::OutOfRange {
+                what: "fill count",
+                ..
+            }]
+        ));
+        assert!(assemble("    NOP\n.fill 255\n").is_ok());
+        assert!(matches!(
+            errors("    NOP\n.align 0\n")[..],
+            [CompileErrorKind::OutOfRange {
+                what: "alignment",
+                ..
+            }]
+        ));
+        assert!(matches!(
+            errors(".org 250\n.align 256\n    HLT\n")[..],
+            [CompileErrorKind::ProgramTooLong(257)]
+        ));
+    }
+
+    /// What `source` assembles with but warns about.
+    fn warnings(source: &str) -> Vec<CompileErrorKind> {
+        compile(source)
+            .warnings
+            .into_iter()
+            .map(|warning| warning.kind)
+            .collect()
+    }
+
+    #[test]
+    fn falling_off_the_end_warns() {
+        let program = compile("    LDI 1\n    OUT\n");
+        assert_eq!(
+            program.warnings[0].kind,
+            CompileErrorKind::FallsOffEnd(Instruction::OUT)
+        );
+        assert_eq!(program.warnings[0].line, 2);
+        assert_eq!(warnings("    LDI 1\n    OUT\n    HLT\n"), []);
+        assert_eq!(warnings("start:\n    OUT\n    JMP start\n"), []);
+        assert_eq!(warnings("    JSR sub\n    HLT\nsub:\n    RSR\n"), []);
+        assert_eq!(
+            warnings("    LDI 1\n    JNZ 0\n"),
+            [CompileErrorKind::FallsOffEnd(Instruction::JNZ)]
+        );
+    }
+
+    /// Assembles and links `files`, each a name and its source.
+    fn link(files: &[(&str, &str)]) -> Result<Program, Vec<CompileError>> {
+        let files: Vec<(String, &Path)> = files
+            .iter()
+            .map(|&(name, source)| (source.to_string(), Path::new(name)))
+            .collect();
+        compile_rom(&files, &[], false)
+    }
+
+    #[test]
+    fn linked_files_call_each_other() {
+        let program = link(&[
+            (
+                "main.ebr",
+                ".import double\n    LDI 21\n    JSR double\n    OUT\n    HLT\n",
+            ),
+            (
+                "lib.ebr",
+                ".export double\ndouble:\n    STA 0\n    ADD 0\n    RSR\n",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(program.rom.len(), 7);
+        assert_eq!(i64::from(program.rom[1].value), 4);
+        let (mut state, stdout) = machine(&program);
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "42\n");
+    }
+
+    #[test]
+    fn link_errors() {
+        let reported = link(&[
+            ("main.ebr", ".import tripel\n    JSR tripel\n    HLT\n"),
+            ("lib.ebr", ".export triple\ntriple:\n    RSR\n"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            reported[0].kind,
+            CompileErrorKind::UnresolvedImport {
+                name: "tripel".to_string(),
+                suggestions: vec!["triple".to_string()],
+            }
+        );
+        assert_eq!(&*reported[0].file, "main.ebr");
+
+        let reported = link(&[
+            ("a.ebr", ".export f\nf:\n    HLT\n"),
+            ("b.ebr", ".export f\nf:\n    HLT\n"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            reported[0].kind,
+            CompileErrorKind::DuplicateExport("f".to_string())
+        );
+        assert_eq!(&*reported[0].file, "b.ebr");
+
+        // Labels that aren't exported stay private.
+        assert!(link(&[("a.ebr", "f:\n    HLT\n"), ("b.ebr", "f:\n    JMP f\n"),]).is_ok());
+
+        let half = "    NOP\n".repeat(200);
+        let reported = link(&[("a.ebr", &half), ("b.ebr", &half)]).unwrap_err();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].kind, CompileErrorKind::ProgramTooLong(400));
+        assert_eq!((&*reported[0].file, reported[0].line), ("b.ebr", 57));
+    }
+
+    #[test]
+    fn faults_cite_the_source_line() {
+        let program = compile("    LDX 10\n    LAX 250\n    HLT\n");
+        let (mut state, _) = machine(&program);
+        let error = finish(&mut state).unwrap_err();
+        assert_eq!(
+            fault_message(&program, &error),
+            format!("test.ebr:2: {}\n    LAX 250", error)
+        );
+
+        // Only the file and line are saved, and test.ebr isn't there to read.
+        let loaded = read_rom(&program.to_bytes(true), false).unwrap();
+        assert_eq!(
+            fault_message(&loaded, &error),
+            format!("test.ebr:2: {}", error)
+        );
+        let loaded = read_rom(&program.to_bytes(false), false).unwrap();
+        assert_eq!(fault_message(&loaded, &error), error.to_string());
+    }
+
+    #[test]
+    fn execution_begins_at_start() {
+        let source = concat!(
+            ".start main\n",
+            "    HLT 37\n",
+            "    HLT 38\n",
+            "main:\n",
+            "    LDR 0\n",
+            "    OUT\n",
+            "    HLT\n",
+        );
+        let program = compile(source);
+        assert_eq!(program.start, 2);
+        let (state, output) = run(source);
+        assert_eq!(output, "37\n");
+        assert_eq!(state.exit_code, 0);
+
+        let loaded = read_rom(&program.to_bytes(false), false).unwrap();
+        assert_eq!(loaded.start, 2);
+        assert_eq!(ProgramState::new(loaded.padded_rom(), 2).program_counter, 2);
+        assert_eq!(compile("    HLT\n").start, 0);
+    }
+
+    #[test]
+    fn only_one_start() {
+        let reported = assemble(".start a\na:\n    NOP\n.start b\nb:\n    HLT\n").unwrap_err();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(
+            reported[0].kind,
+            CompileErrorKind::RepeatedDirective(".start")
+        );
+        assert_eq!((reported[0].line, reported[0].notes[0].line), (4, 1));
+    }
+
+    #[test]
+    fn fmt_canonicalizes_and_keeps_comments() {
+        let messy = concat!(
+            "; counts down\n",
+            "  ; from three\n",
+            "n   equ 3\n",
+            "table:   .data 10 1 2\n",
+            "\tldi   n ; start\n",
+            "loop: out\n",
+            "   SUI\t1    ;  one less\n",
+            "        jnz loop ; again\n",
+            "\n",
+            "hlt   // done\n",
+        );
+        let formatted = format_source(messy);
+        assert_eq!(
+            formatted,
+            concat!(
+                "; counts down\n",
+                "    ; from three\n",
+                "n EQU 3\n",
+                "table: .data 10 1 2\n",
+                "    LDI n    ; start\n",
+                "loop:\n",
+                "    OUT\n",
+                "    SUI 1    ;  one less\n",
+                "    JNZ loop ; again\n",
+                "\n",
+                "    HLT // done\n",
+            )
+        );
+        assert_eq!(format_source(&formatted), formatted);
+        let words = |source: &str| -> Vec<[u8; 8]> {
+            compile(source)
+                .rom
+                .iter()
+                .map(|word| word.pack().unwrap())
+                .collect()
+        };
+        assert_eq!(words(&formatted), words(messy));
+    }
+
+    #[test]
+    fn dead_code_after_a_jump_is_flagged() {
+        let program = compile(concat!(
+            "    JMP end\n",
+            "    LDI 1\n",
+            "    OUT\n",
+            "end:\n",
+            "    HLT\n",
+        ));
+        assert_eq!(program.warnings.len(), 1);
+        assert_eq!(program.warnings[0].kind, CompileErrorKind::Unreachable(2));
+        assert_eq!(program.warnings[0].line, 2);
+    }
+
+    #[test]
+    fn reachable_code_is_not_flagged() {
+        // Only reachable through the branch.
+        assert_eq!(
+            warnings("    LDI 0\n    JZ zero\n    HLT 1\nzero:\n    HLT 2\n"),
+            []
+        );
+        // After a call, which returns to it.
+        assert_eq!(
+            warnings("    JSR sub\n    HLT\nsub:\n    LDI 1\n    RSR\n"),
+            []
+        );
+        assert_eq!(
+            warnings("    CALL sub\n    HLT\nsub:\n    LDI 1\n    RET\n"),
+            []
+        );
+        // Data read with LDR and padding from .fill.
+        assert_eq!(warnings("    LDR 2\n    HLT\n    HLT 5\n.fill 2\n"), []);
+    }
+
+    #[test]
+    fn allow_unreachable_suppresses() {
+        assert_eq!(
+            warnings(concat!(
+                "    JMP end\n",
+                "    LDI 1 ; allow(unreachable)\n",
+                "    OUT   ; allow(unreachable)\n",
+                "end:\n",
+                "    HLT\n",
+            )),
+            []
+        );
+        assert_eq!(
+            warnings(concat!(
+                "    JMP end\n",
+                "    LDI 1 ; allow(unreachable)\n",
+                "    OUT\n",
+                "end:\n",
+                "    HLT\n",
+            )),
+            [CompileErrorKind::Unreachable(1)]
+        );
+    }
+
+    #[test]
+    fn code_reached_through_a_rewritten_jump_is_not_flagged() {
+        assert_eq!(
+            warnings(concat!(
+                "    LDI handler\n",
+                "    STR slot\n",
+                "slot:\n",
+                "    JMP 0\n",
+                "    HLT 1\n",
+                "handler:\n",
+                "    LDI 42\n",
+                "    OUT\n",
+                "    HLT 0\n",
+            )),
+            []
+        );
+    }
+
+    #[test]
+    fn reads_of_unwritten_cells_are_flagged() {
+        let program = compile("    LDI 1\n    STA 21\n    LDA 12\n    OUT\n    HLT\n");
+        assert_eq!(
+            program
+                .warnings
+                .iter()
+                .map(|warning| (&warning.kind, warning.line))
+                .collect::<Vec<_>>(),
+            [(
+                &CompileErrorKind::UninitializedRead {
+                    instruction: Instruction::LDA,
+                    cell: 12,
+                },
+                3
+            )]
+        );
+        assert_eq!(warnings("    LDA 12 ; allow(uninitialized)\n    HLT\n"), []);
+    }
+
+    #[test]
+    fn written_cells_are_not_flagged() {
+        assert_eq!(warnings(".data 12 7\n    LDA 12\n    OUT\n    HLT\n"), []);
+        assert_eq!(warnings("    STI 5 -1\n    LDA 5\n    HLT\n"), []);
+        assert_eq!(warnings("    MOV 3 4\n    ADD 4\n    HLT\n"), []);
+        assert_eq!(warnings("    STA 3\n    ADD 3\n    CMP 3\n    HLT\n"), []);
+    }
+
+    #[test]
+    fn indirect_stores_disable_the_read_lint() {
+        for store in ["STP 0", "SAX 0"] {
+            let source = format!("    {}\n    LDA 12\n    HLT\n", store);
+            assert_eq!(warnings(&source), [], "{}", store);
+        }
+    }
+
+    /// The output of `source` assembled without and with -O, and the length
+    /// of each ROM.
+    fn with_and_without_o(source: &str) -> [(String, usize); 2] {
+        [false, true].map(|optimize| {
+            let files = [(source.to_string(), Path::new("test.ebr"))];
+            let program = compile_rom(&files, &[], optimize).unwrap();
+            let (mut state, stdout) = machine(&program);
+            finish(&mut state).unwrap();
+            (stdout.text(), program.rom.len())
+        })
+    }
+
+    #[test]
+    fn o_keeps_jumps_onto_deleted_words() {
+        let [plain, optimized] = with_and_without_o(concat!(
+            "    LDI 3\n",
+            "loop:\n",
+            "    NOP\n",
+            "    OUT\n",
+            "    SUI 1\n",
+            "    JNZ loop\n",
+            "    JMP load\n",
+            "load:\n",
+            "    LDI 5\n",
+            "    LDI 9\n",
+            "    STA 0\n",
+            "    LDA 0\n",
+            "    OUT\n",
+            "    HLT\n",
+        ));
+        assert_eq!(plain, ("3\n2\n1\n9\n".to_string(), 12));
+        assert_eq!(optimized, ("3\n2\n1\n9\n".to_string(), 9));
+    }
+
+    #[test]
+    fn o_leaves_returns_through_pushed_labels() {
+        let [plain, optimized] = with_and_without_o(concat!(
+            "    NOP\n",
+            "    LDI back\n",
+            "    PUSH\n",
+            "    JMP sub\n",
+            "back:\n",
+            "    LDI 7\n",
+            "    OUT\n",
+            "    HLT\n",
+            "sub:\n",
+            "    RET\n",
+        ));
+        assert_eq!(plain, ("7\n".to_string(), 8));
+        assert_eq!(optimized, plain);
+    }
+
+    #[test]
+    fn o_leaves_jumps_rewritten_with_str() {
+        let [plain, optimized] = with_and_without_o(concat!(
+            "    NOP\n",
+            "    LDI handler\n",
+            "    STR slot\n",
+            "slot:\n",
+            "    JMP 0\n",
+            "    HLT 1\n",
+            "handler:\n",
+            "    LDI 42\n",
+            "    OUT\n",
+            "    HLT 0\n",
+        ));
+        assert_eq!(plain, ("42\n".to_string(), 8));
+        assert_eq!(optimized, plain);
+    }
+
+    #[test]
+    fn jump_targets_past_rom_are_errors() {
+        for source in [
+            "    JMP 256\n",
+            "    JZ 300\n",
+            "    CALL -1\n",
+            "    JR -1\n",
+        ] {
+            assert!(
+                matches!(errors(source)[..], [CompileErrorKind::OutOfRange { .. }]),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn jump_target_warnings() {
+        assert_eq!(
+            warnings("    JC 9\n    HLT\n"),
+            [CompileErrorKind::JumpPastEnd {
+                instruction: Instruction::JC,
+                target: 9,
+                last: 1,
+            }]
+        );
+        assert_eq!(
+            warnings("    LDR table\n    JZ table\n    HLT\ntable:\n    HLT 4\n"),
+            [CompileErrorKind::JumpToData {
+                instruction: Instruction::JZ,
+                target: 3,
+            }]
+        );
+        assert_eq!(
+            warnings("    LDI 2\nloop:\n    DJNZ 0 loop\n    JR 1\n    HLT\n"),
+            []
+        );
+    }
+
+    #[test]
+    fn assert_conditions_parse() {
+        let resolve = |text: &str| resolve(&HashMap::from([("five", 5)]), text);
+        for (condition, parsed) in [
+            ("reg_a == 0", (Probe::A, "==", Probe::Constant(0))),
+            ("ram[five] != reg_b", (Probe::Ram(5), "!=", Probe::B)),
+            ("reg_x<=five*2", (Probe::X, "<=", Probe::Constant(10))),
+            (
+                "ram[0x10] >= -1",
+                (Probe::Ram(16), ">=", Probe::Constant(-1)),
+            ),
+            ("1 < reg_a", (Probe::Constant(1), "<", Probe::A)),
+            ("reg_a > ram[1 + 1]", (Probe::A, ">", Probe::Ram(2))),
+        ] {
+            assert_eq!(
+                parse_condition(condition, resolve),
+                Ok(parsed),
+                "{}",
+                condition
+            );
+        }
+        let usage = CompileErrorKind::InvalidDirective(".assert value == value");
+        assert_eq!(parse_condition("reg_a", resolve), Err(usage.clone()));
+        assert_eq!(parse_condition("== 1", resolve), Err(usage));
+        assert!(matches!(
+            parse_condition("ram[256] == 0", resolve),
+            Err(CompileErrorKind::OutOfRange { .. })
+        ));
+    }
+
+    /// Runs `source`, checking its assertions as `e64br -r` does, returning
+    /// the message of the first that fails.
+    fn check_assertions(source: &str) -> Result<(), String> {
+        let program = compile(source);
+        let (mut state, _) = machine(&program);
+        while !state.is_halted() {
+            if let Some(message) = failed_assertion(&program, &state) {
+                return Err(message);
+            }
+            state.step().unwrap();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn assertions_run_where_they_are_written() {
+        let source = concat!(
+            "    LDI 5\n",
+            "    STA 3\n",
+            ".assert ram[3] == 5\n",
+            "    ADI 1\n",
+            ".assert reg_a == 6\n",
+            "    HLT\n",
+        );
+        assert_eq!(check_assertions(source), Ok(()));
+        assert_eq!(
+            check_assertions(&source.replace("reg_a == 6", "reg_a < ram[3]")),
+            Err(
+                "test.ebr:5: assertion failed: reg_a < ram[3] (reg_a is 6, ram[3] is 5)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn vars_in_two_includes_get_their_own_cells() {
+        let program = assemble_files(
+            "var-include",
+            &[
+                (
+                    "main.ebr",
+                    ".include \"a.ebr\"\n.include \"b.ebr\"\n    LDI 1\n    STA count\n    LDI 2\n    STA total\n    HLT\n",
+                ),
+                ("a.ebr", ".var count\n.var buffer 4\n"),
+                ("b.ebr", ".var total 2\n"),
+            ],
+        )
+        .unwrap_or_else(|errors| panic!("{}", errors[0]));
+        assert_eq!(
+            symbol_table(&program.symbols),
+            concat!(
+                "count 0 variable 1\n",
+                "buffer 1 variable 4\n",
+                "total 5 variable 2\n",
+            )
+        );
+        let (mut state, _) = machine(&program);
+        finish(&mut state).unwrap();
+        assert_eq!(state.ram[0..7], [1, 0, 0, 0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn var_errors() {
+        let reported = assemble(".var a\n.var b 3\n.data 3 9 9\n    HLT\n").unwrap_err();
+        assert_eq!(
+            reported[0].kind,
+            CompileErrorKind::DataOverlapsVariable {
+                name: "b".to_string(),
+                cells: 1..4,
+            }
+        );
+        assert_eq!((reported[0].line, reported[0].notes[0].line), (3, 2));
+        assert!(assemble(".var a\n.data 1 9\n    HLT\n").is_ok());
+
+        assert_eq!(
+            errors(".var a 200\n.var b 50\n.var c 6\n    HLT\n"),
+            [CompileErrorKind::RamExhausted {
+                name: "c".to_string(),
+                size: 6,
+                free: 5,
+            }]
+        );
+        assert!(assemble(".var a 200\n.var b 55\n    HLT\n").is_ok());
+    }
+
+    #[test]
+    fn check_writes_nothing() {
+        let directory = std::env::temp_dir().join(format!("e64br-check-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).display().to_string();
+        std::fs::write(path("good.ebr"), "    LDI 1\n    OUT\n    HLT\n").unwrap();
+        std::fs::write(path("bad.ebr"), "    LDA\n    HLT\n").unwrap();
+        std::fs::write(path("warned.ebr"), "    LDI 1\n    OUT\n").unwrap();
+        let mut options = CompileOptions {
+            defines: Vec::new(),
+            optimize: false,
+            strict: false,
+            json: true,
+            color: false,
+            check: true,
+            output: path("out.ebrc"),
+            source_map: false,
+            listing: Some(path("out.lst")),
+            symbols: Some(path("out.sym")),
+        };
+        let compile =
+            |file: &str, options: &CompileOptions| compile_command(&[path(file)], options).unwrap();
+
+        assert!(compile("good.ebr", &options));
+        assert!(compile("warned.ebr", &options));
+        assert!(!compile("bad.ebr", &options));
+        options.strict = true;
+        assert!(!compile("warned.ebr", &options));
+        for file in ["out.ebrc", "out.lst", "out.sym"] {
+            assert!(!directory.join(file).exists(), "{}", file);
+        }
+
+        options.check = false;
+        assert!(!compile("bad.ebr", &options));
+        assert!(!directory.join("out.ebrc").exists());
+        assert!(compile("good.ebr", &options));
+        let program = read_rom(&std::fs::read(path("out.ebrc")).unwrap(), false).unwrap();
+        assert_eq!(program.rom.len(), 3);
+        assert!(directory.join("out.lst").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// Everything `source` is reported for, rendered without color.
+    fn rendered(source: &str) -> String {
+        let (diagnostics, warning) = match assemble(source) {
+            Ok(program) => (program.warnings, true),
+            Err(errors) => (errors, false),
+        };
+        diagnostics
+            .iter()
+            .map(|diagnostic| render(diagnostic, warning, false))
+            .collect()
+    }
+
+    #[test]
+    fn rendered_diagnostics() {
+        let cases = [
+            (
+                "    LDA 300\n    HLT\n",
+                concat!(
+                    "test.ebr:1: error: RAM address 300 is out of range 0..=255\n",
+                    "    LDA 300\n",
+                    "        ^^^ expected 0..=255\n",
+                ),
+            ),
+            (
+                "    LDAA 5\n    HLT\n",
+                concat!(
+                    "test.ebr:1: error: unknown instruction `LDAA`, did you mean `LDA`?\n",
+                    "    LDAA 5\n",
+                    "    ^^^^ did you mean `LDA`?\n",
+                ),
+            ),
+            (
+                "loop:\n    JMP lop ; again\n",
+                concat!(
+                    "test.ebr:2: error: `lop` is not defined, did you mean `loop`?\n",
+                    "    JMP lop ; again\n",
+                    "        ^^^ did you mean `loop`?\n",
+                ),
+            ),
+            (
+                "    LDA\n    HLT\n",
+                concat!(
+                    "test.ebr:1: error: LDA is missing an operand\n",
+                    "    LDA\n",
+                    "    ^^^\n",
+                ),
+            ),
+            (
+                "\tLDI\t'ab'\n    HLT\n",
+                concat!(
+                    "test.ebr:1: error: invalid character literal `'ab'`: more than one character\n",
+                    "    LDI\t'ab'\n",
+                    "       \t^^^^\n",
+                ),
+            ),
+            (
+                "    NOP 3\n    HLT\n",
+                concat!(
+                    "test.ebr:1: warning: NOP takes no operand, ignoring `3`\n",
+                    "    NOP 3\n",
+                    "        ^\n",
+                ),
+            ),
+            (
+                "x EQU 1\nx EQU 2\n    HLT\n",
+                concat!(
+                    "test.ebr:2: error: `x` is already defined\n",
+                    "    x EQU 2\n",
+                    "    ^\n",
+                    "test.ebr:1: note: first defined here\n",
+                    "    x EQU 1\n",
+                ),
+            ),
+            (
+                ".macro load cell\n    LDA cell\n.endmacro\n    load nowhere\n    HLT\n",
+                concat!(
+                    "test.ebr:2: error: `nowhere` is not defined\n",
+                    "    LDA cell\n",
+                    "    ^^^^^^^^\n",
+                    "test.ebr:4: note: in the macro invoked here\n",
+                    "    load nowhere\n",
+                ),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(rendered(source), expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn rendered_in_color() {
+        let errors = assemble("    LDH 256\n").unwrap_err();
+        assert_eq!(
+            render(&errors[0], false, true),
+            concat!(
+                "test.ebr:1: \x1b[1;31merror\x1b[0m: byte 256 is out of range 0..=255\n",
+                "    LDH 256\n",
+                "        \x1b[1;31m^^^\x1b[0m expected 0..=255\n",
+            )
+        );
+    }
+
+    /// The .ebrc of `source` once it's been disassembled and assembled again.
+    fn reassembled(bytes: &[u8], padding: bool) -> Vec<u8> {
+        let source = disassemble(bytes, padding, false).unwrap();
+        compile(&source).to_bytes(false)
+    }
+
+    #[test]
+    fn every_instruction_form_round_trips() {
+        let mut program = compile("    HLT\n");
+        program.rom = Instruction::all_variants()
+            .iter()
+            .flat_map(|&instruction| {
+                [0, 1, -1, 255, IMMEDIATE_MIN, IMMEDIATE_MAX]
+                    .into_iter()
+                    .filter_map(move |value| representative(instruction, value))
+            })
+            .take(ROM_SIZE - 1)
+            .collect();
+        program.rom.push(RomLayout::new(Instruction::HLT, 0));
+        assert!(program.rom.len() > Instruction::all_variants().len());
+        let bytes = program.to_bytes(false);
+        assert_eq!(reassembled(&bytes, false), bytes);
+        assert_eq!(reassembled(&bytes, true), bytes);
+    }
+
+    #[test]
+    fn sections_and_odd_words_round_trip() {
+        let source = concat!(
+            ".data 10 1 -2 3\n",
+            ".start main\n",
+            "    HLT 7\n",
+            "main:\n",
+            "    LDR 0\n",
+            "    OUT\n",
+            "    HLT\n",
+            ".org 20\n",
+            "    HLT\n",
+        );
+        let mut bytes = compile(source).to_bytes(false);
+        // LDA can't be written with an operand past RAM.
+        let lda = RomLayout::new(Instruction::LDA, 300).pack().unwrap();
+        bytes[16 + 8 * 19..][..8].copy_from_slice(&lda);
+        let disassembled = disassemble(&bytes, false, false).unwrap();
+        assert!(disassembled.contains(".bytes 0x01 0x00 0x00 0x00 0x00 0x00 0x01 0x2c ; 019"));
+        assert_eq!(reassembled(&bytes, false), bytes);
+        assert_eq!(reassembled(&bytes, true), bytes);
+
+        // A word no instruction has can't be assembled, but is still shown.
+        bytes[16 + 8 * 19..][..8].copy_from_slice(&[0xEE, 1, 2, 3, 4, 5, 6, 7]);
+        let disassembled = disassemble(&bytes, false, false).unwrap();
+        assert!(disassembled.contains(".bytes 0xee 0x01 0x02 0x03 0x04 0x05 0x06 0x07 ; 019"));
+
+        // Assertions are kept, but say where they are in the new source.
+        let bytes = compile("    NOP\n.assert reg_a == 0\n    HLT\n").to_bytes(false);
+        assert_eq!(
+            disassemble(&bytes, false, false).unwrap(),
+            "    NOP ; 000\n    .assert reg_a == 0\n    HLT ; 001\n"
+        );
+    }
+
+    #[test]
+    fn padding_is_left_out_unless_asked_for() {
+        let bytes = compile(".org 10\n    HLT\n").to_bytes(false);
+        assert_eq!(
+            disassemble(&bytes, false, false).unwrap(),
+            "    HLT ; 000\n    .org 11\n"
+        );
+        assert_eq!(
+            disassemble(&bytes, true, false).unwrap().lines().count(),
+            11
+        );
+    }
+
+    #[test]
+    fn ebrc_header_is_checked() {
+        let program = compile("    LDI 1\n    OUT\n    HLT\n");
+        let bytes = program.to_bytes(false);
+        assert_eq!(&bytes[..4], b"EBRC");
+        assert_eq!(bytes[4..6], FORMAT_VERSION.to_be_bytes());
+        assert_eq!(bytes[8..12], 3u32.to_be_bytes());
+        assert_eq!(bytes[12..16], 0u32.to_be_bytes());
+        assert_eq!(read_rom(&bytes, false).unwrap().rom.len(), 3);
+
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F'];
+        assert_eq!(
+            read_rom(&jpeg, false).unwrap_err(),
+            "this doesn't look like an ebrc file (if it's from before ebrc files had a header, try --legacy)"
+        );
+
+        let mut future = bytes.clone();
+        future[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            read_rom(&future, false).unwrap_err(),
+            format!(
+                "this ebrc file is format version {}, but this e64br only reads up to version {}",
+                FORMAT_VERSION + 1,
+                FORMAT_VERSION
+            )
+        );
+        assert_eq!(
+            read_rom(&bytes[..10], false).unwrap_err(),
+            "truncated ebrc header"
+        );
+        assert_eq!(
+            read_rom(&bytes[..30], false).unwrap_err(),
+            "the ebrc file ends in its instructions"
+        );
+    }
+
+    #[test]
+    fn ebrc_sections_are_counted() {
+        let program = compile(".data 5 1 2\n.start main\n    NOP\nmain:\n    HLT\n");
+        let bytes = program.to_bytes(false);
+        assert_eq!(bytes[12..16], 2u32.to_be_bytes());
+        let loaded = read_rom(&bytes, false).unwrap();
+        assert_eq!(
+            (loaded.data[0].values.clone(), loaded.start),
+            (vec![1, 2], 1)
+        );
+        assert!(read_rom(&bytes[..bytes.len() - 8], false).is_err());
+
+        // A section this reader doesn't know is skipped.
+        let mut newer = bytes.clone();
+        newer[12..16].copy_from_slice(&3u32.to_be_bytes());
+        newer.extend([SECTION_MARKER, b'?', 0, 0, 0, 0, 0, 1]);
+        newer.extend(42i64.to_be_bytes());
+        assert_eq!(read_rom(&newer, false).unwrap().start, 1);
+    }
+
+    #[test]
+    fn legacy_files_load_with_legacy() {
+        let program = compile(".data 5 7\n    LDA 5\n    OUT\n    HLT\n");
+        let bytes = program.to_bytes(false);
+        let legacy = &bytes[16..];
+        assert!(read_rom(legacy, false).is_err());
+        let loaded = read_rom(legacy, true).unwrap();
+        assert_eq!(loaded.rom.len(), 3);
+        assert_eq!(loaded.data[0].values, [7]);
+        let (mut state, stdout) = machine(&loaded);
+        finish(&mut state).unwrap();
+        assert_eq!(stdout.text(), "7\n");
+    }
+
+    #[test]
+    fn a_full_rom_round_trips() {
+        for source in ["    NOP\n.org 255\n    HLT\n", "    JMP 0\n.fill 255\n"] {
+            let bytes = compile(source).to_bytes(false);
+            assert_eq!(bytes.len(), 16 + 8 * ROM_SIZE);
+            assert_eq!(reassembled(&bytes, false), bytes);
+            assert_eq!(reassembled(&bytes, true), bytes);
+        }
+    }
 }